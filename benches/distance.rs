@@ -0,0 +1,38 @@
+//! Benchmarks of the distance kernels against their scalar versions
+//!
+//! `cargo bench --bench distance`
+
+use criterion::{black_box, criterion_group, criterion_main, Criterion};
+
+//Photodedupe is a binary crate, so the module is included directly. Benchmarks are built with cfg(test), but the module's tests aren't run here.
+#[allow(dead_code, unused_imports)]
+#[path = "../src/distance.rs"]
+mod distance;
+
+/// Pseudo-random bytes so the differences aren't all the same
+fn test_bytes( seed: u64, len: usize ) -> Vec<u8> {
+	let mut state = seed;
+	(0..len).map( |_| {
+		state = state.wrapping_mul( 6364136223846793005 ).wrapping_add( 1442695040888963407 );
+		(state >> 56) as u8
+	}).collect()
+}
+
+fn bench_sum_abs_diff( c: &mut Criterion ) {
+	//The low resolution image (8x8 RGB) and alpha mask (8x8) compared for each pair of images
+	for len in [ 192, 64 ] {
+		let a = test_bytes( 1, len );
+		let b = test_bytes( 2, len );
+		c.bench_function( &format!("sum_abs_diff {} bytes", len), |bench| bench.iter( || distance::sum_abs_diff( black_box( &a ), black_box( &b ) ) ) );
+		c.bench_function( &format!("sum_abs_diff_scalar {} bytes", len), |bench| bench.iter( || distance::sum_abs_diff_scalar( black_box( &a ), black_box( &b ) ) ) );
+	}
+}
+
+fn bench_hamming_distance( c: &mut Criterion ) {
+	let hashes : Vec<u64> = test_bytes( 3, 8 * 1024 ).chunks( 8 ).map( |h| u64::from_le_bytes( h.try_into().unwrap() ) ).collect();
+	c.bench_function( "hamming_distance 1024 hashes", |bench| bench.iter( || hashes.iter().map( |h| distance::hamming_distance( black_box( hashes[0] ), *h ) ).sum::<u32>() ) );
+	c.bench_function( "hamming_distance_scalar 1024 hashes", |bench| bench.iter( || hashes.iter().map( |h| distance::hamming_distance_scalar( black_box( hashes[0] ), *h ) ).sum::<u32>() ) );
+}
+
+criterion_group!( benches, bench_sum_abs_diff, bench_hamming_distance );
+criterion_main!( benches );
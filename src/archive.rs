@@ -0,0 +1,311 @@
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{BufReader, Read};
+use std::ffi::{OsStr, OsString};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use flate2::read::GzDecoder;
+
+use crate::imagehash::{ImageHashAV, ImagePath, ConfigOptions};
+use crate::frames::FramePolicy;
+use crate::image_error::MyImageError;
+use crate::formats;
+use crate::memory::{self, MemoryBudget};
+
+/// Separates the path of an archive from the path of a file inside it e.g. photos.zip!/2019/IMG_1234.jpg
+pub const MEMBER_SEPARATOR : &str = "!/";
+
+/// Archive file name endings (lower case) that can be scanned for images
+const ARCHIVE_EXTENSIONS : [&str; 4] = [ ".zip", ".tar", ".tar.gz", ".tgz" ];
+
+/// Is the file an archive that can be scanned for images, according to its extension
+pub fn is_archive( fpath: &Path ) -> bool {
+	let lower = fpath.to_string_lossy().to_lowercase();
+	ARCHIVE_EXTENSIONS.iter().any( |ext| lower.ends_with( ext ) )
+}
+
+/// Is the archive a ZIP file (otherwise a TAR)
+fn is_zip( archive_path: &Path ) -> bool {
+	archive_path.to_string_lossy().to_lowercase().ends_with( ".zip" )
+}
+
+/// The path used to refer to a file inside an archive
+pub fn member_path( archive_path: &Path, member: &str ) -> PathBuf {
+	let mut fpath = OsString::from( archive_path );
+	fpath.push( MEMBER_SEPARATOR );
+	fpath.push( member );
+	PathBuf::from( fpath )
+}
+
+/// Split a path to a file inside an archive into the path of the archive and the path inside the archive.
+/// Returns None for paths to ordinary files. The archive path may not be UTF-8, but the member names are always listed as UTF-8.
+pub fn split_member_path( fpath: &Path ) -> Option<(&Path, &str)> {
+	let bytes = fpath.as_os_str().as_encoded_bytes();
+	let separator = MEMBER_SEPARATOR.as_bytes();
+	let mut search_from = 0;
+	while let Some(pos) = bytes[search_from..].windows( separator.len() ).position( |w| w == separator ) {
+		let split = search_from + pos;
+		//Safe as the bytes are split next to an ASCII separator
+		let archive_path = Path::new( unsafe { OsStr::from_encoded_bytes_unchecked( &bytes[..split] ) } );
+		if is_archive( archive_path ) && archive_path.is_file() {
+			return Some((archive_path, std::str::from_utf8( &bytes[split + separator.len()..] ).ok()?));
+		}
+		search_from = split + separator.len();
+	}
+	None
+}
+
+/// The path inside the archive of a file in the archive, as given by member_path
+fn member_name<'a>( archive_path: &Path, fpath: &'a Path ) -> &'a str {
+	let member = fpath.as_os_str().as_encoded_bytes().get( archive_path.as_os_str().as_encoded_bytes().len() + MEMBER_SEPARATOR.len().. ).unwrap_or_default();
+	std::str::from_utf8( member ).unwrap_or_default()
+}
+
+/// Open a TAR archive, decompressing it if it is gzipped
+fn open_tar( archive_path: &Path ) -> Result<tar::Archive<Box<dyn Read>>, MyImageError> {
+	let file = match File::open( archive_path ) {
+		Ok(file) => BufReader::new( file ),
+		Err(_) => return Err(MyImageError::FileError(format!("Error: Failed to read archive: {}", archive_path.display()))),
+	};
+	let reader : Box<dyn Read> = if archive_path.to_string_lossy().to_lowercase().ends_with( ".tar" ) { Box::new( file ) } else { Box::new( GzDecoder::new( file ) ) };
+	Ok(tar::Archive::new( reader ))
+}
+
+/// Open a ZIP archive
+fn open_zip( archive_path: &Path ) -> Result<zip::ZipArchive<BufReader<File>>, MyImageError> {
+	let file = match File::open( archive_path ) {
+		Ok(file) => BufReader::new( file ),
+		Err(_) => return Err(MyImageError::FileError(format!("Error: Failed to read archive: {}", archive_path.display()))),
+	};
+	match zip::ZipArchive::new( file ) {
+		Ok(zip) => Ok(zip),
+		Err(_) => Err(MyImageError::FileError(format!("Error: Failed to read ZIP archive: {}", archive_path.display()))),
+	}
+}
+
+/// Whether a file inside an archive is skipped, as when searching directories: invisible files and directories (e.g. __MACOSX/._IMG_1234.jpg) unless --hidden, and paths matching --exclude
+fn skip_member( archive_path: &Path, member: &str, config: &ConfigOptions ) -> bool {
+	if !config.include_hidden && member.split('/').any( |component| component.starts_with('.') ) {
+		return true;
+	}
+	let mut directories = member.match_indices('/').map( |(i, _)| &member[..i] );
+	directories.any( |directory| crate::is_excluded( &member_path( archive_path, directory ), true, config ) )
+		|| crate::is_excluded( &member_path( archive_path, member ), false, config )
+}
+
+/// List the images inside an archive. Images are selected using the same rules as for ordinary files, where --any-file tests the start of each file inside the archive.
+/// Returns the paths of the images e.g. photos.zip!/2019/IMG_1234.jpg
+pub fn list_images( archive_path: &Path, config: &ConfigOptions ) -> Result<Vec<PathBuf>, MyImageError> {
+	let mut members = Vec::new();
+	let is_image = |member: &str, file: &mut dyn Read| !skip_member( archive_path, member, config ) && crate::valid_file_extension_or_sniff( Path::new( member ), config, || formats::sniff_image_reader( file ) );
+	if is_zip( archive_path ) {
+		let mut zip = open_zip( archive_path )?;
+		for i in 0..zip.len() {
+			let mut file = match zip.by_index( i ) {
+				Ok(file) => file,
+				Err(_) => return Err(MyImageError::FileError(format!("Error: Failed to read ZIP archive: {}", archive_path.display()))),
+			};
+			let name = file.name().to_string();
+			if !name.ends_with('/') && is_image( &name, &mut file ) {
+				members.push( name );
+			}
+		}
+	}else{
+		let mut tar = open_tar( archive_path )?;
+		let entries = match tar.entries() {
+			Ok(entries) => entries,
+			Err(_) => return Err(MyImageError::FileError(format!("Error: Failed to read TAR archive: {}", archive_path.display()))),
+		};
+		for entry in entries {
+			match entry {
+				Ok(mut entry) => {
+					if entry.header().entry_type().is_file() {
+						if let Ok(path) = entry.path() {
+							let name = path.to_string_lossy().to_string();
+							if is_image( &name, &mut entry ) {
+								members.push( name );
+							}
+						}
+					}
+				},
+				Err(_) => return Err(MyImageError::FileError(format!("Error: Failed to read TAR archive: {}", archive_path.display()))),
+			}
+		}
+	}
+
+	members.sort();
+	Ok(members.into_iter().map( |m| member_path( archive_path, &m ) ).collect())
+}
+
+/// Read a file that may be inside an archive. Files inside archives larger than the memory limit aren't read, failing with a MemoryLimit error.
+pub fn read_file( fpath: &Path, memory_limit: Option<u64> ) -> Result<Vec<u8>, MyImageError> {
+	let (archive_path, member) = match split_member_path( fpath ) {
+		Some(split) => split,
+		None => {
+			return match fs::read( fpath ) {
+				Ok(data) => Ok(data),
+				Err(_) => Err(MyImageError::FileError(format!("Error: Failed to read image file: {}", fpath.display()))),
+			};
+		}
+	};
+
+	if is_zip( archive_path ) {
+		let mut zip = open_zip( archive_path )?;
+		let read = zip.by_name( member ).map( |file| memory::read_to_limit( file, memory_limit, fpath ) );
+		if let Ok(read) = read {
+			return read;
+		}
+	}else{
+		let mut tar = open_tar( archive_path )?;
+		if let Ok(entries) = tar.entries() {
+			for entry in entries.flatten() {
+				if entry.path().map( |p| p.to_string_lossy() == member ).unwrap_or(false) {
+					return memory::read_to_limit( entry, memory_limit, fpath );
+				}
+			}
+		}
+	}
+	Err(MyImageError::FileError(format!("Error: Failed to read image file: {}", fpath.display())))
+}
+
+/// Hash the images inside an archive, reading through the archive once. Returns a result for each image.
+/// Large images wait for memory to be free before they are decoded, if there is a memory budget.
+pub fn hash_members( archive_path: &Path, images: Vec<ImagePath>, min_width: u32, min_height: u32, frame_policy: FramePolicy, memory_budget: Option<&Arc<MemoryBudget>> ) -> Vec<Result<ImageHashAV, MyImageError>> {
+
+	let mut results = Vec::new();
+
+	let hash_member = |image: &ImagePath, data: &[u8]| {
+		let _reservation = match memory_budget.map( |budget| budget.reserve( memory::estimate_decoded_size_of_data( data, image, frame_policy ).unwrap_or(0), &image.fpath ) ) {
+			Some(Err(e)) => return Err(e),
+			reservation => reservation,
+		};
+		ImageHashAV::from_data( data, image, min_width, min_height, frame_policy )
+	};
+	let failed = |image: &ImagePath| MyImageError::FileError(format!("Error: Failed to read image file: {}", image.fpath.display()));
+
+	if is_zip( archive_path ) {
+		let mut zip = match open_zip( archive_path ) {
+			Ok(zip) => Some(zip),
+			Err(e) => {
+				results.push( Err(e) );
+				None
+			}
+		};
+		for image in images.iter() {
+			let member = member_name( archive_path, &image.fpath );
+			let data = match zip.as_mut().map( |z| z.by_name( member ) ) {
+				Some(Ok(file)) => memory::read_to_limit( file, image.memory_limit, &image.fpath ),
+				_ => Err(failed( image )),
+			};
+			results.push( data.and_then( |data| hash_member( image, &data ) ) );
+		}
+	}else{
+		//TAR archives are read sequentially, so the images are hashed in the order they are stored
+		let mut wanted : HashMap<String, ImagePath> = images.into_iter().map( |i| (member_name( archive_path, &i.fpath ).to_string(), i) ).collect();
+		if let Ok(mut tar) = open_tar( archive_path ) {
+			if let Ok(entries) = tar.entries() {
+				for mut entry in entries.flatten() {
+					let member = match entry.path() {
+						Ok(path) => path.to_string_lossy().to_string(),
+						Err(_) => continue,
+					};
+					if let Some(image) = wanted.remove( &member ) {
+						let data = memory::read_to_limit( &mut entry, image.memory_limit, &image.fpath );
+						results.push( data.and_then( |data| hash_member( &image, &data ) ) );
+					}
+				}
+			}
+		}
+		//Anything not found, so the number of results matches the number of images
+		for image in wanted.values() {
+			results.push( Err(failed( image )) );
+		}
+	}
+	results
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Write;
+
+	/// Create a ZIP and a TAR.GZ archive each holding a duplicate and a text file in a temporary directory
+	fn create_test_archives( dir: &Path ) -> (PathBuf, PathBuf) {
+		let image = fs::read( "unit_test_images/cat1_duplicate_1.jpg" ).unwrap();
+
+		let zip_path = dir.join( "photos.zip" );
+		let mut zip = zip::ZipWriter::new( File::create( &zip_path ).unwrap() );
+		zip.start_file( "2019/cat.jpg", zip::write::SimpleFileOptions::default() ).unwrap();
+		zip.write_all( &image ).unwrap();
+		zip.start_file( "notes.txt", zip::write::SimpleFileOptions::default() ).unwrap();
+		zip.write_all( b"Not an image" ).unwrap();
+		zip.finish().unwrap();
+
+		let tar_path = dir.join( "photos.tar.gz" );
+		let encoder = flate2::write::GzEncoder::new( File::create( &tar_path ).unwrap(), flate2::Compression::default() );
+		let mut tar = tar::Builder::new( encoder );
+		let mut header = tar::Header::new_gnu();
+		header.set_size( image.len() as u64 );
+		header.set_mode( 0o644 );
+		header.set_cksum();
+		tar.append_data( &mut header, "backup/cat.jpg", image.as_slice() ).unwrap();
+		tar.into_inner().unwrap().finish().unwrap();
+
+		(zip_path, tar_path)
+	}
+
+	/// Test images are listed, read and hashed from inside ZIP and TAR archives
+	#[test]
+	fn test_archives() {
+		let dir = std::env::temp_dir().join( "photodedupe_archive_test" );
+		fs::create_dir_all( &dir ).unwrap();
+		let (zip_path, tar_path) = create_test_archives( &dir );
+		let config = crate::get_default_config_options();
+
+		let zip_images = list_images( &zip_path, &config ).unwrap();
+		assert_eq!( zip_images, vec![ PathBuf::from( format!("{}!/2019/cat.jpg", zip_path.display()) ) ], "Only the image is listed" );
+		let tar_images = list_images( &tar_path, &config ).unwrap();
+		assert_eq!( tar_images, vec![ PathBuf::from( format!("{}!/backup/cat.jpg", tar_path.display()) ) ] );
+
+		assert_eq!( split_member_path( &zip_images[0] ), Some((zip_path.as_path(), "2019/cat.jpg")) );
+		assert_eq!( split_member_path( Path::new( "unit_test_images/cat1_best.jpg" ) ), None );
+		assert_eq!( read_file( &tar_images[0], None ).unwrap(), fs::read( "unit_test_images/cat1_duplicate_1.jpg" ).unwrap() );
+
+		let best = ImageHashAV::new( &ImagePath { fpath: "unit_test_images/cat1_best.jpg".into(), ..Default::default() }, 0, 0 ).unwrap();
+		for (archive_path, images) in [ (&zip_path, zip_images), (&tar_path, tar_images) ] {
+			let results = hash_members( archive_path, images.iter().map( |i| ImagePath { fpath: i.clone(), ..Default::default() } ).collect(), 0, 0, FramePolicy::First, None );
+			assert_eq!( results.len(), 1 );
+			let hashed = results[0].as_ref().unwrap();
+			assert!( best.is_dupe( hashed, &config ), "Image in archive is a duplicate" );
+		}
+
+		let budget = MemoryBudget::new( 1 );
+		let results = hash_members( &zip_path, vec![ ImagePath { fpath: member_path( &zip_path, "2019/cat.jpg" ), ..Default::default() } ], 0, 0, FramePolicy::First, Some(&budget) );
+		assert!( matches!( results[0], Err(MyImageError::MemoryLimit(_)) ), "Memory limit applies inside archives" );
+
+		//Files larger than the memory limit aren't read out of the archive
+		for archive_path in [ &zip_path, &tar_path ] {
+			let images = list_images( archive_path, &config ).unwrap();
+			assert!( matches!( read_file( &images[0], Some(1 << 10) ), Err(MyImageError::MemoryLimit(_)) ), "Read of {} limited", archive_path.display() );
+			let results = hash_members( archive_path, vec![ ImagePath { fpath: images[0].clone(), memory_limit: Some(1 << 10), ..Default::default() } ], 0, 0, FramePolicy::First, None );
+			assert!( matches!( results[0], Err(MyImageError::MemoryLimit(_)) ) );
+		}
+
+		//With --any-file, files inside the archive without an image extension are tested by their contents
+		let image = fs::read( "unit_test_images/cat1_duplicate_1.jpg" ).unwrap();
+		let zip_path = dir.join( "filtered.zip" );
+		let mut zip = zip::ZipWriter::new( File::create( &zip_path ).unwrap() );
+		for name in [ "scan_0001", "notes", "__MACOSX/._cat.jpg", "thumbs/cat.jpg", "cat.jpg" ] {
+			zip.start_file( name, zip::write::SimpleFileOptions::default() ).unwrap();
+			zip.write_all( if name == "notes" { b"Not an image".as_slice() } else { &image } ).unwrap();
+		}
+		zip.finish().unwrap();
+		let mut config = crate::get_default_config_options();
+		config.only_known_file_extensions = false;
+		config.exclude_patterns = vec![ glob::Pattern::new( "**/thumbs" ).unwrap() ];
+		let names : Vec<PathBuf> = [ "cat.jpg", "scan_0001" ].iter().map( |m| member_path( &zip_path, m ) ).collect();
+		assert_eq!( list_images( &zip_path, &config ).unwrap(), names, "Hidden and excluded files skipped" );
+
+		fs::remove_dir_all( &dir ).unwrap();
+	}
+}
@@ -0,0 +1,405 @@
+use std::collections::{HashMap, HashSet};
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+use rayon::prelude::*;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+
+use crate::calibrate::Algorithm;
+use crate::imagehash::{self, ImageHashAV, ImagePath, ConfigOptions};
+use crate::image_error::MyImageError;
+use crate::jpeg::{self, JpegDecode};
+
+/// A transformation applied to a source image to generate a variant that should still be detected as a duplicate
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Transform {
+	/// Scale to a percentage of the original size
+	Rescale(u32),
+	/// Save as a JPEG with the given quality
+	Recompress(u8),
+	/// Save in another file format
+	Convert(ImageFormat),
+	/// Remove a percentage of the width and height from each edge
+	Crop(u32),
+	/// Rotate clockwise by a number of degrees about the centre
+	Rotate(u32),
+	/// Adjust the brightness by adding a value to each channel
+	Brightness(i32),
+	/// Overlay a semi-transparent banner in the bottom right corner
+	Watermark,
+}
+
+impl Transform {
+	/// The type of transform, used to group the results
+	pub fn kind( &self ) -> &str {
+		match self {
+			Transform::Rescale(_) => "rescale",
+			Transform::Recompress(_) => "recompress",
+			Transform::Convert(_) => "convert",
+			Transform::Crop(_) => "crop",
+			Transform::Rotate(_) => "rotate",
+			Transform::Brightness(_) => "brightness",
+			Transform::Watermark => "watermark",
+		}
+	}
+
+	/// Description of the setting of the transform e.g. the quality or percentage
+	pub fn setting( &self ) -> String {
+		match self {
+			Transform::Rescale(percent) => format!("{}%", percent),
+			Transform::Recompress(quality) => format!("jpeg q{}", quality),
+			Transform::Convert(format) => format!("{:?}", format).to_lowercase(),
+			Transform::Crop(percent) => format!("{}% each edge", percent),
+			Transform::Rotate(degrees) => format!("{} degrees", degrees),
+			Transform::Brightness(amount) => format!("{:+}", amount),
+			Transform::Watermark => "banner".to_string(),
+		}
+	}
+
+	/// Generate the variant of an image. The variant is encoded to a file format and decoded again, as it would be if saved to disk.
+	/// Returns the decoded variant and its encoded size in bytes.
+	pub fn apply( &self, img: &DynamicImage ) -> Result<(DynamicImage, u64), String> {
+		let (width, height) = (img.width(), img.height());
+		let (transformed, format) = match *self {
+			Transform::Rescale(percent) => {
+				let new_width = (width * percent / 100).max(1);
+				let new_height = (height * percent / 100).max(1);
+				(img.resize_exact( new_width, new_height, FilterType::Lanczos3 ), ImageFormat::Png)
+			},
+			Transform::Recompress(_) => (img.clone(), ImageFormat::Jpeg),
+			Transform::Convert(format) => (img.clone(), format),
+			Transform::Crop(percent) => {
+				let x = width * percent / 100;
+				let y = height * percent / 100;
+				(img.crop_imm( x, y, width - (2 * x), height - (2 * y) ), ImageFormat::Png)
+			},
+			Transform::Rotate(90) => (img.rotate90(), ImageFormat::Png),
+			Transform::Rotate(180) => (img.rotate180(), ImageFormat::Png),
+			Transform::Rotate(270) => (img.rotate270(), ImageFormat::Png),
+			Transform::Rotate(degrees) => (rotate( img, degrees as f32 ), ImageFormat::Png),
+			Transform::Brightness(amount) => (img.brighten( amount ), ImageFormat::Png),
+			Transform::Watermark => (watermark( img ), ImageFormat::Png),
+		};
+
+		let quality = match *self {
+			Transform::Recompress(quality) => quality,
+			_ => 90,
+		};
+		let encoded = encode( &transformed, format, quality )?;
+		match image::load_from_memory_with_format( &encoded, format ) {
+			Ok(decoded) => Ok((decoded, encoded.len() as u64)),
+			Err(e) => Err(format!("Error: Failed to decode {} variant: {}", format!("{:?}", format).to_lowercase(), e)),
+		}
+	}
+}
+
+/// The transforms tested by default, from mild to severe within each type
+pub fn default_transforms() -> Vec<Transform> {
+	vec![
+		Transform::Rescale(50), Transform::Rescale(25), Transform::Rescale(10),
+		Transform::Recompress(90), Transform::Recompress(70), Transform::Recompress(50), Transform::Recompress(30), Transform::Recompress(10),
+		Transform::Convert(ImageFormat::Png), Transform::Convert(ImageFormat::Gif), Transform::Convert(ImageFormat::Bmp),
+		Transform::Convert(ImageFormat::Tiff), Transform::Convert(ImageFormat::WebP),
+		Transform::Crop(1), Transform::Crop(3), Transform::Crop(5), Transform::Crop(10),
+		Transform::Rotate(1), Transform::Rotate(3), Transform::Rotate(90), Transform::Rotate(180),
+		Transform::Brightness(10), Transform::Brightness(-10), Transform::Brightness(30), Transform::Brightness(-30),
+		Transform::Watermark,
+	]
+}
+
+/// Encode an image in memory. Transparency is dropped as photos don't use it and not all formats support it.
+fn encode( img: &DynamicImage, format: ImageFormat, jpeg_quality: u8 ) -> Result<Vec<u8>, String> {
+	let rgb = DynamicImage::ImageRgb8( img.to_rgb8() );
+	let mut encoded : Vec<u8> = Vec::new();
+	let result = if format == ImageFormat::Jpeg {
+		rgb.write_with_encoder( JpegEncoder::new_with_quality( &mut encoded, jpeg_quality ) )
+	}else{
+		rgb.write_to( &mut Cursor::new( &mut encoded ), format )
+	};
+	match result {
+		Ok(_) => Ok(encoded),
+		Err(e) => Err(format!("Error: Failed to encode {} variant: {}", format!("{:?}", format).to_lowercase(), e)),
+	}
+}
+
+/// Rotate an image by an arbitrary angle keeping the same dimensions. Areas rotated in from outside the image are filled from the nearest edge.
+fn rotate( img: &DynamicImage, degrees: f32 ) -> DynamicImage {
+	let src = img.to_rgb8();
+	let (width, height) = src.dimensions();
+	let (sin, cos) = degrees.to_radians().sin_cos();
+	let (centre_x, centre_y) = (width as f32 / 2.0, height as f32 / 2.0);
+
+	let rotated = RgbImage::from_fn( width, height, |x, y| {
+		let dx = x as f32 - centre_x;
+		let dy = y as f32 - centre_y;
+		let src_x = (dx * cos + dy * sin + centre_x).round().clamp( 0.0, (width - 1) as f32 );
+		let src_y = (dy * cos - dx * sin + centre_y).round().clamp( 0.0, (height - 1) as f32 );
+		*src.get_pixel( src_x as u32, src_y as u32 )
+	});
+	DynamicImage::ImageRgb8( rotated )
+}
+
+/// Overlay a half transparent white banner over the bottom right corner, as a stand in for a text watermark
+fn watermark( img: &DynamicImage ) -> DynamicImage {
+	let mut marked = img.to_rgb8();
+	let (width, height) = marked.dimensions();
+	for y in (height - height / 8)..height {
+		for x in (width - width / 3)..width {
+			let Rgb([r, g, b]) = *marked.get_pixel( x, y );
+			marked.put_pixel( x, y, Rgb([ r / 2 + 128, g / 2 + 128, b / 2 + 128 ]) );
+		}
+	}
+	DynamicImage::ImageRgb8( marked )
+}
+
+/// The hashes of a source image and of the variants generated from it
+struct SourceHashes {
+	original : ImageHashAV,
+	/// Index of the transform and the hash of the variant it produced
+	variants : Vec<(usize, ImageHashAV)>,
+	/// Variants that could not be generated or hashed e.g. because they were too small
+	errors : Vec<String>,
+}
+
+/// Load a source image, generate a variant for each transform and hash them all
+fn hash_source( source: &Path, transforms: &[Transform] ) -> Result<SourceHashes, MyImageError> {
+	let img = imagehash::load_image_from_file( source )?;
+	let file_size = std::fs::metadata( source ).map( |m| m.len() ).unwrap_or(0);
+	let original = ImageHashAV::from_image( &img, &ImagePath { fpath: source.to_path_buf(), ..Default::default() }, file_size, 0, 0 )?;
+
+	let mut variants = Vec::new();
+	let mut errors = Vec::new();
+	for (i, transform) in transforms.iter().enumerate() {
+		let mut variant_name = source.as_os_str().to_owned();
+		variant_name.push( format!(" [{} {}]", transform.kind(), transform.setting()) );
+		let variant_path = ImagePath { fpath: PathBuf::from( variant_name ), ..Default::default() };
+		match transform.apply( &img ) {
+			Ok((variant, variant_size)) => {
+				match ImageHashAV::from_image( &variant, &variant_path, variant_size, 0, 0 ) {
+					Ok(hash) => variants.push( (i, hash) ),
+					Err(e) => errors.push( e.to_string() ),
+				}
+			},
+			Err(e) => errors.push( format!("{}: {}", e, variant_path.fpath.display()) ),
+		}
+	}
+	Ok(SourceHashes { original, variants, errors })
+}
+
+/// Detection results for one transform
+pub struct TransformResult {
+	pub transform : Transform,
+	/// Number of variants generated and hashed successfully
+	pub tested : usize,
+	/// Number of variants grouped with their source image by each algorithm (ColourDiff, Hamming)
+	pub detected : [usize; 2],
+}
+
+/// Results of the whole benchmark
+pub struct BenchmarkResult {
+	pub num_sources : usize,
+	pub transforms : Vec<TransformResult>,
+	/// Number of variants grouped with an image from a different source by each algorithm (ColourDiff, Hamming)
+	pub false_matches : [usize; 2],
+}
+
+/// Generate variants of each source image on all threads and test whether each grouping algorithm detects them as duplicates of their source
+pub fn run_benchmark( sources: Vec<PathBuf>, transforms: &[Transform], config: &ConfigOptions ) -> BenchmarkResult {
+	let results : Vec<Result<SourceHashes, MyImageError>> = sources.par_iter().map( |source| hash_source( source, transforms ) ).collect();
+
+	let mut hashed_sources : Vec<SourceHashes> = Vec::new();
+	for result in results {
+		match result {
+			Ok(source_hashes) => {
+				for e in source_hashes.errors.iter() {
+					eprintln!("{}", e);
+				}
+				hashed_sources.push( source_hashes );
+			},
+			Err(e) => eprintln!("{}", e),
+		}
+	}
+	//Sort so that the output doesn't depend on the order the sources were listed
+	hashed_sources.sort_by( |a, b| a.original.image_path.fpath.cmp( &b.original.image_path.fpath ) );
+
+	let mut result = BenchmarkResult {
+		num_sources : hashed_sources.len(),
+		transforms : transforms.iter().map( |t| TransformResult { transform: *t, tested: 0, detected: [0, 0] } ).collect(),
+		false_matches : [0, 0],
+	};
+
+	//Each transform is tested separately against the original images, so that variants of the same source can't affect each other's grouping
+	for (transform_index, transform_result) in result.transforms.iter_mut().enumerate() {
+		let mut images : Vec<ImageHashAV> = Vec::new();
+		let mut source_of : HashMap<PathBuf, usize> = HashMap::new();
+		let mut is_variant : HashSet<PathBuf> = HashSet::new();
+		for (source_index, source_hashes) in hashed_sources.iter().enumerate() {
+			source_of.insert( source_hashes.original.image_path.fpath.clone(), source_index );
+			images.push( source_hashes.original.clone() );
+			for (_, variant) in source_hashes.variants.iter().filter( |(i, _)| *i == transform_index ) {
+				source_of.insert( variant.image_path.fpath.clone(), source_index );
+				is_variant.insert( variant.image_path.fpath.clone() );
+				images.push( variant.clone() );
+			}
+		}
+		transform_result.tested = is_variant.len();
+
+		for (alg_index, algorithm) in [Algorithm::ColourDiff, Algorithm::Hamming].iter().enumerate() {
+			let mut grouped = images.clone();
+			match algorithm {
+				Algorithm::ColourDiff => crate::colour_n_square_check( &mut grouped, config ),
+				Algorithm::Hamming => crate::hamming_check( &mut grouped, config ),
+			}
+			grouped.sort();
+
+			for group in crate::duplicate_groups( &grouped, config ) {
+				let members = &grouped[group];
+				for variant in members.iter().filter( |m| is_variant.contains( &m.image_path.fpath ) ) {
+					let source = source_of[&variant.image_path.fpath];
+					if members.iter().any( |m| m.image_path.fpath == hashed_sources[source].original.image_path.fpath ) {
+						transform_result.detected[alg_index] += 1;
+					}
+					if members.iter().any( |m| source_of[&m.image_path.fpath] != source ) {
+						result.false_matches[alg_index] += 1;
+					}
+				}
+			}
+		}
+	}
+
+	result
+}
+
+/// Time taken to hash the source JPEGs when decoded at full and at reduced resolution, and how far apart the hashes are
+pub struct DecodeResult {
+	/// Number of JPEGs hashed both ways
+	pub num_jpegs : usize,
+	/// Seconds taken to hash the JPEGs at full resolution and at reduced resolution
+	pub seconds : [f64; 2],
+	/// Largest colour difference between the two hashes of an image
+	pub max_colour_diff : u64,
+	/// Number of images whose two hashes are not duplicates of each other
+	pub unstable : usize,
+}
+
+/// Hash the source JPEGs on one thread decoding at full resolution, then again decoding at reduced resolution, to compare the throughput and check the hashes agree
+pub fn benchmark_decoding( sources: &[PathBuf], config: &ConfigOptions ) -> DecodeResult {
+	let jpegs : Vec<&PathBuf> = sources.iter().filter( |s| jpeg::is_jpeg_path( s ) ).collect();
+	let hash_all = |jpeg_decode: JpegDecode| {
+		let start = Instant::now();
+		let hashes : Vec<Option<ImageHashAV>> = jpegs.iter()
+			.map( |s| ImageHashAV::new( &ImagePath { fpath: s.to_path_buf(), jpeg_decode, ..Default::default() }, 0, 0 ).ok() )
+			.collect();
+		(hashes, start.elapsed().as_secs_f64())
+	};
+	let (full, full_seconds) = hash_all( JpegDecode::Full );
+	let (reduced, reduced_seconds) = hash_all( JpegDecode::Scaled );
+
+	let mut result = DecodeResult { num_jpegs: 0, seconds: [full_seconds, reduced_seconds], max_colour_diff: 0, unstable: 0 };
+	for (full, reduced) in full.iter().zip( reduced.iter() ) {
+		if let (Some(full), Some(reduced)) = (full, reduced) {
+			result.num_jpegs += 1;
+			result.max_colour_diff = result.max_colour_diff.max( full.diff_colour( reduced ) );
+			if !full.is_dupe( reduced, config ) {
+				result.unstable += 1;
+			}
+		}
+	}
+	result
+}
+
+/// Print the throughput of hashing JPEGs decoded at full and reduced resolution
+pub fn print_decode_report( result: &DecodeResult ) {
+	if result.num_jpegs == 0 {
+		return;
+	}
+	let rate = |seconds: f64| if seconds > 0.0 { format!("{:.1} images/s", result.num_jpegs as f64 / seconds) } else { "-".to_string() };
+	println!();
+	println!("JPEG decoding ({} images, 1 thread): full resolution {}, reduced resolution {}",
+		result.num_jpegs, rate( result.seconds[0] ), rate( result.seconds[1] ));
+	println!("Largest colour difference between full and reduced resolution hashes: {}, images no longer duplicates of themselves: {}",
+		result.max_colour_diff, result.unstable);
+}
+
+/// Percentage of variants detected, formatted for the report
+fn detection_rate( detected: usize, tested: usize ) -> String {
+	if tested == 0 {
+		return "-".to_string();
+	}
+	format!("{:.1}%", 100.0 * detected as f64 / tested as f64)
+}
+
+/// Print the detection rate of each transform, followed by totals for each type of transform
+pub fn print_report( result: &BenchmarkResult ) {
+	println!("Source images: {}", result.num_sources);
+	println!();
+	println!("{:<12} {:<16} {:>7} {:>12} {:>12}", "Transform", "Setting", "Tested", Algorithm::ColourDiff.name(), Algorithm::Hamming.name());
+	for t in result.transforms.iter() {
+		println!("{:<12} {:<16} {:>7} {:>12} {:>12}", t.transform.kind(), t.transform.setting(), t.tested,
+			detection_rate( t.detected[0], t.tested ), detection_rate( t.detected[1], t.tested ));
+	}
+
+	println!();
+	println!("{:<29} {:>7} {:>12} {:>12}", "Transform type", "Tested", Algorithm::ColourDiff.name(), Algorithm::Hamming.name());
+	let mut kinds : Vec<&str> = Vec::new();
+	for t in result.transforms.iter() {
+		if !kinds.contains( &t.transform.kind() ) {
+			kinds.push( t.transform.kind() );
+		}
+	}
+	for kind in kinds {
+		let of_kind = result.transforms.iter().filter( |t| t.transform.kind() == kind );
+		let (tested, colour, hamming) = of_kind.fold( (0, 0, 0), |acc, t| (acc.0 + t.tested, acc.1 + t.detected[0], acc.2 + t.detected[1]) );
+		println!("{:<29} {:>7} {:>12} {:>12}", kind, tested, detection_rate( colour, tested ), detection_rate( hamming, tested ));
+	}
+
+	println!();
+	println!("Variants falsely grouped with a different source image: {} {}, {} {}",
+		Algorithm::ColourDiff.name(), result.false_matches[0], Algorithm::Hamming.name(), result.false_matches[1]);
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Test each transform produces a variant of the expected size
+	#[test]
+	fn test_transforms() {
+		let img = imagehash::load_image_from_file( Path::new( "unit_test_images/bridge1_best.jpg" ) ).unwrap();
+		let (width, height) = (img.width(), img.height());
+
+		for transform in default_transforms() {
+			let (variant, size) = transform.apply( &img ).unwrap();
+			assert!( size > 0, "Variant encoded" );
+			let expected = match transform {
+				Transform::Rescale(percent) => (width * percent / 100, height * percent / 100),
+				Transform::Crop(percent) => (width - 2 * (width * percent / 100), height - 2 * (height * percent / 100)),
+				Transform::Rotate(90) | Transform::Rotate(270) => (height, width),
+				_ => (width, height),
+			};
+			assert_eq!( (variant.width(), variant.height()), expected, "Dimensions of {} {}", transform.kind(), transform.setting() );
+		}
+	}
+
+	/// Test that mild transforms are detected and that a 90 degree rotation is not (rotations aren't detected by design)
+	#[test]
+	fn test_benchmark() {
+		let config = crate::get_default_config_options();
+		let transforms = [ Transform::Rescale(50), Transform::Recompress(70), Transform::Rotate(90) ];
+		let sources = vec![ PathBuf::from( "unit_test_images/bridge1_best.jpg" ), PathBuf::from( "unit_test_images/cat1_best.jpg" ) ];
+		let result = run_benchmark( sources, &transforms, &config );
+
+		assert_eq!( result.num_sources, 2 );
+		assert_eq!( result.transforms[0].tested, 2 );
+		assert_eq!( result.transforms[0].detected, [2, 2], "Rescale detected" );
+		assert_eq!( result.transforms[1].detected, [2, 2], "Recompress detected" );
+		assert_eq!( result.transforms[2].detected, [0, 0], "Rotation not detected" );
+		assert_eq!( result.false_matches, [0, 0], "Sources not confused" );
+
+		let decoding = benchmark_decoding( &[ PathBuf::from( "unit_test_images/book1_best.jpg" ), PathBuf::from( "unit_test_images/format1_best.bmp" ) ], &config );
+		assert_eq!( decoding.num_jpegs, 1, "Only JPEGs timed" );
+		assert_eq!( decoding.unstable, 0, "Reduced resolution hash is a duplicate of the full resolution hash" );
+	}
+}
@@ -0,0 +1,374 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+
+use crate::imagehash::{ImageHashAV, ConfigOptions};
+use crate::distance;
+
+/// Colour difference thresholds tested when calibrating
+const COLOUR_THRESHOLDS : [u64; 14] = [ 0, 32, 64, 128, 192, 256, 384, 512, 768, 1024, 1536, 2048, 3072, 4096 ];
+
+/// Standard deviation thresholds tested when calibrating
+const STD_DEV_THRESHOLDS : [f32; 4] = [ 0.0, 1.5, 3.0, 6.0 ];
+
+/// The comparison algorithms in the order they are tested
+const ALGORITHMS : [Algorithm; 2] = [ Algorithm::ColourDiff, Algorithm::Hamming ];
+
+/// Number of combinations of settings tested
+const NUM_SETTINGS : usize = ALGORITHMS.len() * STD_DEV_THRESHOLDS.len() * COLOUR_THRESHOLDS.len();
+
+/// Counts of true positives, false positives and false negatives for each combination of settings, in the order they are tested
+type Outcomes = Vec<[u64; 3]>;
+
+/// The comparison algorithms that can be calibrated
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Algorithm {
+	/// All to all colour difference comparison
+	ColourDiff,
+	/// Grey hashes must be within 1 bit in addition to the colour difference check (used on large image sets)
+	Hamming,
+}
+
+impl Algorithm {
+	pub fn name( &self ) -> &str {
+		match self {
+			Algorithm::ColourDiff => "colour difference",
+			Algorithm::Hamming => "hamming",
+		}
+	}
+}
+
+/// A pair of images with a known answer as to whether they are duplicates
+pub struct LabelledPair {
+	pub a : usize,
+	pub b : usize,
+	pub is_duplicate : bool,
+}
+
+/// The pairs of images to calibrate with
+pub enum Pairs {
+	/// Every pair of images, where the pairs whose images have the same label are duplicates. Holds the label of each image.
+	/// The pairs are generated as they are measured because on large sets there are too many to hold in memory.
+	AllPairs( Vec<Option<String>> ),
+	/// Only the listed pairs e.g. from a CSV file
+	Listed( Vec<LabelledPair> ),
+}
+
+impl Pairs {
+
+	/// Every pair of images, labelled as duplicates where the file names share a label.
+	/// Images that don't follow the naming convention are treated as unique.
+	pub fn from_file_names( paths: &[PathBuf] ) -> Pairs {
+		Pairs::AllPairs( paths.iter().map( |p| label_from_file_name( p ) ).collect() )
+	}
+
+	/// The number of pairs and how many of them are duplicates
+	pub fn count( &self ) -> (usize, usize) {
+		match self {
+			Pairs::AllPairs( labels ) => {
+				let mut images_per_label : HashMap<&str, usize> = HashMap::new();
+				for label in labels.iter().flatten() {
+					*images_per_label.entry( label ).or_default() += 1;
+				}
+				let pairs_of = |n: usize| n * n.saturating_sub( 1 ) / 2;
+				(pairs_of( labels.len() ), images_per_label.values().map( |&n| pairs_of( n ) ).sum())
+			},
+			Pairs::Listed( pairs ) => (pairs.len(), pairs.iter().filter( |p| p.is_duplicate ).count()),
+		}
+	}
+}
+
+/// The measurements of a pair of images that the duplicate decision depends on
+struct PairStats {
+	colour_diff : u64,
+	hamming_distance : u32,
+	similar_aspect_ratio : bool,
+	min_std_dev : f32,
+	is_duplicate : bool,
+}
+
+/// The result of testing one combination of settings
+#[derive(Clone, Debug)]
+pub struct CalibrationPoint {
+	pub algorithm : Algorithm,
+	pub colour_threshold : u64,
+	pub std_dev_threshold : f32,
+	pub precision : f64,
+	pub recall : f64,
+	pub f1 : f64,
+}
+
+/// Determine the label of an image from its file name using the naming convention of unit_test_images
+/// e.g. both cat1_best.jpg and cat1_duplicate_2.png are labelled "cat1". Returns None if the name doesn't follow the convention.
+pub fn label_from_file_name( fpath: &Path ) -> Option<String> {
+	let stem = fpath.file_stem()?.to_str()?;
+	if let Some(label) = stem.strip_suffix("_best") {
+		return Some(label.to_string());
+	}
+	let (label, number) = stem.rsplit_once("_duplicate_")?;
+	number.parse::<u32>().ok()?;
+	Some(label.to_string())
+}
+
+/// A pair of image paths and whether they are duplicates
+pub type PathPair = (PathBuf, PathBuf, bool);
+
+/// Read a CSV file of labelled pairs with lines of the form: path_a,path_b,label
+/// Paths containing commas can be quoted e.g. "holiday, 2019/a.jpg". The label is 1, true, yes or duplicate for duplicates, anything else is treated as not a duplicate.
+/// Relative paths are relative to the directory containing the CSV file. An optional header line is skipped.
+/// Returns the list of image paths and the pairs referencing them.
+pub fn pairs_from_csv( csv_path: &Path ) -> Result<(Vec<PathBuf>, Vec<PathPair>), String> {
+	let contents = match fs::read_to_string( csv_path ) {
+		Ok(contents) => contents,
+		Err(e) => return Err(format!("Error: Failed to read pairs file {}: {}", csv_path.display(), e)),
+	};
+	let base_dir = csv_path.parent().unwrap_or( Path::new("") );
+
+	let mut pairs = Vec::new();
+	let mut paths = Vec::new();
+	for (line_num, line) in contents.lines().enumerate() {
+		let line = line.trim();
+		if line.is_empty() {
+			continue;
+		}
+		let fields = split_csv_line( line );
+		if fields.len() != 3 {
+			return Err(format!("Error: Line {} of {} should have 3 fields: path_a,path_b,label", line_num+1, csv_path.display()));
+		}
+		let label = fields[2].to_lowercase();
+		if line_num == 0 && label == "label" {
+			continue;
+		}
+		let is_duplicate = ["1", "true", "yes", "duplicate"].contains( &label.as_str() );
+		let path_a = base_dir.join( &fields[0] );
+		let path_b = base_dir.join( &fields[1] );
+		paths.push( path_a.clone() );
+		paths.push( path_b.clone() );
+		pairs.push( (path_a, path_b, is_duplicate) );
+	}
+
+	paths.sort();
+	paths.dedup();
+	Ok((paths, pairs))
+}
+
+/// Split a line of a CSV file into fields. A field in double quotes can contain commas, with a doubled quote for a quote character.
+/// Spaces around the fields are ignored.
+fn split_csv_line( line: &str ) -> Vec<String> {
+	let mut fields = Vec::new();
+	let mut field = String::new();
+	let mut in_quotes = false;
+	let mut chars = line.chars().peekable();
+	while let Some(c) = chars.next() {
+		match c {
+			'"' if in_quotes && chars.peek() == Some(&'"') => {
+				field.push( '"' );
+				chars.next();
+			},
+			'"' => in_quotes = !in_quotes,
+			',' if !in_quotes => fields.push( std::mem::take( &mut field ).trim().to_string() ),
+			_ => field.push( c ),
+		}
+	}
+	fields.push( field.trim().to_string() );
+	fields
+}
+
+/// Convert pairs of paths to pairs of indexes into a list of successfully hashed images. Pairs where either image failed are dropped.
+pub fn index_pairs( images: &[ImageHashAV], path_pairs: &[PathPair] ) -> Vec<LabelledPair> {
+	let index : HashMap<&Path, usize> = images.iter().enumerate().map( |(i, img)| (img.image_path.fpath.as_path(), i) ).collect();
+	let mut pairs = Vec::new();
+	for (path_a, path_b, is_duplicate) in path_pairs {
+		if let (Some(a), Some(b)) = (index.get( path_a.as_path() ), index.get( path_b.as_path() )) {
+			pairs.push( LabelledPair { a: *a, b: *b, is_duplicate: *is_duplicate } );
+		}
+	}
+	pairs
+}
+
+/// Test every combination of algorithm, colour threshold and standard deviation threshold against the labelled pairs
+pub fn sweep( images: &[ImageHashAV], pairs: &Pairs ) -> Vec<CalibrationPoint> {
+
+	//Measure each pair once, then each combination of settings is just a comparison against the thresholds.
+	//The pairs are shared between all threads, each counting the outcomes of its own pairs.
+	let new_outcomes = || vec![ [0u64; 3]; NUM_SETTINGS ];
+	let outcomes = match pairs {
+		Pairs::AllPairs( labels ) => (0..labels.len()).into_par_iter().fold( new_outcomes, |mut outcomes, a| {
+			for b in (a+1)..labels.len() {
+				let is_duplicate = labels[a].is_some() && labels[a] == labels[b];
+				count_outcomes( &mut outcomes, &measure_pair( &images[a], &images[b], is_duplicate ) );
+			}
+			outcomes
+		}).reduce( new_outcomes, add_outcomes ),
+		Pairs::Listed( pairs ) => pairs.par_iter().fold( new_outcomes, |mut outcomes, p| {
+			count_outcomes( &mut outcomes, &measure_pair( &images[p.a], &images[p.b], p.is_duplicate ) );
+			outcomes
+		}).reduce( new_outcomes, add_outcomes ),
+	};
+
+	let mut points = Vec::new();
+	let mut setting_outcomes = outcomes.iter();
+	for algorithm in ALGORITHMS {
+		for std_dev_threshold in STD_DEV_THRESHOLDS {
+			for colour_threshold in COLOUR_THRESHOLDS {
+				let [true_positives, false_positives, false_negatives] = *setting_outcomes.next().unwrap();
+				points.push( calibration_point( algorithm, colour_threshold, std_dev_threshold, true_positives, false_positives, false_negatives ) );
+			}
+		}
+	}
+	points
+}
+
+/// Measure the statistics of a pair of images that the duplicate decision depends on
+fn measure_pair( a: &ImageHashAV, b: &ImageHashAV, is_duplicate: bool ) -> PairStats {
+	PairStats {
+		colour_diff : a.diff_colour( b ),
+		hamming_distance : distance::hamming_distance( a.grey_hash, b.grey_hash ),
+		similar_aspect_ratio : a.has_similar_aspect_ratio( b ),
+		min_std_dev : a.std_dev.min( b.std_dev ),
+		is_duplicate,
+	}
+}
+
+/// Count the outcome of the pair for every combination of settings, in the order they are tested
+fn count_outcomes( outcomes: &mut Outcomes, s: &PairStats ) {
+	let mut setting = 0;
+	for algorithm in ALGORITHMS {
+		for std_dev_threshold in STD_DEV_THRESHOLDS {
+			for colour_threshold in COLOUR_THRESHOLDS {
+				let mut predicted = s.min_std_dev > std_dev_threshold && s.similar_aspect_ratio && s.colour_diff <= colour_threshold;
+				if algorithm == Algorithm::Hamming {
+					predicted = predicted && s.hamming_distance <= 1;
+				}
+				match (predicted, s.is_duplicate) {
+					(true, true) => outcomes[setting][0] += 1,
+					(true, false) => outcomes[setting][1] += 1,
+					(false, true) => outcomes[setting][2] += 1,
+					(false, false) => {},
+				}
+				setting += 1;
+			}
+		}
+	}
+}
+
+/// Add together the outcomes counted by two threads
+fn add_outcomes( mut a: Outcomes, b: Outcomes ) -> Outcomes {
+	for (counts_a, counts_b) in a.iter_mut().zip( b ) {
+		for (count_a, count_b) in counts_a.iter_mut().zip( counts_b ) {
+			*count_a += count_b;
+		}
+	}
+	a
+}
+
+/// Calculate precision, recall and F1 from the counts of outcomes
+fn calibration_point( algorithm: Algorithm, colour_threshold: u64, std_dev_threshold: f32, true_positives: u64, false_positives: u64, false_negatives: u64 ) -> CalibrationPoint {
+	let ratio = |n: u64, d: u64| if d == 0 { 0.0 } else { n as f64 / d as f64 };
+	let precision = ratio( true_positives, true_positives + false_positives );
+	let recall = ratio( true_positives, true_positives + false_negatives );
+	let f1 = if precision + recall > 0.0 { 2.0 * precision * recall / (precision + recall) } else { 0.0 };
+	CalibrationPoint { algorithm, colour_threshold, std_dev_threshold, precision, recall, f1 }
+}
+
+/// The settings with the highest F1 score. Where scores are equal prefers the first tested, which is the lowest threshold.
+pub fn recommend( points: &[CalibrationPoint] ) -> Option<&CalibrationPoint> {
+	let mut best : Option<&CalibrationPoint> = None;
+	for point in points {
+		if best.is_none_or( |b| point.f1 > b.f1 ) {
+			best = Some(point);
+		}
+	}
+	best
+}
+
+/// Print the precision, recall and F1 curves followed by the recommended settings
+pub fn print_report( points: &[CalibrationPoint], config: &ConfigOptions, num_duplicate_pairs: usize, num_pairs: usize ) {
+	println!("Labelled pairs: {} ({} duplicate pairs)", num_pairs, num_duplicate_pairs);
+	for algorithm in ALGORITHMS {
+		for std_dev_threshold in STD_DEV_THRESHOLDS {
+			println!();
+			println!("Algorithm: {} Std dev threshold: {}", algorithm.name(), std_dev_threshold);
+			println!("{:>10} {:>10} {:>10} {:>10}", "Threshold", "Precision", "Recall", "F1");
+			for p in points.iter().filter( |p| p.algorithm == algorithm && p.std_dev_threshold == std_dev_threshold ) {
+				let marker = if p.colour_threshold == config.colour_difference_threshold && p.std_dev_threshold == config.std_dev_threshold { " (current)" } else { "" };
+				println!("{:>10} {:>10.4} {:>10.4} {:>10.4}{}", p.colour_threshold, p.precision, p.recall, p.f1, marker);
+			}
+		}
+	}
+
+	println!();
+	match recommend( points ) {
+		Some(best) => {
+			println!("Recommended: --colour-diff-threshold {} --std-dev-threshold {} (Precision: {:.4} Recall: {:.4} F1: {:.4})",
+				best.colour_threshold, best.std_dev_threshold, best.precision, best.recall, best.f1);
+			if best.algorithm == Algorithm::ColourDiff {
+				println!("The colour difference algorithm performed best. Consider --force-colour-diff-only on collections of more than {} images.", config.alg_flip_threshold);
+			}else{
+				println!("The hamming algorithm performed best, which is used automatically on collections of more than {} images.", config.alg_flip_threshold);
+			}
+		},
+		None => println!("No labelled pairs to calibrate with."),
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Test labels are extracted from the unit_test_images naming convention
+	#[test]
+	fn test_label_from_file_name() {
+		assert_eq!( label_from_file_name( Path::new( "unit_test_images/cat1_best.jpg" ) ), Some("cat1".to_string()) );
+		assert_eq!( label_from_file_name( Path::new( "unit_test_images/cat1_duplicate_2.png" ) ), Some("cat1".to_string()) );
+		assert_eq!( label_from_file_name( Path::new( "some_dir/holiday_best_duplicate_1.jpg" ) ), Some("holiday_best".to_string()) );
+		assert_eq!( label_from_file_name( Path::new( "unit_test_images/cat1_duplicate_x.jpg" ) ), None );
+		assert_eq!( label_from_file_name( Path::new( "unit_test_images/random.jpg" ) ), None );
+
+		let paths : Vec<PathBuf> = ["a_best.jpg", "a_duplicate_1.jpg", "b_best.jpg", "other.jpg"].iter().map( PathBuf::from ).collect();
+		let pairs = Pairs::from_file_names( &paths );
+		assert_eq!( pairs.count(), (6, 1), "Every pair counted, only the a images are duplicates" );
+	}
+
+	/// Test quoted CSV fields can contain commas and quotes
+	#[test]
+	fn test_split_csv_line() {
+		assert_eq!( split_csv_line( "a.jpg, b.jpg ,1" ), vec![ "a.jpg", "b.jpg", "1" ] );
+		assert_eq!( split_csv_line( "\"holiday, 2019/a.jpg\",\"say \"\"cheese\"\".jpg\",0" ), vec![ "holiday, 2019/a.jpg", "say \"cheese\".jpg", "0" ] );
+		assert_eq!( split_csv_line( "a.jpg,b.jpg" ).len(), 2 );
+	}
+
+	/// Test the precision, recall and F1 calculation
+	#[test]
+	fn test_calibration_point() {
+		let point = calibration_point( Algorithm::ColourDiff, 256, 3.0, 8, 2, 8 );
+		assert!( (point.precision - 0.8).abs() < 1e-9, "Precision" );
+		assert!( (point.recall - 0.5).abs() < 1e-9, "Recall" );
+		assert!( (point.f1 - (2.0 * 0.8 * 0.5 / 1.3)).abs() < 1e-9, "F1" );
+
+		let empty = calibration_point( Algorithm::Hamming, 0, 0.0, 0, 0, 0 );
+		assert_eq!( empty.f1, 0.0, "No positives gives zero F1" );
+	}
+
+	/// Test that the sweep over a labelled set recommends a setting that finds the duplicates
+	#[test]
+	fn test_sweep() {
+		let paths = [ "unit_test_images/cat1_best.jpg", "unit_test_images/cat1_duplicate_1.jpg", "unit_test_images/bridge1_best.jpg", "unit_test_images/bridge1_duplicate_1.jpg" ];
+		let images : Vec<ImageHashAV> = paths.iter().map( |p| ImageHashAV::new( &crate::imagehash::ImagePath { fpath: p.into(), ..Default::default() }, 0, 0 ).unwrap() ).collect();
+		let path_bufs : Vec<PathBuf> = paths.iter().map( PathBuf::from ).collect();
+		let pairs = Pairs::from_file_names( &path_bufs );
+
+		let points = sweep( &images, &pairs );
+		assert_eq!( points.len(), NUM_SETTINGS, "Every combination tested" );
+
+		let best = recommend( &points ).unwrap();
+		assert_eq!( best.f1, 1.0, "Duplicates perfectly separated" );
+
+		//The same pairs listed explicitly give the same results
+		let listed = Pairs::Listed( vec![ LabelledPair { a: 0, b: 1, is_duplicate: true }, LabelledPair { a: 0, b: 2, is_duplicate: false }, LabelledPair { a: 0, b: 3, is_duplicate: false },
+			LabelledPair { a: 1, b: 2, is_duplicate: false }, LabelledPair { a: 1, b: 3, is_duplicate: false }, LabelledPair { a: 2, b: 3, is_duplicate: true } ] );
+		let listed_points = sweep( &images, &listed );
+		assert!( points.iter().zip( &listed_points ).all( |(p, l)| p.f1 == l.f1 && p.precision == l.precision ), "Same outcomes" );
+	}
+}
@@ -0,0 +1,151 @@
+use std::io::{BufRead, Seek};
+use image::{DynamicImage, ImageDecoder, ImageReader, ImageResult, Limits};
+use moxcms::{ColorProfile, DataColorSpace, Layout, TransformOptions};
+
+use crate::formats;
+
+/// Decode an image along with its embedded ICC colour profile, if it has one. Fails with a limits error if the decoded image would be larger than the limits allow.
+pub fn decode_with_profile<R: BufRead + Seek>( reader: ImageReader<R>, mut limits: Limits ) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+	let mut decoder = formats::into_decoder( reader )?;
+	limits.reserve( decoder.total_bytes() )?;
+	decoder.set_limits( limits )?;
+	let icc_profile = decoder.icc_profile().ok().flatten();
+	let img = DynamicImage::from_decoder( decoder )?;
+	Ok((img, icc_profile))
+}
+
+/// Convert an image to 8 bit sRGB so that colours are compared as they are seen, regardless of the colour space or bit depth of the file.
+/// Wide gamut images (e.g. Display P3 or Adobe RGB) are converted to sRGB using their ICC profile. High bit depth images are converted from their full precision.
+/// Profiles that don't match the decoded pixels are ignored, e.g. CMYK JPEGs are already converted to RGB by the decoder so their CMYK profile can't be applied.
+pub fn normalise( img: DynamicImage, icc_profile: Option<&[u8]> ) -> DynamicImage {
+
+	let profile = match icc_profile.and_then( |icc| ColorProfile::new_from_slice( icc ).ok() ) {
+		Some(profile) => profile,
+		None => return to_8_bit( img ),
+	};
+
+	let has_alpha = img.color().has_alpha();
+	let is_rgb = !matches!( img, DynamicImage::ImageLuma8(_) | DynamicImage::ImageLumaA8(_) | DynamicImage::ImageLuma16(_) | DynamicImage::ImageLumaA16(_) );
+	match (profile.color_space, is_rgb) {
+		(DataColorSpace::Rgb, true) => {},
+		//Greyscale images are converted to RGB as the grey profile may have a different tone curve to sRGB
+		(DataColorSpace::Gray, false) => {},
+		_ => return to_8_bit( img ),
+	}
+
+	let converted = if img.color().bytes_per_pixel() / img.color().channel_count() > 1 {
+		convert_16_bit( &img, &profile, is_rgb, has_alpha )
+	}else{
+		convert_8_bit( &img, &profile, is_rgb, has_alpha )
+	};
+	converted.unwrap_or_else( || to_8_bit( img ) )
+}
+
+/// Convert to 8 bits per channel, keeping the alpha channel if there is one
+fn to_8_bit( img: DynamicImage ) -> DynamicImage {
+	match img {
+		DynamicImage::ImageRgb8(_) | DynamicImage::ImageRgba8(_) => img,
+		_ if img.color().has_alpha() => DynamicImage::ImageRgba8( img.to_rgba8() ),
+		_ => DynamicImage::ImageRgb8( img.to_rgb8() ),
+	}
+}
+
+/// The layouts of the source and sRGB destination pixels for a transform
+fn layouts( is_rgb: bool, has_alpha: bool ) -> (Layout, Layout) {
+	match (is_rgb, has_alpha) {
+		(true, false) => (Layout::Rgb, Layout::Rgb),
+		(true, true) => (Layout::Rgba, Layout::Rgba),
+		(false, false) => (Layout::Gray, Layout::Rgb),
+		(false, true) => (Layout::GrayAlpha, Layout::Rgba),
+	}
+}
+
+/// Convert an 8 bit image to sRGB
+fn convert_8_bit( img: &DynamicImage, profile: &ColorProfile, is_rgb: bool, has_alpha: bool ) -> Option<DynamicImage> {
+	let (src_layout, dst_layout) = layouts( is_rgb, has_alpha );
+	let transform = profile.create_transform_8bit( src_layout, &ColorProfile::new_srgb(), dst_layout, TransformOptions::default() ).ok()?;
+	let (width, height) = (img.width(), img.height());
+	let src = match (is_rgb, has_alpha) {
+		(true, false) => img.to_rgb8().into_raw(),
+		(true, true) => img.to_rgba8().into_raw(),
+		(false, false) => img.to_luma8().into_raw(),
+		(false, true) => img.to_luma_alpha8().into_raw(),
+	};
+	let mut dst = vec![ 0u8; width as usize * height as usize * if has_alpha { 4 } else { 3 } ];
+	transform.transform( &src, &mut dst ).ok()?;
+	if has_alpha {
+		image::RgbaImage::from_raw( width, height, dst ).map( DynamicImage::ImageRgba8 )
+	}else{
+		image::RgbImage::from_raw( width, height, dst ).map( DynamicImage::ImageRgb8 )
+	}
+}
+
+/// Convert a high bit depth image to sRGB at 16 bits and then reduce it to 8 bits
+fn convert_16_bit( img: &DynamicImage, profile: &ColorProfile, is_rgb: bool, has_alpha: bool ) -> Option<DynamicImage> {
+	let (src_layout, dst_layout) = layouts( is_rgb, has_alpha );
+	let transform = profile.create_transform_16bit( src_layout, &ColorProfile::new_srgb(), dst_layout, TransformOptions::default() ).ok()?;
+	let (width, height) = (img.width(), img.height());
+	let src = match (is_rgb, has_alpha) {
+		(true, false) => img.to_rgb16().into_raw(),
+		(true, true) => img.to_rgba16().into_raw(),
+		(false, false) => img.to_luma16().into_raw(),
+		(false, true) => img.to_luma_alpha16().into_raw(),
+	};
+	let mut dst = vec![ 0u16; width as usize * height as usize * if has_alpha { 4 } else { 3 } ];
+	transform.transform( &src, &mut dst ).ok()?;
+	let converted = if has_alpha {
+		image::ImageBuffer::from_raw( width, height, dst ).map( DynamicImage::ImageRgba16 )?
+	}else{
+		image::ImageBuffer::from_raw( width, height, dst ).map( DynamicImage::ImageRgb16 )?
+	};
+	Some(to_8_bit( converted ))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::io::Cursor;
+	use std::path::Path;
+	use image::ImageEncoder;
+	use image::codecs::png::PngEncoder;
+	use crate::imagehash::{self, ImageHashAV, ImagePath};
+
+	/// Encode an image as a PNG with an embedded ICC profile
+	fn encode_png( img: &DynamicImage, icc_profile: Option<Vec<u8>> ) -> Vec<u8> {
+		let mut data = Vec::new();
+		let mut encoder = PngEncoder::new( &mut data );
+		if let Some(icc_profile) = icc_profile {
+			encoder.set_icc_profile( icc_profile ).unwrap();
+		}
+		encoder.write_image( img.as_bytes(), img.width(), img.height(), img.color().into() ).unwrap();
+		data
+	}
+
+	/// Test wide gamut and 16 bit images are compared by their colour in sRGB
+	#[test]
+	fn test_colour_normalisation() {
+		let config = crate::get_default_config_options();
+		let original = imagehash::load_image_from_file( Path::new( "unit_test_images/cat1_best.jpg" ) ).unwrap().to_rgb8();
+		let hash = |data: &[u8]| ImageHashAV::from_data( data, &ImagePath { fpath: "cat.png".into(), ..Default::default() }, 0, 0, crate::frames::FramePolicy::First ).unwrap();
+		let srgb = hash( &encode_png( &DynamicImage::ImageRgb8( original.clone() ), None ) );
+
+		//Convert the pixels to Display P3, as a wide gamut camera would save them
+		let p3 = ColorProfile::new_display_p3();
+		let transform = ColorProfile::new_srgb().create_transform_8bit( Layout::Rgb, &p3, Layout::Rgb, TransformOptions::default() ).unwrap();
+		let mut p3_pixels = vec![ 0u8; original.as_raw().len() ];
+		transform.transform( original.as_raw(), &mut p3_pixels ).unwrap();
+		let p3_img = DynamicImage::ImageRgb8( image::RgbImage::from_raw( original.width(), original.height(), p3_pixels ).unwrap() );
+
+		let managed = hash( &encode_png( &p3_img, Some(p3.encode().unwrap()) ) );
+		let unmanaged = hash( &encode_png( &p3_img, None ) );
+		assert!( managed.diff_colour( &srgb ) < unmanaged.diff_colour( &srgb ) / 2, "Colour difference reduced by using the profile" );
+		assert!( managed.is_dupe( &srgb, &config ) );
+
+		let deep = hash( &encode_png( &DynamicImage::ImageRgb16( DynamicImage::ImageRgb8( original.clone() ).to_rgb16() ), None ) );
+		assert!( deep.diff_colour( &srgb ) < 16, "16 bit image has the same colours" );
+
+		let (decoded, icc) = decode_with_profile( ImageReader::new( Cursor::new( encode_png( &p3_img, Some(p3.encode().unwrap()) ) ) ).with_guessed_format().unwrap(), Limits::default() ).unwrap();
+		assert!( icc.is_some(), "Profile read from the file" );
+		assert!( matches!( normalise( decoded, None ), DynamicImage::ImageRgb8(_) ) );
+	}
+}
@@ -0,0 +1,167 @@
+/// Sum of the absolute differences between the bytes of two equal length slices, e.g. the colour channels of two low resolution images.
+/// Called for every pair of images compared, so uses SIMD instructions where the CPU has them.
+pub fn sum_abs_diff( a: &[u8], b: &[u8] ) -> u64 {
+	assert_eq!( a.len(), b.len() );
+
+	#[cfg(target_arch = "x86_64")]
+	{
+		if is_x86_feature_detected!( "avx2" ) {
+			//Safe as the CPU supports AVX2
+			return unsafe { x86::sum_abs_diff_avx2( a, b ) };
+		}
+		//SSE2 is part of the x86_64 baseline
+		return unsafe { x86::sum_abs_diff_sse2( a, b ) };
+	}
+
+	#[cfg(target_arch = "aarch64")]
+	{
+		//NEON is part of the aarch64 baseline
+		return unsafe { arm::sum_abs_diff_neon( a, b ) };
+	}
+
+	#[allow(unreachable_code)]
+	sum_abs_diff_scalar( a, b )
+}
+
+/// Scalar version of sum_abs_diff, gives identical results
+pub fn sum_abs_diff_scalar( a: &[u8], b: &[u8] ) -> u64 {
+	a.iter().zip( b.iter() ).map( |(a, b)| a.abs_diff( *b ) as u64 ).sum()
+}
+
+/// Number of bits that differ between two hashes
+pub fn hamming_distance( a: u64, b: u64 ) -> u32 {
+
+	#[cfg(target_arch = "x86_64")]
+	{
+		if is_x86_feature_detected!( "popcnt" ) {
+			//Safe as the CPU supports POPCNT
+			return unsafe { x86::popcount( a ^ b ) };
+		}
+	}
+
+	hamming_distance_scalar( a, b )
+}
+
+/// Scalar version of hamming_distance for CPUs without a population count instruction, gives identical results
+pub fn hamming_distance_scalar( a: u64, b: u64 ) -> u32 {
+	//Sum the bits in pairs, then nibbles, then add up the bytes
+	let x = a ^ b;
+	let x = x - ( ( x >> 1 ) & 0x5555_5555_5555_5555 );
+	let x = ( x & 0x3333_3333_3333_3333 ) + ( ( x >> 2 ) & 0x3333_3333_3333_3333 );
+	let x = ( x + ( x >> 4 ) ) & 0x0f0f_0f0f_0f0f_0f0f;
+	( x.wrapping_mul( 0x0101_0101_0101_0101 ) >> 56 ) as u32
+}
+
+#[cfg(target_arch = "x86_64")]
+mod x86 {
+	use std::arch::x86_64::*;
+
+	/// Sum of absolute differences 32 bytes at a time using VPSADBW
+	#[target_feature(enable = "avx2")]
+	pub unsafe fn sum_abs_diff_avx2( a: &[u8], b: &[u8] ) -> u64 {
+		let mut total = _mm256_setzero_si256();
+		let chunks = a.len() / 32;
+		for i in 0..chunks {
+			let va = _mm256_loadu_si256( a.as_ptr().add( i * 32 ) as *const __m256i );
+			let vb = _mm256_loadu_si256( b.as_ptr().add( i * 32 ) as *const __m256i );
+			total = _mm256_add_epi64( total, _mm256_sad_epu8( va, vb ) );
+		}
+		let mut lanes = [0u64; 4];
+		_mm256_storeu_si256( lanes.as_mut_ptr() as *mut __m256i, total );
+		lanes.iter().sum::<u64>() + sum_abs_diff_sse2( &a[chunks * 32..], &b[chunks * 32..] )
+	}
+
+	/// Sum of absolute differences 16 bytes at a time using PSADBW
+	#[target_feature(enable = "sse2")]
+	pub unsafe fn sum_abs_diff_sse2( a: &[u8], b: &[u8] ) -> u64 {
+		let mut total = _mm_setzero_si128();
+		let chunks = a.len() / 16;
+		for i in 0..chunks {
+			let va = _mm_loadu_si128( a.as_ptr().add( i * 16 ) as *const __m128i );
+			let vb = _mm_loadu_si128( b.as_ptr().add( i * 16 ) as *const __m128i );
+			total = _mm_add_epi64( total, _mm_sad_epu8( va, vb ) );
+		}
+		let mut lanes = [0u64; 2];
+		_mm_storeu_si128( lanes.as_mut_ptr() as *mut __m128i, total );
+		lanes[0] + lanes[1] + super::sum_abs_diff_scalar( &a[chunks * 16..], &b[chunks * 16..] )
+	}
+
+	/// Population count using the POPCNT instruction
+	#[target_feature(enable = "popcnt")]
+	pub unsafe fn popcount( x: u64 ) -> u32 {
+		x.count_ones()
+	}
+}
+
+#[cfg(target_arch = "aarch64")]
+mod arm {
+	use std::arch::aarch64::*;
+
+	/// Sum of absolute differences 16 bytes at a time using UABD, widening and adding across the lanes for each block
+	#[target_feature(enable = "neon")]
+	pub unsafe fn sum_abs_diff_neon( a: &[u8], b: &[u8] ) -> u64 {
+		let mut total : u64 = 0;
+		let chunks = a.len() / 16;
+		for i in 0..chunks {
+			let va = vld1q_u8( a.as_ptr().add( i * 16 ) );
+			let vb = vld1q_u8( b.as_ptr().add( i * 16 ) );
+			total += vaddlvq_u8( vabdq_u8( va, vb ) ) as u64;
+		}
+		total + super::sum_abs_diff_scalar( &a[chunks * 16..], &b[chunks * 16..] )
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use proptest::prelude::*;
+
+	/// The colour difference as originally calculated, one pixel at a time
+	fn diff_per_pixel( a: &[u8;192], b: &[u8;192] ) -> u64 {
+		let mut diff: u64 = 0;
+		for i in 0..64 {
+			let rdiff : u32 = (b[i*3] as i32 - a[i*3] as i32).unsigned_abs();
+			let gdiff : u32 = (b[(i*3)+1] as i32 - a[(i*3)+1] as i32).unsigned_abs();
+			let bdiff : u32 = (b[(i*3)+2] as i32 - a[(i*3)+2] as i32).unsigned_abs();
+			diff += ( rdiff + gdiff + bdiff ) as u64;
+		}
+		diff
+	}
+
+	/// Number of differing bits counted one bit at a time
+	fn bits_differing( a: u64, b: u64 ) -> u32 {
+		(0..64).filter( |i| (a >> i) & 1 != (b >> i) & 1 ).count() as u32
+	}
+
+	proptest! {
+		/// Test the SIMD sum of absolute differences matches the scalar code for low resolution images, alpha masks and lengths that leave a remainder
+		#[test]
+		fn test_sum_abs_diff_equivalent( pixels in proptest::collection::vec( (any::<u8>(), any::<u8>()), 192 ), len in 0usize..=192 ) {
+			let (a, b) : (Vec<u8>, Vec<u8>) = pixels.into_iter().unzip();
+			let low_res_a : [u8;192] = a.clone().try_into().unwrap();
+			let low_res_b : [u8;192] = b.clone().try_into().unwrap();
+			prop_assert_eq!( sum_abs_diff( &a, &b ), diff_per_pixel( &low_res_a, &low_res_b ) );
+			prop_assert_eq!( sum_abs_diff( &a[..64], &b[..64] ), sum_abs_diff_scalar( &a[..64], &b[..64] ) );
+			prop_assert_eq!( sum_abs_diff( &a[..len], &b[..len] ), sum_abs_diff_scalar( &a[..len], &b[..len] ) );
+			//SSE2 is otherwise only used for the remainder on CPUs with AVX2
+			#[cfg(target_arch = "x86_64")]
+			prop_assert_eq!( unsafe { x86::sum_abs_diff_sse2( &a[..len], &b[..len] ) }, sum_abs_diff_scalar( &a[..len], &b[..len] ) );
+		}
+
+		/// Test the Hamming distance matches counting the bits one at a time
+		#[test]
+		fn test_hamming_distance_equivalent( a in any::<u64>(), b in any::<u64>() ) {
+			prop_assert_eq!( hamming_distance( a, b ), bits_differing( a, b ) );
+			prop_assert_eq!( hamming_distance_scalar( a, b ), bits_differing( a, b ) );
+		}
+	}
+
+	/// Test the extremes, where every byte and bit differs
+	#[test]
+	fn test_distance_extremes() {
+		assert_eq!( sum_abs_diff( &[0u8;192], &[255u8;192] ), 192 * 255 );
+		assert_eq!( sum_abs_diff( &[7u8;192], &[7u8;192] ), 0 );
+		assert_eq!( hamming_distance( 0, u64::MAX ), 64 );
+		assert_eq!( hamming_distance_scalar( u64::MAX, u64::MAX ), 0 );
+	}
+}
@@ -0,0 +1,129 @@
+use std::fs::File;
+use std::io::{BufRead, Read, Seek};
+use std::path::Path;
+use image::{ImageDecoder, ImageFormat, ImageReader, ImageResult};
+use jxl_oxide::integration::JxlDecoder;
+
+use crate::raw;
+
+/// How files of a format are decoded
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Decoder {
+	/// Decoded by the image crate
+	Image(ImageFormat),
+	/// JPEG XL, decoded by jxl-oxide as the image crate can't read it
+	Jxl,
+	/// Camera RAW, decoded from the embedded preview
+	Raw,
+}
+
+/// An image file format that photodedupe can read
+pub struct FileFormat {
+	/// Name of the format
+	pub name : &'static str,
+	/// Lower case file name extensions of the format
+	pub extensions : &'static [&'static str],
+	/// How files of the format are decoded
+	pub decoder : Decoder,
+}
+
+/// The supported image file formats. Files with these extensions are tested unless --any-file is used.
+/// To support a new format add an entry with a decoder that can read it.
+pub const FORMATS : [FileFormat; 11] = [
+	FileFormat { name: "JPEG", extensions: &[ "jpg", "jpeg" ], decoder: Decoder::Image(ImageFormat::Jpeg) },
+	FileFormat { name: "PNG", extensions: &[ "png" ], decoder: Decoder::Image(ImageFormat::Png) },
+	FileFormat { name: "TIFF", extensions: &[ "tif", "tiff" ], decoder: Decoder::Image(ImageFormat::Tiff) },
+	FileFormat { name: "GIF", extensions: &[ "gif" ], decoder: Decoder::Image(ImageFormat::Gif) },
+	FileFormat { name: "WebP", extensions: &[ "webp" ], decoder: Decoder::Image(ImageFormat::WebP) },
+	FileFormat { name: "BMP", extensions: &[ "bmp" ], decoder: Decoder::Image(ImageFormat::Bmp) },
+	FileFormat { name: "QOI", extensions: &[ "qoi" ], decoder: Decoder::Image(ImageFormat::Qoi) },
+	FileFormat { name: "PNM", extensions: &[ "pbm", "pgm", "ppm", "pnm", "pam" ], decoder: Decoder::Image(ImageFormat::Pnm) },
+	FileFormat { name: "TGA", extensions: &[ "tga" ], decoder: Decoder::Image(ImageFormat::Tga) },
+	FileFormat { name: "JPEG XL", extensions: &[ "jxl" ], decoder: Decoder::Jxl },
+	FileFormat { name: "Camera RAW", extensions: &raw::RAW_EXTENSIONS, decoder: Decoder::Raw },
+];
+
+/// Find the format of a file from its extension (case insensitive)
+pub fn format_from_path( fpath: &Path ) -> Option<&'static FileFormat> {
+	let extension = fpath.extension()?.to_str()?.to_lowercase();
+	FORMATS.iter().find( |f| f.extensions.contains( &extension.as_str() ) )
+}
+
+/// Signatures at the start of a JPEG XL file, either a bare codestream or the ISO BMFF container
+const JXL_SIGNATURES : [&[u8]; 2] = [ &[ 0xFF, 0x0A ], &[ 0, 0, 0, 0x0C, b'J', b'X', b'L', b' ', 0x0D, 0x0A, 0x87, 0x0A ] ];
+
+/// Create the decoder for an image whose format has been guessed from its content or extension.
+/// Content the image crate can't identify is tried as JPEG XL, which the image crate doesn't decode.
+pub fn into_decoder<'a, R: BufRead + Seek + 'a>( reader: ImageReader<R> ) -> ImageResult<Box<dyn ImageDecoder + 'a>> {
+	match reader.format() {
+		Some(_) => Ok(Box::new( reader.into_decoder()? )),
+		None => Ok(Box::new( JxlDecoder::new( reader.into_inner() )? )),
+	}
+}
+
+/// Number of bytes read from the start of a file to identify the format from its magic number
+const HEADER_SIZE : usize = 32;
+
+/// Identify whether a file is an image from the magic number at the start of the file, without decoding it.
+/// Formats without a magic number (e.g. TGA) can't be identified this way.
+pub fn sniff_image( fpath: &Path ) -> bool {
+	match File::open( fpath ) {
+		Ok(file) => sniff_image_reader( file ),
+		Err(_) => false,
+	}
+}
+
+/// Identify whether the file being read is an image from its magic number, as sniff_image e.g. for files inside archives
+pub fn sniff_image_reader( reader: impl Read ) -> bool {
+	let mut header = Vec::with_capacity( HEADER_SIZE );
+	if reader.take( HEADER_SIZE as u64 ).read_to_end( &mut header ).is_err() {
+		return false;
+	}
+	image::guess_format( &header ).is_ok() || header.starts_with( b"FUJIFILMCCD-RAW" ) || JXL_SIGNATURES.iter().any( |signature| header.starts_with( signature ) )
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::imagehash;
+
+	/// Test formats are found by extension
+	#[test]
+	fn test_format_from_path() {
+		assert_eq!( format_from_path( Path::new("a/photo.JPG") ).unwrap().name, "JPEG" );
+		assert_eq!( format_from_path( Path::new("scan.pgm") ).unwrap().decoder, Decoder::Image(ImageFormat::Pnm) );
+		assert_eq!( format_from_path( Path::new("IMG_1234.CR2") ).unwrap().decoder, Decoder::Raw );
+		assert_eq!( format_from_path( Path::new("photo.JXL") ).unwrap().decoder, Decoder::Jxl );
+		assert!( format_from_path( Path::new("notes.txt") ).is_none() );
+		assert!( format_from_path( Path::new("no_extension") ).is_none() );
+	}
+
+	/// Test images are identified by their content regardless of file name
+	#[test]
+	fn test_sniff_image() {
+		let dir = std::env::temp_dir();
+		let image_path = dir.join( "photodedupe_sniff_test.dat" );
+		let text_path = dir.join( "photodedupe_sniff_test.txt" );
+		std::fs::copy( "unit_test_images/cat1_best.jpg", &image_path ).unwrap();
+		std::fs::write( &text_path, "Not an image" ).unwrap();
+
+		assert!( sniff_image( &image_path ), "JPEG identified without an extension" );
+		assert!( sniff_image( Path::new("unit_test_images/format1_duplicate_1.qoi") ), "QOI identified" );
+		assert!( sniff_image( Path::new("unit_test_images/format3_duplicate_1.jxl") ), "JPEG XL identified" );
+		assert!( !sniff_image( &text_path ), "Text file is not an image" );
+		assert!( !sniff_image( &dir.join( "photodedupe_missing_file" ) ), "Missing file is not an image" );
+
+		std::fs::remove_file( image_path ).unwrap();
+		std::fs::remove_file( text_path ).unwrap();
+	}
+
+	/// Test an example of each format in unit_test_images can be decoded
+	#[test]
+	fn test_decode_each_format() {
+		for path in [ "unit_test_images/format1_best.bmp", "unit_test_images/format1_duplicate_1.qoi", "unit_test_images/format1_duplicate_2.tga", "unit_test_images/format3_duplicate_1.jxl",
+					"unit_test_images/format2_best.ppm", "unit_test_images/format2_duplicate_1.pam", "unit_test_images/format2_duplicate_2.pnm" ] {
+			let img = imagehash::load_image_from_file( Path::new( path ) );
+			assert!( img.is_ok(), "Decoded {}", path );
+		}
+	}
+}
@@ -0,0 +1,403 @@
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use image::{AnimationDecoder, DynamicImage, ImageBuffer, ImageDecoder, ImageError, ImageFormat, ImageReader};
+use image::codecs::gif::GifDecoder;
+use image::codecs::png::PngDecoder;
+use image::codecs::webp::WebPDecoder;
+use tiff::{ColorType, TiffError};
+use tiff::decoder::{Decoder as TiffDecoder, DecodingResult, Limits as TiffLimits};
+
+use crate::colour;
+use crate::formats::{self, Decoder};
+use crate::image_error::MyImageError;
+use crate::memory;
+
+/// Which frames of animated images (GIF, APNG, WebP) and pages of multi-page TIFFs are compared
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum FramePolicy {
+	/// Only the first frame, so an animation is a duplicate of a still of its first frame
+	First,
+	/// Every frame, files are only duplicates if they have the same number of frames and all of them match
+	All,
+	/// Frames sampled evenly through the animation, files are duplicates if all the sampled frames match
+	Sample,
+}
+
+impl FramePolicy {
+	/// Parse the name of a policy as used on the command line
+	pub fn from_name( name: &str ) -> Option<FramePolicy> {
+		match name.to_lowercase().as_str() {
+			"first" => Some(FramePolicy::First),
+			"all" => Some(FramePolicy::All),
+			"sample" => Some(FramePolicy::Sample),
+			_ => None,
+		}
+	}
+
+	/// The indexes of the frames compared under the policy given the number of frames decoded. The first frame is always included.
+	pub fn frame_indexes( &self, num_frames: usize ) -> Vec<usize> {
+		match self {
+			FramePolicy::First => vec![ 0 ],
+			FramePolicy::All => (0..num_frames).collect(),
+			FramePolicy::Sample => {
+				if num_frames <= SAMPLE_FRAMES {
+					(0..num_frames).collect()
+				}else{
+					//Always includes the first and last frames
+					(0..SAMPLE_FRAMES).map( |i| i * (num_frames - 1) / (SAMPLE_FRAMES - 1) ).collect()
+				}
+			},
+		}
+	}
+}
+
+/// Number of frames compared when sampling an animation
+pub const SAMPLE_FRAMES : usize = 5;
+
+/// Maximum number of frames compared when comparing every frame, later frames are ignored with a warning
+pub const MAX_FRAMES : usize = 1000;
+
+/// Iterator over the decoded frames of an image
+pub type Frames = Box<dyn Iterator<Item = Result<DynamicImage, MyImageError>>>;
+
+/// Decode the frames of an image that may have more than one (GIF, APNG, WebP and TIFF).
+/// Returns None if the format only ever holds a single image, in which case the image should be decoded normally.
+/// Frames that would need the decoder to allocate more than the memory limit fail with a MemoryLimit error.
+pub fn decode_frames( data: &[u8], fpath: &Path, memory_limit: Option<u64> ) -> Option<Frames> {
+
+	let format = frame_format( data, fpath )?;
+	let path = fpath.to_path_buf();
+	let decode_error = move |e| match e {
+		ImageError::Limits(_) => memory::limit_error( &path ),
+		_ => MyImageError::DecodeFail(format!("Error: Failed to correctly decode frame of image: {}", path.display())),
+	};
+	let limits = memory::decoder_limits( memory_limit );
+	let reader = Cursor::new( data.to_vec() );
+
+	match format {
+		ImageFormat::Gif => {
+			let mut decoder = GifDecoder::new( reader ).ok()?;
+			if let Err(e) = decoder.set_limits( limits ) {
+				return Some(Box::new( std::iter::once( Err(decode_error( e )) ) ));
+			}
+			Some(Box::new( decoder.into_frames().map( move |f| f.map( |f| DynamicImage::ImageRgba8( f.into_buffer() ) ).map_err( &decode_error ) ) ))
+		},
+		ImageFormat::Png => {
+			let mut decoder = PngDecoder::new( reader ).ok()?;
+			if !decoder.is_apng().ok()? {
+				return None;
+			}
+			if let Err(e) = decoder.set_limits( limits ) {
+				return Some(Box::new( std::iter::once( Err(decode_error( e )) ) ));
+			}
+			let icc_profile = decoder.icc_profile().ok().flatten();
+			let decoder = decoder.apng().ok()?;
+			Some(Box::new( decoder.into_frames().map( move |f| f.map( |f| colour::normalise( DynamicImage::ImageRgba8( f.into_buffer() ), icc_profile.as_deref() ) ).map_err( &decode_error ) ) ))
+		},
+		ImageFormat::WebP => {
+			let mut decoder = WebPDecoder::new( reader ).ok()?;
+			if !decoder.has_animation() {
+				return None;
+			}
+			if let Err(e) = decoder.set_limits( limits ) {
+				return Some(Box::new( std::iter::once( Err(decode_error( e )) ) ));
+			}
+			let icc_profile = decoder.icc_profile().ok().flatten();
+			Some(Box::new( decoder.into_frames().map( move |f| f.map( |f| colour::normalise( DynamicImage::ImageRgba8( f.into_buffer() ), icc_profile.as_deref() ) ).map_err( &decode_error ) ) ))
+		},
+		ImageFormat::Tiff => {
+			//The first page is decoded by the image crate which supports more TIFF variants than are handled for later pages.
+			//Later pages are assumed to share the colour profile of the first.
+			let (first, icc_profile) = match colour::decode_with_profile( ImageReader::with_format( Cursor::new( data ), ImageFormat::Tiff ), limits ) {
+				Ok((img, icc_profile)) => (Ok(colour::normalise( img, icc_profile.as_deref() )), icc_profile),
+				Err(e) => (Err(decode_error( e )), None),
+			};
+			let mut tiff_limits = TiffLimits::default();
+			if let Some(memory_limit) = memory_limit {
+				tiff_limits.decoding_buffer_size = memory_limit.try_into().unwrap_or( usize::MAX );
+			}
+			let decoder = TiffDecoder::new( reader ).ok()?.with_limits( tiff_limits );
+			Some(Box::new( TiffPages { decoder, first: Some(first), icc_profile, fpath: fpath.to_path_buf() } ))
+		},
+		_ => None,
+	}
+}
+
+/// Count the frames of an image without decoding them, so that frames that aren't sampled can be skipped.
+/// Returns None if the count can't be read, or the format only ever holds a single image.
+pub fn count_frames( data: &[u8], fpath: &Path ) -> Option<usize> {
+	match frame_format( data, fpath )? {
+		ImageFormat::Gif => count_gif_frames( data ),
+		ImageFormat::Png => count_apng_frames( data ),
+		ImageFormat::WebP => count_webp_frames( data ),
+		ImageFormat::Tiff => count_tiff_pages( data ),
+		_ => None,
+	}
+}
+
+/// The format of an image from its contents, or its extension if the contents aren't recognised
+fn frame_format( data: &[u8], fpath: &Path ) -> Option<ImageFormat> {
+	match image::guess_format( data ) {
+		Ok(format) => Some(format),
+		Err(_) => match formats::format_from_path( fpath ) {
+			Some(formats::FileFormat { decoder: Decoder::Image(format), .. }) => Some(*format),
+			_ => None,
+		},
+	}
+}
+
+/// Count the images in a GIF by walking its blocks, skipping over the compressed data
+fn count_gif_frames( data: &[u8] ) -> Option<usize> {
+	//Header and logical screen descriptor, followed by the global colour table if there is one
+	let flags = *data.get( 10 )?;
+	let mut pos = 13;
+	if flags & 0x80 != 0 {
+		pos += 3 << ((flags & 0x07) + 1);
+	}
+	let mut count = 0;
+	loop {
+		match *data.get( pos )? {
+			//Extension: the label and then data sub-blocks
+			0x21 => pos = skip_gif_sub_blocks( data, pos + 2 )?,
+			//Image descriptor: the local colour table if there is one, the LZW code size and then data sub-blocks
+			0x2C => {
+				let flags = *data.get( pos + 9 )?;
+				pos += 10;
+				if flags & 0x80 != 0 {
+					pos += 3 << ((flags & 0x07) + 1);
+				}
+				pos = skip_gif_sub_blocks( data, pos + 1 )?;
+				count += 1;
+			},
+			//Trailer
+			0x3B => return Some(count),
+			_ => return None,
+		}
+	}
+}
+
+/// Skip a sequence of GIF data sub-blocks, returning the position after the empty block that ends them
+fn skip_gif_sub_blocks( data: &[u8], mut pos: usize ) -> Option<usize> {
+	loop {
+		let length = *data.get( pos )? as usize;
+		pos += 1 + length;
+		if length == 0 {
+			return Some(pos);
+		}
+	}
+}
+
+/// Read the number of frames from the animation control chunk of an APNG, which comes before the image data
+fn count_apng_frames( data: &[u8] ) -> Option<usize> {
+	let mut pos = 8;
+	while let Some(header) = data.get( pos..pos + 8 ) {
+		let length = u32::from_be_bytes( [header[0], header[1], header[2], header[3]] ) as usize;
+		match &header[4..8] {
+			b"acTL" => {
+				let num_frames = data.get( pos + 8..pos + 12 )?;
+				return Some(u32::from_be_bytes( [num_frames[0], num_frames[1], num_frames[2], num_frames[3]] ) as usize);
+			},
+			b"IDAT" => return None,
+			_ => pos = pos.checked_add( length )?.checked_add( 12 )?,
+		}
+	}
+	None
+}
+
+/// Count the frame chunks of an animated WebP
+fn count_webp_frames( data: &[u8] ) -> Option<usize> {
+	if data.get( 0..4 )? != b"RIFF" || data.get( 8..12 )? != b"WEBP" {
+		return None;
+	}
+	let mut pos = 12;
+	let mut count = 0;
+	while let Some(header) = data.get( pos..pos + 8 ) {
+		if &header[0..4] == b"ANMF" {
+			count += 1;
+		}
+		//Chunks are padded to an even length
+		let size = u32::from_le_bytes( [header[4], header[5], header[6], header[7]] ) as usize;
+		pos = pos.checked_add( size )?.checked_add( 8 + (size & 1) )?;
+	}
+	Some(count)
+}
+
+/// Count the pages of a TIFF by following the chain of IFDs, without reading the image data
+fn count_tiff_pages( data: &[u8] ) -> Option<usize> {
+	let mut decoder = TiffDecoder::new( Cursor::new( data ) ).ok()?;
+	let mut count = 1;
+	while decoder.more_images() && decoder.next_image().is_ok() {
+		count += 1;
+	}
+	Some(count)
+}
+
+/// Iterates through the pages of a TIFF file. Stops at the first page that can't be decoded, or fails if a page is larger than the memory limit.
+struct TiffPages {
+	decoder : TiffDecoder<Cursor<Vec<u8>>>,
+	first : Option<Result<DynamicImage, MyImageError>>,
+	icc_profile : Option<Vec<u8>>,
+	fpath : PathBuf,
+}
+
+impl Iterator for TiffPages {
+	type Item = Result<DynamicImage, MyImageError>;
+
+	fn next( &mut self ) -> Option<Self::Item> {
+		if let Some(first) = self.first.take() {
+			return Some(first);
+		}
+		if !self.decoder.more_images() || self.decoder.next_image().is_err() {
+			return None;
+		}
+		match tiff_page_to_image( &mut self.decoder ) {
+			Ok(img) => img.map( |img| Ok(colour::normalise( img, self.icc_profile.as_deref() )) ),
+			Err(TiffError::LimitsExceeded) => Some(Err(memory::limit_error( &self.fpath ))),
+			Err(_) => None,
+		}
+	}
+
+	/// Pages that are skipped over aren't decoded
+	fn nth( &mut self, n: usize ) -> Option<Self::Item> {
+		let mut skip = n;
+		if skip > 0 && self.first.take().is_some() {
+			skip -= 1;
+		}
+		for _ in 0..skip {
+			if !self.decoder.more_images() || self.decoder.next_image().is_err() {
+				return None;
+			}
+		}
+		self.next()
+	}
+}
+
+/// Convert the current page of a TIFF to an image. Only greyscale and RGB pages of 8 or 16 bits are supported, others are None.
+fn tiff_page_to_image( decoder: &mut TiffDecoder<Cursor<Vec<u8>>> ) -> Result<Option<DynamicImage>, TiffError> {
+	let (width, height) = decoder.dimensions()?;
+	let colour_type = decoder.colortype()?;
+	Ok(match (colour_type, decoder.read_image()?) {
+		(ColorType::Gray(8), DecodingResult::U8(buf)) => ImageBuffer::from_raw( width, height, buf ).map( DynamicImage::ImageLuma8 ),
+		(ColorType::GrayA(8), DecodingResult::U8(buf)) => ImageBuffer::from_raw( width, height, buf ).map( DynamicImage::ImageLumaA8 ),
+		(ColorType::RGB(8), DecodingResult::U8(buf)) => ImageBuffer::from_raw( width, height, buf ).map( DynamicImage::ImageRgb8 ),
+		(ColorType::RGBA(8), DecodingResult::U8(buf)) => ImageBuffer::from_raw( width, height, buf ).map( DynamicImage::ImageRgba8 ),
+		(ColorType::Gray(16), DecodingResult::U16(buf)) => ImageBuffer::from_raw( width, height, buf ).map( DynamicImage::ImageLuma16 ),
+		(ColorType::GrayA(16), DecodingResult::U16(buf)) => ImageBuffer::from_raw( width, height, buf ).map( DynamicImage::ImageLumaA16 ),
+		(ColorType::RGB(16), DecodingResult::U16(buf)) => ImageBuffer::from_raw( width, height, buf ).map( DynamicImage::ImageRgb16 ),
+		(ColorType::RGBA(16), DecodingResult::U16(buf)) => ImageBuffer::from_raw( width, height, buf ).map( DynamicImage::ImageRgba16 ),
+		_ => None,
+	})
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use image::{Frame, RgbaImage};
+	use image::codecs::gif::GifEncoder;
+	use tiff::encoder::{TiffEncoder, colortype};
+	use crate::imagehash::{ImageHashAV, ImagePath};
+
+	/// Encode frames as an animated GIF
+	fn encode_gif( frames: &[RgbaImage] ) -> Vec<u8> {
+		let mut data = Vec::new();
+		let mut encoder = GifEncoder::new( &mut data );
+		encoder.encode_frames( frames.iter().map( |f| Frame::new( f.clone() ) ) ).unwrap();
+		drop(encoder);
+		data
+	}
+
+	/// Encode frames as the pages of a multi-page TIFF
+	fn encode_tiff( frames: &[RgbaImage] ) -> Vec<u8> {
+		let mut data = Cursor::new( Vec::new() );
+		let mut encoder = TiffEncoder::new( &mut data ).unwrap();
+		for frame in frames {
+			let rgb = DynamicImage::ImageRgba8( frame.clone() ).to_rgb8();
+			encoder.write_image::<colortype::RGB8>( rgb.width(), rgb.height(), rgb.as_raw() ).unwrap();
+		}
+		data.into_inner()
+	}
+
+	/// Test which frames are compared under each policy
+	#[test]
+	fn test_frame_indexes() {
+		assert_eq!( FramePolicy::First.frame_indexes( 10 ), vec![ 0 ] );
+		assert_eq!( FramePolicy::All.frame_indexes( 3 ), vec![ 0, 1, 2 ] );
+		assert_eq!( FramePolicy::Sample.frame_indexes( 3 ), vec![ 0, 1, 2 ] );
+		assert_eq!( FramePolicy::Sample.frame_indexes( 101 ), vec![ 0, 25, 50, 75, 100 ] );
+		assert_eq!( FramePolicy::from_name( "Sample" ), Some(FramePolicy::Sample) );
+		assert_eq!( FramePolicy::from_name( "every" ), None );
+	}
+
+	/// Test animations and multi-page TIFFs are matched to each other and to stills according to the policy
+	#[test]
+	fn test_frame_policies() {
+		let frames : Vec<RgbaImage> = [ "cat1_best.jpg", "bridge1_best.jpg", "car1_best.jpg" ].iter()
+			.map( |f| crate::imagehash::load_image_from_file( Path::new( &format!("unit_test_images/{}", f) ) ).unwrap().resize_exact( 120, 90, image::imageops::FilterType::Triangle ).to_rgba8() )
+			.collect();
+		let animation = encode_gif( &frames );
+		let reordered = encode_gif( &[ frames[0].clone(), frames[2].clone(), frames[1].clone() ] );
+		let pages = encode_tiff( &frames );
+		let mut still = Vec::new();
+		DynamicImage::ImageRgba8( frames[0].clone() ).write_to( &mut Cursor::new( &mut still ), ImageFormat::Png ).unwrap();
+		assert!( decode_frames( &still, Path::new( "still.png" ), None ).is_none(), "A still PNG has no frames" );
+
+		let config = crate::get_default_config_options();
+		let hash = |data: &[u8], fpath: &str, policy: FramePolicy| ImageHashAV::from_data( data, &ImagePath { fpath: fpath.into(), ..Default::default() }, 0, 0, policy ).unwrap();
+
+		let first = hash( &animation, "animation.gif", FramePolicy::First );
+		assert!( first.frames.is_empty() );
+		assert!( first.is_dupe( &hash( &still, "still.png", FramePolicy::First ), &config ), "Animation matches a still of its first frame" );
+
+		let all = hash( &animation, "animation.gif", FramePolicy::All );
+		assert_eq!( all.frames.len(), 2 );
+		assert!( all.is_dupe( &hash( &pages, "pages.tif", FramePolicy::All ), &config ), "Animation matches a multi-page TIFF of the same frames" );
+		assert!( !all.is_dupe( &hash( &still, "still.png", FramePolicy::All ), &config ), "Animation doesn't match a still" );
+		assert!( !all.is_dupe( &hash( &reordered, "reordered.gif", FramePolicy::All ), &config ), "Animation doesn't match the frames in a different order" );
+
+		let sampled = hash( &animation, "animation.gif", FramePolicy::Sample );
+		assert!( sampled.is_dupe( &hash( &pages, "pages.tif", FramePolicy::Sample ), &config ) );
+
+		//Longer animations only have the sampled frames hashed
+		let long_frames : Vec<RgbaImage> = (0..7).map( |i| frames[i % 3].clone() ).collect();
+		let long_animation = encode_gif( &long_frames );
+		let long_pages = encode_tiff( &long_frames );
+		assert_eq!( count_frames( &long_animation, Path::new( "long.gif" ) ), Some(7) );
+		assert_eq!( count_frames( &long_pages, Path::new( "long.tif" ) ), Some(7) );
+		let sampled = hash( &long_animation, "long.gif", FramePolicy::Sample );
+		assert_eq!( sampled.frames.len(), 4, "Frames 1, 3, 4 and 6 sampled after the first" );
+		assert!( sampled.is_dupe( &hash( &long_pages, "long.tif", FramePolicy::Sample ), &config ) );
+		let expected = hash( &encode_gif( &[ frames[0].clone(), frames[1].clone(), frames[0].clone(), frames[1].clone(), frames[0].clone() ] ), "expected.gif", FramePolicy::All );
+		assert!( sampled.is_dupe( &expected, &config ), "Sampled frames 0, 1, 3, 4 and 6" );
+	}
+
+	/// Test the memory limit applies to the frame decoders, and the estimate of the memory needed allows for each frame kept
+	#[test]
+	fn test_frame_memory_limit() {
+		let frame = crate::imagehash::load_image_from_file( Path::new( "unit_test_images/cat1_best.jpg" ) ).unwrap().resize_exact( 120, 90, image::imageops::FilterType::Triangle ).to_rgba8();
+		let frames = vec![ frame; 7 ];
+		let animation = encode_gif( &frames );
+		let pages = encode_tiff( &frames );
+		let path = |fpath: &str, memory_limit: Option<u64>| ImagePath { fpath: fpath.into(), memory_limit, ..Default::default() };
+
+		let one_frame = memory::estimate_decoded_size_of_data( &animation, &path( "a.gif", None ), FramePolicy::First ).unwrap();
+		assert_eq!( memory::estimate_decoded_size_of_data( &animation, &path( "a.gif", None ), FramePolicy::All ), Some(one_frame * 7) );
+		assert_eq!( memory::estimate_decoded_size_of_data( &animation, &path( "a.gif", None ), FramePolicy::Sample ), Some(one_frame * SAMPLE_FRAMES as u64) );
+
+		for (data, fpath) in [ (&animation, "a.gif"), (&pages, "a.tif") ] {
+			let mut decoded = decode_frames( data, Path::new( fpath ), Some(1 << 10) ).unwrap();
+			assert!( matches!( decoded.next(), Some(Err(MyImageError::MemoryLimit(_))) ), "Frames of {} limited", fpath );
+			let hashed = ImageHashAV::from_data( data, &path( fpath, Some(1 << 10) ), 0, 0, FramePolicy::All );
+			assert!( matches!( hashed, Err(MyImageError::MemoryLimit(_)) ) );
+			assert!( ImageHashAV::from_data( data, &path( fpath, Some(16 << 20) ), 0, 0, FramePolicy::All ).is_ok() );
+		}
+	}
+
+	/// Test frames are counted from the structure of the file
+	#[test]
+	fn test_count_frames() {
+		assert_eq!( count_webp_frames( b"RIFF\0\0\0\0WEBPANMF\x01\0\0\0x\0ANMF\0\0\0\0" ), Some(2), "Odd sized chunk padded" );
+		assert_eq!( count_apng_frames( b"\x89PNG\r\n\x1a\n\0\0\0\x08acTL\0\0\0\x0c\0\0\0\0" ), Some(12) );
+		assert_eq!( count_gif_frames( b"GIF89a" ), None, "Truncated" );
+		assert_eq!( count_frames( b"not an image", Path::new( "notes.txt" ) ), None );
+	}
+}
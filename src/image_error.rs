@@ -16,4 +16,8 @@ pub enum MyImageError {
     //The image library couldn't decode the file as an image
     #[error("{0}")]
     DecodeFail(String),
+    
+    //Decoding the image would need more memory than the configured limit
+    #[error("{0}")]
+    MemoryLimit(String),
 }
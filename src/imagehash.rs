@@ -2,23 +2,49 @@ extern crate image;
 
 use std::cmp::Ordering;
 use image::{GenericImageView, DynamicImage};
-use image::imageops::FilterType;
+use image::{GrayImage, RgbImage};
+use image::imageops::{self, FilterType};
 use image::ImageReader;
 use std::fs;
+use std::io::{BufRead, Cursor, Seek};
 
 use crate::image_error::MyImageError;
+use crate::raw;
+use crate::archive;
+use crate::colour;
+use crate::distance;
+use crate::jpeg::{self, JpegDecode};
+use crate::frames::{self, FramePolicy};
+use crate::formats::{self, Decoder, FileFormat};
+use crate::memory;
+use std::path::{Path, PathBuf};
 
-#[derive(Clone)]
+#[derive(Clone, Default)]
 pub struct ImagePath {
 	/// The path to a valid image file
-	pub fpath: String,
-	/// True if the image exists in the new images comparison dir (false if feature not in use or else in existing collection)	
-	pub is_compare_dir : bool,
-	/// True if when using --compare a duplicate should always be marked even if a better quality than the existing image
+	pub fpath: PathBuf,
+	/// Priority of the collection the image is in, where 0 is the existing collection and higher numbers are the collections compared with it (e.g. the new images with --compare)
+	pub collection : u32,
+	/// True if when using --compare or --collection a duplicate should always be marked even if a better quality than the image in a higher priority collection
 	pub always_mark_dupe_compare : bool,
+	/// True if the image should be chosen as the best version of its duplicates regardless of resolution (e.g. RAW originals when using --prefer-raw)
+	pub prefer_as_best : bool,
+	/// True if the file is a video, compared using frames sampled from it
+	pub is_video : bool,
+	/// Colour that transparent pixels are composited onto before hashing (white if not set)
+	pub alpha_background : Option<[u8;3]>,
+	/// How the image is decoded if it is a JPEG
+	pub jpeg_decode : JpegDecode,
+	/// Maximum bytes the image decoder may allocate, the image is skipped if it needs more
+	pub memory_limit : Option<u64>,
+	/// Paths of metadata files accompanying the image (e.g. .xmp, .aae, .json) that should be moved or deleted together with it
+	pub sidecars : Vec<PathBuf>,
+	/// Other paths to the same file (e.g. hard links, symbolic links or the same file given twice), which are listed with the image rather than as duplicates
+	pub aliases : Vec<PathBuf>,
 }
 
 /// Statistics about an image that are used to perform the deduplication
+#[derive(Clone)]
 pub struct ImageHashAV {
 	/// A common key to group potential duplicates - same integer means possible (but not yet confirmed) dupe
 	pub dupe_group : u64 ,
@@ -38,6 +64,29 @@ pub struct ImageHashAV {
 	pub std_dev : f32,
 	/// The path to the image
 	pub image_path: ImagePath,
+	/// Statistics of the frames after the first that are compared under the frame policy (empty for still images)
+	pub frames : Vec<FrameHash>,
+	/// Fraction of the image that is transparent (0.0 to 1.0)
+	pub transparency : f32,
+	/// The alpha channel of a low resolution version of the image, only where the image has significant transparency
+	pub alpha_mask : Option<[u8;64]>,
+}
+
+/// Statistics of a single frame of an animation or page of a multi-page TIFF
+#[derive(Clone)]
+pub struct FrameHash {
+	/// The pixels of a colour low resolution version of the frame
+	pub low_res : [u8;192],
+	/// Standard deviation of colour values from the mean
+	pub std_dev : f32,
+}
+
+/// A named set of files and directories of images, given with --collection
+pub struct Collection {
+	/// Name of the collection, empty for the images given as arguments and with --compare
+	pub name : String,
+	/// Files and directories of images in the collection
+	pub paths : Vec<PathBuf>,
 }
 
 /// Holds the configuration options that are set on the command line
@@ -52,17 +101,53 @@ pub struct ConfigOptions {
 	pub alg_colour_diff_only : bool,
 	/// Only consider known image file extensions e.g. .jpg .png etc
 	pub only_known_file_extensions : bool,
+	/// Additional file extensions (lower case, without the dot) to test as images
+	pub include_extensions : Vec<String>,
+	/// File extensions (lower case, without the dot) never tested even if a known image extension
+	pub exclude_extensions : Vec<String>,
+	/// Follow symbolic links when searching directories
+	pub follow_symlinks : bool,
+	/// Include files and directories with names starting with a dot
+	pub include_hidden : bool,
+	/// Maximum depth of directories searched below each directory given
+	pub max_depth : Option<usize>,
+	/// Files and directories with paths matching these patterns are skipped
+	pub exclude_patterns : Vec<glob::Pattern>,
+	/// Don't search directories on other filesystems than the directory given
+	pub one_file_system : bool,
+	/// Look for images inside ZIP and TAR archives
+	pub scan_archives : bool,
+	/// Which frames of animations and pages of multi-page TIFFs are compared
+	pub frame_policy : FramePolicy,
+	/// Compare videos using frames sampled from them
+	pub scan_videos : bool,
+	/// Path to the ffmpeg binary used to read videos, found on the PATH if not set
+	pub ffmpeg_path : Option<String>,
+	/// Colour transparent pixels are composited onto before hashing
+	pub alpha_background : [u8;3],
+	/// How JPEGs are decoded, at reduced resolution or from their EXIF thumbnail
+	pub jpeg_decode : JpegDecode,
 	/// Option to only list the duplicates found and not the best versions of each image
 	pub only_list_duplicates : bool,
 	/// Option to only list the uniques images found and not the duplicates
 	pub only_list_uniques : bool,
 	/// Whether to output all images found as opposed to just those with duplicates
 	pub list_all : bool,
+	/// Print paths with unprintable characters and bytes that aren't UTF-8 escaped, rather than as their exact bytes
+	pub escape_paths : bool,
+	/// Character output after each path, a newline or NUL with --print0
+	pub path_terminator : u8,
 	/// How many threads to use to process images
 	pub num_threads : u32,
-	/// The path to the comparison directory			
-	pub compare_dir : String,
-	/// If the --compare option is used
+	/// Maximum bytes used by images being decoded at once
+	pub memory_limit : Option<u64>,
+	/// Collections of images compared with each other, in order of priority. The new images given with --compare are the last collection.
+	pub collections : Vec<Collection>,
+	/// Index of the existing collection that the new images are compared against, instead of reading the collection
+	pub against_index : Option<PathBuf>,
+	/// Add the new images listed as unique to the index
+	pub update_index : bool,
+	/// If there is more than one collection, e.g. the --compare option is used
 	pub am_comparing : bool,
 	/// If the --always-mark-duplicates option is used
 	pub always_mark_duplicates : bool,
@@ -70,16 +155,31 @@ pub struct ConfigOptions {
 	pub min_width : u32,
 	/// The minimum accepted image height
 	pub min_height : u32,
+	/// Write metadata missing from the best version of each image into a sidecar
+	pub merge_metadata : bool,
+	/// Find the sidecar files of each image and list them with the image
+	pub include_sidecars : bool,
+	/// Choose camera RAW files as the best version of each image over JPEG exports
+	pub prefer_raw : bool,
+	/// Find sets of similar (but not duplicate) shots such as bursts
+	pub find_similar : bool,
+	/// Colour difference threshold under which images are similar shots
+	pub similar_colour_threshold : u64,
+	/// Number of bits the grey hashes of similar shots may differ by
+	pub similar_hash_distance : u32,
+	/// If set, similar shots must have been captured within this many seconds of each other
+	pub similar_time_window : Option<u64>,
 }
 
 
 /// Describes the sort order for ImageHashAV objects
 /// Order the images with the following keys
 /// 1st) The dupe_group (ascending)
-/// 2nd) If the comparison image is in the --compare directory, sort further down the list if the --always-mark-duplicates option is set
-/// 3rd) The total number of pixels (descending) - prefers higher resolution images as better quality
-/// 4th) The file size (descending) - prefers larger images as better quality where they are the same resolution
-/// 5th) Where --compare is used, prefers the image in the original collection and the new image will be the duplicate 
+/// 2nd) If the --ignore-resolution option is set, images in lower priority collections (e.g. the --compare directory) sort further down the list
+/// 3rd) Images preferred as best e.g. RAW files with --prefer-raw
+/// 4th) The total number of pixels (descending) - prefers higher resolution images as better quality
+/// 5th) The file size (descending) - prefers larger images as better quality where they are the same resolution
+/// 6th) Where --compare or --collection is used, prefers the image in the higher priority collection and the other image will be the duplicate 
 impl Ord for ImageHashAV {
 	
     fn cmp(&self, other: &Self) -> Ordering {
@@ -92,16 +192,24 @@ impl Ord for ImageHashAV {
 		return Ordering::Greater;
 	}
 
-	//If the comparison image is in the --compare directory, sort further down the list if the --always-mark-duplicates option is set
+	//If the image is in a lower priority collection (e.g. the --compare directory), sort further down the list if the --ignore-resolution option is set
 	//This makes it a duplicate prior to checking if it's better resolution
-	if self.image_path.always_mark_dupe_compare && self.image_path.is_compare_dir && (!other.image_path.always_mark_dupe_compare) {
-		return Ordering::Greater;
+	if self.image_path.always_mark_dupe_compare || other.image_path.always_mark_dupe_compare {
+		match self.image_path.collection.cmp( &other.image_path.collection ) {
+			Ordering::Equal => {},
+			ordering => return ordering,
+		}
 	}
-
-	if (!self.image_path.always_mark_dupe_compare) && other.image_path.always_mark_dupe_compare && other.image_path.is_compare_dir {
+	
+	//Push images preferred as the best version (e.g. RAW originals) further up the list
+	if self.image_path.prefer_as_best && !other.image_path.prefer_as_best {
 		return Ordering::Less;
 	}
 	
+	if !self.image_path.prefer_as_best && other.image_path.prefer_as_best {
+		return Ordering::Greater;
+	}
+	
 	//Push files with greater number of pixels further up the list
 	if self.num_pixels > other.num_pixels{
 		return Ordering::Less;
@@ -120,16 +228,8 @@ impl Ord for ImageHashAV {
 		return Ordering::Greater;
 	}
 
-	//Where --compare is used, prefers the image in the original collection and the new image will be the duplicate
-	if self.image_path.is_compare_dir && (!other.image_path.is_compare_dir) {
-		return Ordering::Greater;
-	}
-
-	if (!self.image_path.is_compare_dir) && other.image_path.is_compare_dir {
-		return Ordering::Less;
-	}
-	
-	return Ordering::Equal
+	//Where --compare or --collection is used, prefers the image in the higher priority collection and the other image will be the duplicate
+	self.image_path.collection.cmp( &other.image_path.collection )
     }
     
 }
@@ -154,32 +254,74 @@ impl PartialEq for ImageHashAV {
     }
 }
 
-///Open an image from the specific path. Tries to guess the format if it's not known.
-fn load_image_from_file( image_path: &str  ) -> std::result::Result<DynamicImage, MyImageError> {
+///Open an image from the specific path. Tries to guess the format if it's not known. RAW files are decoded from their embedded preview.
+///Images inside archives are read using their archive path e.g. photos.zip!/IMG_1234.jpg
+pub fn load_image_from_file( image_path: &Path  ) -> std::result::Result<DynamicImage, MyImageError> {
+	load_image_from_file_with_limit( image_path, None )
+}
+
+///Open an image from the specific path, failing with a MemoryLimit error if decoding would allocate more than the limit in bytes
+pub fn load_image_from_file_with_limit( image_path: &Path, memory_limit: Option<u64> ) -> std::result::Result<DynamicImage, MyImageError> {
 	
+	if archive::split_member_path( image_path ).is_some() {
+		let data = archive::read_file( image_path, memory_limit )?;
+		return load_image_from_memory( &data, image_path, memory_limit );
+	}
+	
+	let known_format = formats::format_from_path( image_path );
+	
+	//Camera RAW files are read from their embedded preview
+	if let Some(FileFormat { decoder: Decoder::Raw, .. }) = known_format {
+		return raw::load_raw_file( image_path, memory_limit );
+	}
 	
 	let img = match ImageReader::open(image_path) {
 		Ok(image) => image,
 		Err(_) => {
-			return Err(MyImageError::FileError(format!("Error: Failed to read image file: {}", image_path).to_string()));
+			return Err(MyImageError::FileError(format!("Error: Failed to read image file: {}", image_path.display()).to_string()));
 		},
 	};
 	
+	decode_image( img, known_format, image_path, memory_limit )
+}
+
+///Decode an image already read into memory e.g. from inside an archive. The path is used to find the format from its extension.
+///Fails with a MemoryLimit error if decoding would allocate more than the limit in bytes.
+pub fn load_image_from_memory( data: &[u8], image_path: &Path, memory_limit: Option<u64> ) -> std::result::Result<DynamicImage, MyImageError> {
+	
+	let known_format = formats::format_from_path( image_path );
+	
+	if let Some(FileFormat { decoder: Decoder::Raw, .. }) = known_format {
+		return raw::load_raw_data( data, image_path, memory_limit );
+	}
+	
+	decode_image( ImageReader::new( Cursor::new( data ) ), known_format, image_path, memory_limit )
+}
+
+///Decode an image from a reader, guessing the format from the content if possible
+fn decode_image<R: BufRead + Seek>( mut img: ImageReader<R>, known_format: Option<&FileFormat>, image_path: &Path, memory_limit: Option<u64> ) -> std::result::Result<DynamicImage, MyImageError> {
+	
+	//Start from the format of the extension, used where the content can't be identified (e.g. TGA has no magic number)
+	if let Some(FileFormat { decoder: Decoder::Image(image_format), .. }) = known_format {
+		img.set_format( *image_format );
+	}
+	
 	let format_guessed = match img.with_guessed_format() {
 		Ok( format_guessed ) => format_guessed,
 		Err(_) => {
-				return Err(MyImageError::DecodeFail(format!("Error: Failed to identify image file format {}", image_path).to_string()));
+				return Err(MyImageError::DecodeFail(format!("Error: Failed to identify image file format {}", image_path.display()).to_string()));
 		}
 	};
 	
-	let decoded_img = match format_guessed.decode() {
-		Ok( decoded_img ) => decoded_img,
+	//Colours are compared in 8 bit sRGB, converting from the embedded colour profile if there is one
+	match colour::decode_with_profile( format_guessed, memory::decoder_limits( memory_limit ) ) {
+		Ok( (decoded_img, icc_profile) ) => Ok(colour::normalise( decoded_img, icc_profile.as_deref() )),
+		Err(image::ImageError::Limits(_)) => Err( memory::limit_error( image_path ) ),
 		Err(_) => {
-				return Err( MyImageError::DecodeFail(format!("Error: Failed to correctly decode image: {}", image_path).to_string()) );
+				let format_name = known_format.map( |f| format!("{} ", f.name) ).unwrap_or_default();
+				Err( MyImageError::DecodeFail(format!("Error: Failed to correctly decode {}image: {}", format_name, image_path.display()).to_string()) )
 		}
-	};
-	
-	return Ok(decoded_img);
+	}
 }
 
 
@@ -192,17 +334,126 @@ impl ImageHashAV {
 	pub const DEFAULT_STD_DEV_THRESHOLD : f32 = 3.0;
 	/// Number of files at which we flip to the less accurate but faster algorithm
 	pub const DEFAULT_ALG_FLIP_THRESHOLD : u64 = 50000;
+	/// Default colour difference threshold under which two images are declared similar shots
+	pub const DEFAULT_SIMILAR_COLOUR_DIFF_THRESHOLD : u64 = 2048;
+	/// Default number of bits the grey hashes of similar shots may differ by
+	pub const DEFAULT_SIMILAR_HASH_DISTANCE : u32 = 8;
+	/// Default colour transparent pixels are composited onto
+	pub const DEFAULT_ALPHA_BACKGROUND : [u8;3] = [255, 255, 255];
+	/// Fraction of an image that must be transparent for the alpha mask to be compared
+	pub const SIGNIFICANT_TRANSPARENCY : f32 = 0.01;
 		
 	pub fn new(fpath : &ImagePath, min_width: u32, min_height : u32) -> Result<ImageHashAV,MyImageError> {
-		let mut object = ImageHashAV {	dupe_group: 0, grey_hash: 0, low_res: [0;192], 
-						width: 0, height: 0, num_pixels: 0, std_dev: 0f32, 
-						file_size: 0, image_path : ImagePath { fpath: "".to_string(), is_compare_dir: false, always_mark_dupe_compare: false } };
+		let mut object = ImageHashAV::blank( 0 );
 		match object.calc_image_hash( &fpath,  min_width, min_height ) {
 			Some(e) => return Err(e),
 			None => return Ok(object),
 		}
 	}
 	
+	/// Calculate the statistics of an image, comparing the frames of animations and pages of multi-page TIFFs selected by the frame policy
+	pub fn new_with_frames( fpath: &ImagePath, min_width: u32, min_height : u32, frame_policy: FramePolicy ) -> Result<ImageHashAV,MyImageError> {
+		if frame_policy == FramePolicy::First {
+			return ImageHashAV::new( fpath, min_width, min_height );
+		}
+		let data = archive::read_file( &fpath.fpath, fpath.memory_limit )?;
+		ImageHashAV::from_data( &data, fpath, min_width, min_height, frame_policy )
+	}
+	
+	/// Calculate the statistics of an image file that has already been read into memory e.g. from inside an archive
+	pub fn from_data( data: &[u8], im_path: &ImagePath, min_width: u32, min_height : u32, frame_policy: FramePolicy ) -> Result<ImageHashAV,MyImageError> {
+		let file_size = data.len() as u64;
+		
+		let frames = match frame_policy {
+			FramePolicy::First => None,
+			_ => frames::decode_frames( data, &im_path.fpath, im_path.memory_limit ),
+		};
+		let frames = match frames {
+			Some(frames) => frames,
+			None => {
+				let mut object = ImageHashAV::blank( file_size );
+				return match object.calc_data_hash( data, im_path, min_width, min_height ) {
+					Some(e) => Err(e),
+					None => Ok(object),
+				};
+			},
+		};
+		
+		let num_frames = match frame_policy {
+			FramePolicy::Sample => frames::count_frames( data, &im_path.fpath ),
+			_ => None,
+		};
+		ImageHashAV::from_frames( frames, num_frames, im_path, file_size, min_width, min_height, frame_policy )
+	}
+	
+	/// Calculate the statistics of a sequence of decoded frames e.g. of an animation or frames extracted from a video, keeping the frames selected by the frame policy.
+	/// When sampling, the number of frames should be given if it is known so that only the sampled frames are hashed.
+	pub fn from_frames( mut frames: frames::Frames, num_frames: Option<usize>, im_path: &ImagePath, file_size: u64, min_width: u32, min_height : u32, frame_policy: FramePolicy ) -> Result<ImageHashAV,MyImageError> {
+		
+		//Frames between the samples are skipped over. Otherwise every frame is hashed up to the maximum, and then the frames to compare are picked.
+		let (indexes, sampled) = match (frame_policy, num_frames) {
+			(FramePolicy::Sample, Some(num_frames)) => (frame_policy.frame_indexes( num_frames ), true),
+			_ => ((0..=frames::MAX_FRAMES).collect(), false),
+		};
+		
+		//The first frame sets the dimensions, later frames are only hashed
+		let mut object = ImageHashAV::blank( file_size );
+		let mut frame_hashes = Vec::new();
+		let mut next_index = 0;
+		for i in indexes {
+			let frame = match frames.nth( i - next_index ) {
+				Some(frame) => frame,
+				None => break,
+			};
+			next_index = i + 1;
+			if !sampled && i == frames::MAX_FRAMES {
+				eprintln!("Warning: Only the first {} frames are compared of: {}", frames::MAX_FRAMES, im_path.fpath.display());
+				break;
+			}
+			match frame {
+				Ok(frame) if i == 0 => {
+					if let Some(e) = object.calc_decoded_image_hash( &frame, im_path, min_width, min_height ) {
+						return Err(e);
+					}
+				},
+				Ok(frame) => frame_hashes.push( FrameHash::new( &frame, im_path )? ),
+				Err(e) if i == 0 => return Err(e),
+				Err(e @ MyImageError::MemoryLimit(_)) => return Err(e),
+				//A damaged frame ends the animation, the frames before it are still compared
+				Err(_) => break,
+			}
+		}
+		if object.num_pixels == 0 {
+			return Err(MyImageError::DecodeFail(format!("Error: No frames found in image: {}", im_path.fpath.display())));
+		}
+		
+		object.frames = if sampled {
+			frame_hashes
+		}else{
+			frame_policy.frame_indexes( frame_hashes.len() + 1 ).into_iter()
+				.skip(1)
+				.map( |i| frame_hashes[i-1].clone() )
+				.collect()
+		};
+		Ok(object)
+	}
+	
+	/// Calculate the statistics of an image that has already been decoded e.g. one held in memory rather than read from a file
+	pub fn from_image( img: &DynamicImage, im_path: &ImagePath, file_size: u64, min_width: u32, min_height : u32 ) -> Result<ImageHashAV,MyImageError> {
+		let mut object = ImageHashAV::blank( file_size );
+		match object.calc_decoded_image_hash( img, im_path, min_width, min_height ) {
+			Some(e) => Err(e),
+			None => Ok(object),
+		}
+	}
+	
+	/// An object with no statistics calculated yet
+	fn blank( file_size: u64 ) -> ImageHashAV {
+		ImageHashAV {	dupe_group: 0, grey_hash: 0, low_res: [0;192], 
+						width: 0, height: 0, num_pixels: 0, std_dev: 0f32, 
+						file_size, image_path : ImagePath::default(), frames: Vec::new(), transparency: 0f32, alpha_mask: None }
+	}
+	
 	/// Check if two image aspect ratios are within 2% of each other
 	pub fn has_similar_aspect_ratio( &self, comp: &ImageHashAV ) -> bool {
 		let aspect_ratio_a : f32 = self.width as f32 / self.height as f32;
@@ -220,23 +471,27 @@ impl ImageHashAV {
 	
 	/// Difference between the low_res version of this and another imagehash
 	pub fn diff_colour( &self, comp: &ImageHashAV ) -> u64{
-		
-		let mut diff: u64 = 0;
-		
-		for i in 0..64 {
-			let rdiff : u32 = (comp.low_res[i*3] as i32 - self.low_res[i*3] as i32).abs() as u32;
-			let gdiff : u32 = (comp.low_res[(i*3)+1] as i32 - self.low_res[(i*3)+1] as i32).abs() as u32;
-			let bdiff : u32 = (comp.low_res[(i*3)+2] as i32 - self.low_res[(i*3)+2] as i32).abs() as u32;
-			
-			diff += ( rdiff + gdiff + bdiff ) as u64;
-		}
-		
-		return diff;
-		
+		diff_low_res( &self.low_res, &comp.low_res )
 	}
 	
 	/// Test if two images are duplicates of each other by looking at the comparitive variance in the colours
 	pub fn is_dupe ( &self, other : &ImageHashAV, config: &ConfigOptions ) -> bool {
+		
+		//Images that have the same colours once composited onto the background must also have similar transparency
+		if !self.has_similar_alpha_mask( other, config ) {
+			return false;
+		}
+		
+		//A still is a duplicate of a video if it matches any of the frames sampled from the video
+		if self.image_path.is_video != other.image_path.is_video {
+			let (still, video) = if self.image_path.is_video { (other, self) } else { (self, other) };
+			return still.is_frame_of( video, config );
+		}
+		
+		//Animations and videos are compared frame by frame
+		if !self.frames.is_empty() || !other.frames.is_empty() {
+			return self.is_dupe_animation( other, config );
+		}
 
 		//Excludes dark images with little variation which are difficult to dedupe correctly
 		if self.std_dev > config.std_dev_threshold && other.std_dev > config.std_dev_threshold {	
@@ -252,6 +507,60 @@ impl ImageHashAV {
 		return false;
 	}
 	
+	/// Test if two animations are duplicates. The compared frames must all match, other than frames with little variation in both files.
+	/// Animations never match a still image as they have a different number of compared frames.
+	fn is_dupe_animation( &self, other : &ImageHashAV, config: &ConfigOptions ) -> bool {
+		
+		if self.frames.len() != other.frames.len() || !self.has_similar_aspect_ratio( other ) {
+			return false;
+		}
+		
+		let mut compared_frames = 0;
+		for (a, b) in self.frame_stats().zip( other.frame_stats() ) {
+			let a_varied = a.1 > config.std_dev_threshold;
+			let b_varied = b.1 > config.std_dev_threshold;
+			if a_varied != b_varied {
+				return false;
+			}
+			if a_varied {
+				if diff_low_res( a.0, b.0 ) > config.colour_difference_threshold {
+					return false;
+				}
+				compared_frames += 1;
+			}
+		}
+		
+		compared_frames > 0
+	}
+	
+	/// Check the alpha masks are similar where both images have significant transparency.
+	/// An image with transparency can still match an opaque image, e.g. a logo on a transparent background and the same logo on a plain background.
+	pub fn has_similar_alpha_mask( &self, other : &ImageHashAV, config: &ConfigOptions ) -> bool {
+		match (self.alpha_mask, other.alpha_mask) {
+			(Some(a), Some(b)) => {
+				let diff : u64 = distance::sum_abs_diff( &a, &b );
+				//The colour threshold covers 3 channels whereas the mask is a single channel
+				diff <= config.colour_difference_threshold / 3
+			},
+			_ => true,
+		}
+	}
+	
+	/// Test if the first frame of this image matches any frame of a video
+	fn is_frame_of( &self, video : &ImageHashAV, config: &ConfigOptions ) -> bool {
+		
+		if self.std_dev <= config.std_dev_threshold || !self.has_similar_aspect_ratio( video ) {
+			return false;
+		}
+		
+		video.frame_stats().any( |(low_res, std_dev)| std_dev > config.std_dev_threshold && diff_low_res( &self.low_res, low_res ) <= config.colour_difference_threshold )
+	}
+	
+	/// The low resolution pixels and colour variation of each compared frame, starting with the first
+	fn frame_stats( &self ) -> impl Iterator<Item = (&[u8;192], f32)> {
+		std::iter::once( (&self.low_res, self.std_dev) ).chain( self.frames.iter().map( |f| (&f.low_res, f.std_dev) ) )
+	}
+	
 	/// For each colour channel calculate the stdv of the pixels values and then take the average of the colour channels
 	pub fn calc_std_dev_colour_hash ( &mut self ) {
 		
@@ -287,89 +596,161 @@ impl ImageHashAV {
 	
 	/// Populates image statistics including the perceptual hash
 	pub fn calc_image_hash(&mut self, im_path: &ImagePath, min_width: u32, min_height : u32 ) -> Option<MyImageError> {
+		
+		//Images in archives are sized by their uncompressed data. JPEGs are read into memory so they can be decoded at reduced resolution.
+		if archive::split_member_path( &im_path.fpath ).is_some() || ( jpeg::is_jpeg_path( &im_path.fpath ) && im_path.jpeg_decode != JpegDecode::Full ) {
+			return match archive::read_file( &im_path.fpath, im_path.memory_limit ) {
+				Ok(data) => {
+					self.file_size = data.len() as u64;
+					self.calc_data_hash( &data, im_path, min_width, min_height )
+				},
+				Err(e) => Some(e),
+			};
+		}
 		   
-		match load_image_from_file( &im_path.fpath ) {
+		match load_image_from_file_with_limit( &im_path.fpath, im_path.memory_limit ) {
 			Ok(img) => {
-			
-				//Ignore very small images that the technique can't work with and also images below the user configured size
-				let (width, height) = img.dimensions();
-				if width < 16 || height < 16 {
-					return Some( MyImageError::ImageTooSmall(format!("Warning: Image too small to deduplicate: {}", im_path.fpath).to_string()) );
-				}
-				
-				if min_width > 0 && min_height > 0 {
-					if width < min_width || height < min_height {
-						return Some( MyImageError::ImageTooSmall(format!("Warning: Ignored image because dimensions ({},{}) are below minimum: {}",width,height, im_path.fpath).to_string()) );
-					}
-				}
-		
-				self.width = width;
-				self.height = height;
-				self.num_pixels = (width as u64)*(height as u64);
-				self.image_path = im_path.clone();		
-
 				//Get the file size as a tie breaker if image dimensions are the same
-				match fs::metadata(im_path.fpath.clone()) {
+				match fs::metadata(&im_path.fpath) {
 					Ok(md)=> {
 						self.file_size = md.len();
 					}
 					Err(_)=> {
-						return Some(MyImageError::FileError(format!("Error: Failed to get size of: {}", im_path.fpath).to_string()));
+						return Some(MyImageError::FileError(format!("Error: Failed to get size of: {}", im_path.fpath.display()).to_string()));
 					}
 				}
+				
+				self.calc_decoded_image_hash( &img, im_path, min_width, min_height )
+			},
+			Err(e) => Some(e),
+		}
+	}
+	
+	/// Populates image statistics from an image file read into memory. JPEGs are decoded at reduced resolution unless the image path asks for a full decode.
+	fn calc_data_hash(&mut self, data: &[u8], im_path: &ImagePath, min_width: u32, min_height : u32 ) -> Option<MyImageError> {
+		if let Some((img, dimensions)) = jpeg::load_reduced( data, im_path.jpeg_decode, im_path.memory_limit ) {
+			return self.calc_scaled_image_hash( &img, dimensions, im_path, min_width, min_height );
+		}
+		match load_image_from_memory( data, &im_path.fpath, im_path.memory_limit ) {
+			Ok(img) => self.calc_decoded_image_hash( &img, im_path, min_width, min_height ),
+			Err(e) => Some(e),
+		}
+	}
+	
+	/// Populates image statistics including the perceptual hash from a decoded image
+	fn calc_decoded_image_hash(&mut self, img: &DynamicImage, im_path: &ImagePath, min_width: u32, min_height : u32 ) -> Option<MyImageError> {
+		self.calc_scaled_image_hash( img, img.dimensions(), im_path, min_width, min_height )
+	}
+	
+	/// Populates image statistics from a decoded image that may have been reduced in size, given the dimensions of the original image
+	fn calc_scaled_image_hash(&mut self, img: &DynamicImage, dimensions: (u32, u32), im_path: &ImagePath, min_width: u32, min_height : u32 ) -> Option<MyImageError> {
+		   
+		//Ignore very small images that the technique can't work with and also images below the user configured size
+		let (width, height) = dimensions;
+		if width < 16 || height < 16 {
+			return Some( MyImageError::ImageTooSmall(format!("Warning: Image too small to deduplicate: {}", im_path.fpath.display()).to_string()) );
+		}
 		
-								
-				//Seems to work best with Gaussian, although it's the slowest
-				let scaled = img.resize_exact(8,8,FilterType::Gaussian);
+		if min_width > 0 && min_height > 0 && (width < min_width || height < min_height) {
+			return Some( MyImageError::ImageTooSmall(format!("Warning: Ignored image because dimensions ({},{}) are below minimum: {}",width,height, im_path.fpath.display()).to_string()) );
+		}
 		
-				let (width, height) = scaled.dimensions();
-				if width != 8 || height != 8 {
-					return Some( MyImageError::DecodeFail(format!("Error: Failed to resize image correctly: {}", im_path.fpath).to_string()) );
+		self.width = width;
+		self.height = height;
+		self.num_pixels = (width as u64)*(height as u64);
+		self.image_path = im_path.clone();		
+
+		//Transparent pixels can hold any colour, so composite onto a background to get the colours as displayed
+		let composited;
+		let img = if img.color().has_alpha() {
+			let background = im_path.alpha_background.unwrap_or( ImageHashAV::DEFAULT_ALPHA_BACKGROUND );
+			let (flattened, alpha) = composite_alpha( img, background );
+			let total_alpha : u64 = alpha.pixels().map( |p| p.0[0] as u64 ).sum();
+			self.transparency = 1.0 - ( total_alpha as f32 / ( ( alpha.width() as u64 * alpha.height() as u64 ) as f32 * 255.0 ) );
+			if self.transparency > ImageHashAV::SIGNIFICANT_TRANSPARENCY {
+				let mut mask = [0u8;64];
+				for (m, p) in mask.iter_mut().zip( imageops::resize( &alpha, 8, 8, FilterType::Gaussian ).pixels() ) {
+					*m = p.0[0];
 				}
+				self.alpha_mask = Some(mask);
+			}
+			composited = flattened;
+			&composited
+		}else{
+			img
+		};
 
-				let gs = scaled.grayscale( );
+		//Seems to work best with Gaussian, although it's the slowest
+		let scaled = img.resize_exact(8,8,FilterType::Gaussian);
 		
-				let mut num_pixels = 0;
-				let mut total: u64 = 0;
-				for pixel in gs.pixels() {
-					let p: u64 = ((pixel.2).0)[0].into();
-					total += p;
-					num_pixels+=1;
-				}
-				let average: f32 = (total as f32)/ (num_pixels as f32);
+		let (width, height) = scaled.dimensions();
+		if width != 8 || height != 8 {
+			return Some( MyImageError::DecodeFail(format!("Error: Failed to resize image correctly: {}", im_path.fpath.display()).to_string()) );
+		}
+
+		let gs = scaled.grayscale( );
 		
-				let mut hash_val: u64 = 0;
-				let mut this_bit: u64 = 0;
+		let mut num_pixels = 0;
+		let mut total: u64 = 0;
+		for pixel in gs.pixels() {
+			let p: u64 = ((pixel.2).0)[0].into();
+			total += p;
+			num_pixels+=1;
+		}
+		let average: f32 = (total as f32)/ (num_pixels as f32);
 		
-				for pixel in gs.pixels() {
-					let p: f32 = ((pixel.2).0)[0].into();
-					if p >= average {
-						hash_val = (((1 as u64) << this_bit ) as u64) | hash_val;
-					}
-					this_bit+=1;
-				}				
-		
-				//Add the pixels of the low res original image into the struct
-				let mut pnum : usize = 0;
-				for pixel in scaled.pixels() {
-					self.low_res[pnum*3] = ((pixel.2)[0]).into();
-					self.low_res[(pnum*3)+1] = ((pixel.2)[1]).into();
-					self.low_res[(pnum*3)+2] = ((pixel.2)[2]).into();
-					pnum+=1;
-				}
+		let mut hash_val: u64 = 0;
+		
+		for (this_bit, pixel) in gs.pixels().enumerate() {
+			let p: f32 = ((pixel.2).0)[0].into();
+			if p >= average {
+				hash_val |= 1u64 << this_bit;
+			}
+		}				
 		
-				self.dupe_group = hash_val;
-				self.grey_hash = hash_val;
-				self.calc_std_dev_colour_hash();
+		//Add the pixels of the low res original image into the struct
+		for (pnum, pixel) in scaled.pixels().enumerate() {
+			self.low_res[pnum*3] = (pixel.2)[0];
+			self.low_res[(pnum*3)+1] = (pixel.2)[1];
+			self.low_res[(pnum*3)+2] = (pixel.2)[2];
+		}
+		
+		self.dupe_group = hash_val;
+		self.grey_hash = hash_val;
+		self.calc_std_dev_colour_hash();
 
-				return None;
-			},
-			Err(e) => {
-				return Some(e);
-			}	
+		None
+	}
+
+}
+
+impl FrameHash {
+	
+	/// Calculate the statistics of a single frame
+	fn new( img: &DynamicImage, im_path: &ImagePath ) -> Result<FrameHash,MyImageError> {
+		let frame = ImageHashAV::from_image( img, im_path, 0, 0, 0 )?;
+		Ok(FrameHash { low_res: frame.low_res, std_dev: frame.std_dev })
+	}
+}
+
+/// Composite an image with an alpha channel onto a background colour. Returns the opaque image and the alpha channel.
+fn composite_alpha( img: &DynamicImage, background: [u8;3] ) -> (DynamicImage, GrayImage) {
+	let rgba = img.to_rgba8();
+	let mut flattened = RgbImage::new( rgba.width(), rgba.height() );
+	let mut alpha = GrayImage::new( rgba.width(), rgba.height() );
+	for ((src, dest), a) in rgba.pixels().zip( flattened.pixels_mut() ).zip( alpha.pixels_mut() ) {
+		let opacity = src.0[3] as u32;
+		for ((d, s), b) in dest.0.iter_mut().zip( src.0.iter() ).zip( background.iter() ) {
+			*d = ( ( *s as u32 * opacity + *b as u32 * (255 - opacity) ) / 255 ) as u8;
 		}
+		a.0[0] = src.0[3];
 	}
+	(DynamicImage::ImageRgb8( flattened ), alpha)
+}
 
+/// Sum of the differences between the colour channels of two low resolution images
+fn diff_low_res( a: &[u8;192], b: &[u8;192] ) -> u64 {
+	distance::sum_abs_diff( a, b )
 }
 
 #[cfg(test)]
@@ -392,7 +773,7 @@ mod tests {
 	/// Test an image is read and metadata extracted correctly
 	#[test]
 	fn test_image_read() {
-		let result = ImageHashAV::new( &ImagePath { fpath: "unit_test_images/bridge1_best.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
+		let result = ImageHashAV::new( &ImagePath { fpath: "unit_test_images/bridge1_best.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
 		assert_eq!(768,result.width,"Width OK");
 		assert_eq!(576,result.height,"Height OK");
 		assert_eq!(576*768,result.num_pixels,"NUm pixels OK");
@@ -405,9 +786,9 @@ mod tests {
 	
 		//Get a list of all the images in the unit_test_images directory.
 		for entry in glob("unit_test_images/*").expect("Failed to read glob pattern") {
-			let path = entry.unwrap().display().to_string();
+			let path = entry.unwrap();
 			//Only list files that contain best and duplicate
-			if path.contains("_best.") || path.contains("_duplicate_") {
+			if path.to_str().unwrap().contains("_best.") || path.to_str().unwrap().contains("_duplicate_") {
 				image_paths.push( path );
 			}
 		
@@ -419,9 +800,9 @@ mod tests {
 	
 		//Check the best image matches the two duplicates
 		for i in 0..(image_paths.len()/3) {
-			let result = ImageHashAV::new( &ImagePath { fpath: image_paths[i*3].clone(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
-			let dupe1 = ImageHashAV::new( &ImagePath { fpath:  image_paths[(i*3)+1].clone(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
-			let dupe2 = ImageHashAV::new( &ImagePath { fpath:  image_paths[(i*3)+2].clone(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
+			let result = ImageHashAV::new( &ImagePath { fpath: image_paths[i*3].clone(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
+			let dupe1 = ImageHashAV::new( &ImagePath { fpath:  image_paths[(i*3)+1].clone(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
+			let dupe2 = ImageHashAV::new( &ImagePath { fpath:  image_paths[(i*3)+2].clone(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
 		
 			//Check the duplicates match the best versions within a hamming distance of 1 bit (max 64 bits can be similar)
 			assert!( calc_hamming_distance(result.dupe_group, dupe1.dupe_group) >= 63, "First duplicate grey hash matches" );
@@ -432,6 +813,38 @@ mod tests {
 		}			
 	}
     
+	/// Test transparent images are composited onto the background and their alpha masks compared
+	#[test]
+	fn test_alpha_compositing() {
+		let config = crate::get_default_config_options();
+		let cat = load_image_from_file( Path::new( "unit_test_images/cat1_best.jpg" ) ).unwrap().resize_exact( 200, 150, FilterType::Triangle ).to_rgba8();
+		let in_circle = |x: u32, y: u32| (x as i32 - 100).pow(2) + (y as i32 - 75).pow(2) < 60i32.pow(2);
+		//Create an image from a function giving the colour of each pixel
+		let make = |pixel: &dyn Fn(u32, u32) -> [u8;4]| DynamicImage::ImageRgba8( image::RgbaImage::from_fn( 200, 150, |x, y| image::Rgba( pixel( x, y ) ) ) );
+		let hash = |img: &DynamicImage, background: [u8;3]| ImageHashAV::from_image( img, &ImagePath { fpath: "logo.png".into(), alpha_background: Some(background), ..Default::default() }, 0, 0, 0 ).unwrap();
+		let white = ImageHashAV::DEFAULT_ALPHA_BACKGROUND;
+		let black = [0, 0, 0];
+
+		//The cat in a circle, surrounded by transparent black, transparent white or opaque white
+		let transparent_black = make( &|x, y| if in_circle( x, y ) { cat.get_pixel( x, y ).0 } else { [0, 0, 0, 0] } );
+		let transparent_white = make( &|x, y| if in_circle( x, y ) { cat.get_pixel( x, y ).0 } else { [255, 255, 255, 0] } );
+		let opaque_white = make( &|x, y| if in_circle( x, y ) { cat.get_pixel( x, y ).0 } else { [255, 255, 255, 255] } );
+
+		let a = hash( &transparent_black, white );
+		assert!( a.transparency > 0.3 && a.alpha_mask.is_some(), "Transparency measured" );
+		assert!( a.is_dupe( &hash( &transparent_white, white ), &config ), "Colour of transparent pixels is ignored" );
+		assert!( a.is_dupe( &hash( &opaque_white, white ), &config ), "Matches the image on the same background" );
+		assert!( !hash( &transparent_black, black ).is_dupe( &hash( &opaque_white, black ), &config ), "Doesn't match the image on a different background" );
+		assert!( hash( &opaque_white, white ).alpha_mask.is_none() );
+
+		//A white circle over black that looks the same composited onto black, but is transparent in a different area
+		let circle_mask = make( &|x, y| if in_circle( x, y ) { [255, 255, 255, 255] } else { [255, 255, 255, 0] } );
+		let corner_mask = make( &|x, y| if in_circle( x, y ) { [255, 255, 255, 255] } else if x < 40 && y < 40 { [200, 0, 0, 0] } else { [0, 0, 0, 255] } );
+		let circle = hash( &circle_mask, black );
+		assert_eq!( circle.diff_colour( &hash( &corner_mask, black ) ), 0 );
+		assert!( !circle.is_dupe( &hash( &corner_mask, black ), &config ), "Alpha masks differ" );
+	}
+	
 	/// Test that images which should not be duplicates of each other do not match
 	#[test]
 	fn test_image_uniques() {
@@ -440,14 +853,14 @@ mod tests {
 	
 		//Get a list of images that should be unique
 		for entry in glob("unit_test_images/*").expect("Failed to read glob pattern") {
-			let path = entry.unwrap().display().to_string();
-			if path.contains("_best.") {
+			let path = entry.unwrap();
+			if path.to_str().unwrap().contains("_best.") {
 				image_paths.push( path );
 			}
 		}
 	
 		for path in &image_paths {
-			let result = ImageHashAV::new( &ImagePath { fpath:  path.to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
+			let result = ImageHashAV::new( &ImagePath { fpath:  path.clone(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
 			image_hashes.push( result );
 		}
 		
@@ -0,0 +1,801 @@
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fs::{self, File};
+use std::io::{BufReader, BufWriter, Read, Write};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use rayon::prelude::*;
+
+use crate::archive;
+use crate::frames::FramePolicy;
+use crate::imagehash::{ConfigOptions, FrameHash, ImageHashAV, ImagePath};
+use crate::jpeg::JpegDecode;
+use crate::paths;
+use crate::raw;
+use crate::video;
+
+/// Identifies a photodedupe index file
+const MAGIC : &[u8;8] = b"PDDINDEX";
+
+/// Version of the index file format, increased when the format changes
+const VERSION : u32 = 4;
+
+/// Files and directories modified this close to the time they are read may be modified again without their modification time changing, so they are always read again on the next update
+const RACY_PERIOD : Duration = Duration::from_secs(2);
+
+/// The hashes of a collection of images saved to a file, so that new images can be compared with the collection without reading every image again.
+/// The file is little endian with paths stored as bytes, so it can be moved between machines.
+pub struct Index {
+	/// Which frames of animations were hashed
+	pub frame_policy : FramePolicy,
+	/// Colour that transparent pixels were composited onto before hashing
+	pub alpha_background : [u8;3],
+	/// Whether files of any extension were tested as images (--any-file)
+	pub any_file : bool,
+	/// How JPEGs were decoded, the EXIF thumbnail gives slightly different statistics to the image
+	pub jpeg_decode : JpegDecode,
+	/// Whether hidden files and directories were searched (--hidden)
+	pub include_hidden : bool,
+	/// Whether symbolic links were followed when searching directories (--follow-symlinks)
+	pub follow_symlinks : bool,
+	/// Maximum depth of directories searched (--max-depth)
+	pub max_depth : Option<usize>,
+	/// Files and directories skipped when searching (--exclude)
+	pub exclude_patterns : Vec<glob::Pattern>,
+	/// Whether images inside archives were indexed (--archives)
+	pub scan_archives : bool,
+	/// Whether videos were indexed (--videos)
+	pub scan_videos : bool,
+	/// Maximum bytes used by images being decoded at once when hashing (--memory-limit)
+	pub memory_limit : Option<u64>,
+	/// The files and directories indexed, which are searched again by update
+	pub roots : Vec<PathBuf>,
+	/// Modification time of each directory when it was last read, used to skip directories that haven't changed
+	directories : BTreeMap<PathBuf, u64>,
+	/// The images in the collection
+	pub entries : Vec<IndexEntry>,
+}
+
+/// An image in the index, with the state of its file when it was hashed
+pub struct IndexEntry {
+	/// The statistics of the image used to compare it
+	pub image : ImageHashAV,
+	/// Modification time of the file in nanoseconds since 1970, or 0 if it may have changed since
+	modified : u64,
+	/// Size of the file in bytes
+	size : u64,
+	/// Hash of the contents of the file, used to recognise files that have been moved
+	content_hash : u64,
+}
+
+/// Number of images found to have changed by an update
+#[derive(Debug, Default, PartialEq)]
+pub struct UpdateCounts {
+	pub added : usize,
+	pub removed : usize,
+	pub modified : usize,
+	pub moved : usize,
+	pub unchanged : usize,
+}
+
+/// Files found when searching the indexed directories
+#[derive(Default)]
+struct Scan {
+	/// Modification time of each directory read, or that hasn't changed
+	directories : BTreeMap<PathBuf, u64>,
+	/// Entries in directories that haven't changed, kept without checking the files
+	unchanged : Vec<usize>,
+	/// Files to check, with their modification time and size
+	files : Vec<(PathBuf, u64, u64)>,
+}
+
+impl Index {
+
+	/// Create an empty index of the files and directories given, hashed with the configured options. Relative paths are made absolute so the index can be updated from any directory.
+	pub fn new( roots: &[PathBuf], config: &ConfigOptions ) -> Index {
+		let roots = roots.iter().map( |root| std::path::absolute( root ).unwrap_or_else( |_| root.clone() ) ).collect();
+		Index { frame_policy: config.frame_policy, alpha_background: config.alpha_background, any_file: !config.only_known_file_extensions, jpeg_decode: config.jpeg_decode,
+				include_hidden: config.include_hidden, follow_symlinks: config.follow_symlinks, max_depth: config.max_depth, exclude_patterns: config.exclude_patterns.clone(),
+				scan_archives: config.scan_archives, scan_videos: config.scan_videos, memory_limit: config.memory_limit,
+				roots, directories: BTreeMap::new(), entries: Vec::new() }
+	}
+
+	/// Whether a file is a photodedupe index, of any version
+	pub fn is_index( fpath: &Path ) -> bool {
+		let mut magic = [0u8;8];
+		File::open( fpath ).and_then( |mut f| f.read_exact( &mut magic ) ).is_ok() && &magic == MAGIC
+	}
+
+	/// Check that images hashed with the configured options can be compared with the index
+	pub fn check_compatible( &self, config: &ConfigOptions ) -> Result<(), String> {
+		if self.frame_policy != config.frame_policy || self.alpha_background != config.alpha_background || self.jpeg_decode != config.jpeg_decode {
+			return Err("Error: The index was built with different --frames, --alpha-background or --exif-thumbnails options, so the images can't be compared with it.".to_string());
+		}
+		Ok(())
+	}
+
+	/// Configure the options used to find and hash images as they were when the index was built
+	pub fn configure( &self, config: &mut ConfigOptions ) {
+		config.frame_policy = self.frame_policy;
+		config.alpha_background = self.alpha_background;
+		config.only_known_file_extensions = !self.any_file;
+		config.jpeg_decode = self.jpeg_decode;
+		config.include_hidden = self.include_hidden;
+		config.follow_symlinks = self.follow_symlinks;
+		config.max_depth = self.max_depth;
+		config.exclude_patterns = self.exclude_patterns.clone();
+		config.scan_archives = self.scan_archives;
+		config.scan_videos = self.scan_videos;
+		config.memory_limit = self.memory_limit;
+	}
+
+	/// Add images that have already been hashed to the index, replacing any already indexed at the same path. Relative paths are made absolute.
+	/// Images outside the indexed files and directories are kept by later updates.
+	pub fn add( &mut self, mut images: Vec<ImageHashAV> ) {
+		for image in images.iter_mut() {
+			image.image_path.fpath = std::path::absolute( &image.image_path.fpath ).unwrap_or( image.image_path.fpath.clone() );
+		}
+		let new_paths : HashSet<PathBuf> = images.iter().map( |i| i.image_path.fpath.clone() ).collect();
+		self.entries.retain( |e| !new_paths.contains( &e.image.image_path.fpath ) );
+		let scan_time = SystemTime::now();
+		let states : Vec<(u64, u64, u64)> = images.par_iter().map( |image| {
+			let (modified, size) = fs::metadata( file_on_disk( &image.image_path.fpath ) ).map( |m| (modified_time( &m, scan_time ), m.len()) ).unwrap_or((0, 0));
+			(modified, size, content_hash( &image.image_path.fpath ).unwrap_or(0))
+		}).collect();
+		for (image, (modified, size, content_hash)) in images.into_iter().zip( states ) {
+			self.entries.push( IndexEntry { image, modified, size, content_hash } );
+		}
+	}
+
+	/// Bring the index up to date with the indexed directories, only hashing images that are new or have been modified.
+	/// Directories whose modification time hasn't changed aren't read again, and unless checking files the images in them are assumed not to have changed.
+	/// Files that have been moved or renamed are recognised by the hash of their contents and keep their entry.
+	pub fn update( &mut self, config: &ConfigOptions, check_files: bool ) -> UpdateCounts {
+		let scan = self.scan( config, check_files );
+		let mut counts = UpdateCounts::default();
+
+		let mut old_entries : Vec<Option<IndexEntry>> = std::mem::take( &mut self.entries ).into_iter().map( Some ).collect();
+		let mut entries = Vec::new();
+		for i in scan.unchanged {
+			if let Some(entry) = old_entries[i].take() {
+				entries.push( entry );
+			}
+		}
+		let old_index : HashMap<PathBuf, usize> = old_entries.iter().enumerate().filter_map( |(i, e)| e.as_ref().map( |e| (e.image.image_path.fpath.clone(), i) ) ).collect();
+
+		//Files with the same modification time and size haven't changed, otherwise the contents are compared
+		let mut changed = Vec::new();
+		for (fpath, modified, size) in scan.files {
+			let old = old_index.get( &fpath ).copied();
+			match old.and_then( |i| old_entries[i].as_mut() ) {
+				Some(entry) if entry.modified == modified && entry.modified != 0 && entry.size == size => {
+					entries.push( old_entries[old.unwrap()].take().unwrap() );
+				},
+				_ => changed.push( (fpath, modified, size, old) ),
+			}
+		}
+		let content_hashes : Vec<u64> = changed.par_iter().map( |(fpath, ..)| content_hash( fpath ).unwrap_or(0) ).collect();
+
+		let mut to_hash = Vec::new();
+		let mut new_states = HashMap::new();
+		for ((fpath, modified, size, old), hash) in changed.into_iter().zip( content_hashes ) {
+			if let Some(i) = old {
+				let same_contents = old_entries[i].as_ref().is_some_and( |e| e.size == size && e.content_hash == hash && hash != 0 );
+				if same_contents {
+					let mut entry = old_entries[i].take().unwrap();
+					entry.modified = modified;
+					entries.push( entry );
+					continue;
+				}
+			}
+			new_states.insert( fpath.clone(), (modified, size, hash, old.is_some()) );
+			to_hash.push( fpath );
+		}
+
+		//A new file with the same contents as a file that has gone was moved or renamed, so its entry is kept under the new path
+		let seen : HashSet<&PathBuf> = to_hash.iter().chain( entries.iter().map( |e| &e.image.image_path.fpath ) ).collect();
+		let mut gone : HashMap<(u64, u64), Vec<usize>> = HashMap::new();
+		for (i, entry) in old_entries.iter().enumerate() {
+			if let Some(entry) = entry {
+				if !seen.contains( &entry.image.image_path.fpath ) && entry.content_hash != 0 {
+					gone.entry( (entry.size, entry.content_hash) ).or_default().push( i );
+				}
+			}
+		}
+		to_hash.retain( |fpath| {
+			let (modified, size, hash, was_indexed) = new_states[fpath];
+			if was_indexed {
+				return true;
+			}
+			match gone.get_mut( &(size, hash) ).and_then( |g| g.pop() ) {
+				Some(i) => {
+					let mut entry = old_entries[i].take().unwrap();
+					entry.image.image_path.fpath = fpath.clone();
+					entry.modified = modified;
+					entries.push( entry );
+					counts.moved += 1;
+					false
+				},
+				None => true,
+			}
+		});
+		counts.unchanged = entries.len() - counts.moved;
+
+		//Hash the new and modified images
+		let image_paths : Vec<ImagePath> = to_hash.into_iter().map( |fpath| {
+			let is_video = config.scan_videos && video::is_video_file( &fpath );
+			ImagePath { fpath, is_video, alpha_background: Some(config.alpha_background), jpeg_decode: config.jpeg_decode, memory_limit: config.memory_limit, ..Default::default() }
+		}).collect();
+		let hashed = if image_paths.is_empty() { Vec::new() } else { crate::hash_images( image_paths, config ) };
+		for image in hashed {
+			let (modified, size, content_hash, was_indexed) = new_states[&image.image_path.fpath];
+			if was_indexed {
+				counts.modified += 1;
+			}else{
+				counts.added += 1;
+			}
+			entries.push( IndexEntry { image, modified, size, content_hash } );
+		}
+
+		let new_paths : HashSet<&PathBuf> = entries.iter().map( |e| &e.image.image_path.fpath ).collect();
+		counts.removed = old_entries.iter().flatten().filter( |e| !new_paths.contains( &e.image.image_path.fpath ) ).count();
+
+		entries.sort_by( |a, b| a.image.image_path.fpath.cmp( &b.image.image_path.fpath ) );
+		self.entries = entries;
+		self.directories = scan.directories;
+		counts
+	}
+
+	/// Search the indexed directories for images, with the hidden, exclude, symbolic link, depth, archive and video options the index was built with.
+	/// Directories that haven't changed since they were last read aren't read again, instead the images and directories indexed in them are used.
+	fn scan( &self, config: &ConfigOptions, check_files: bool ) -> Scan {
+		let mut scan = Scan::default();
+		let scan_time = SystemTime::now();
+
+		let mut subdirectories : HashMap<&Path, Vec<&Path>> = HashMap::new();
+		for directory in self.directories.keys() {
+			if let Some(parent) = directory.parent() {
+				subdirectories.entry( parent ).or_default().push( directory );
+			}
+		}
+		//Images inside an archive are in the directory of the archive
+		let mut images_in : HashMap<&Path, Vec<usize>> = HashMap::new();
+		for (i, entry) in self.entries.iter().enumerate() {
+			if let Some(parent) = file_on_disk( &entry.image.image_path.fpath ).parent() {
+				images_in.entry( parent ).or_default().push( i );
+			}
+		}
+
+		//Directories are read with their depth below the root
+		let mut to_read : Vec<(PathBuf, usize)> = Vec::new();
+		for root in &self.roots {
+			match fs::metadata( root ) {
+				Ok(metadata) if metadata.is_dir() => to_read.push( (root.clone(), 0) ),
+				Ok(metadata) => {
+					if !crate::is_excluded( root, false, config ) {
+						add_file( root.clone(), &metadata, config, scan_time, &mut scan );
+					}
+				},
+				Err(_) => {
+					//Images aren't removed from the index because a disk isn't mounted
+					eprintln!("ERROR: Failed to read: {}", root.display());
+					self.keep_stored( root, &mut scan );
+				}
+			}
+		}
+
+		//Images added from outside the indexed files and directories, e.g. with --update-index, are kept as they won't be found by searching
+		for (i, entry) in self.entries.iter().enumerate() {
+			let fpath = &entry.image.image_path.fpath;
+			if !self.roots.iter().any( |root| fpath.starts_with( root ) ) {
+				if !check_files {
+					scan.unchanged.push( i );
+				}else if let Ok(metadata) = fs::metadata( file_on_disk( fpath ) ) {
+					scan.files.push( (fpath.clone(), modified_time( &metadata, scan_time ), metadata.len()) );
+				}
+			}
+		}
+
+		//A directory reached again through a symbolic link, e.g. one that loops back to a directory containing it, is only read once
+		let mut read_directories = HashSet::new();
+		while let Some((directory, depth)) = to_read.pop() {
+			if config.follow_symlinks && !read_directories.insert( fs::canonicalize( &directory ).unwrap_or_else( |_| directory.clone() ) ) {
+				continue;
+			}
+			let modified = match fs::metadata( &directory ) {
+				Ok(metadata) => modified_time( &metadata, scan_time ),
+				Err(_) => 0,
+			};
+
+			if modified != 0 && self.directories.get( &directory ) == Some(&modified) {
+				//Nothing has been added, removed or renamed in the directory
+				for &i in images_in.get( directory.as_path() ).into_iter().flatten() {
+					let fpath = &self.entries[i].image.image_path.fpath;
+					if !check_files {
+						scan.unchanged.push( i );
+					}else if let Ok(metadata) = fs::metadata( file_on_disk( fpath ) ) {
+						scan.files.push( (fpath.clone(), modified_time( &metadata, scan_time ), metadata.len()) );
+					}
+				}
+				to_read.extend( subdirectories.get( directory.as_path() ).into_iter().flatten().map( |d| (d.to_path_buf(), depth + 1) ) );
+				scan.directories.insert( directory, modified );
+				continue;
+			}
+
+			let dir_entries = match fs::read_dir( &directory ) {
+				Ok(dir_entries) => dir_entries,
+				Err(e) => {
+					eprintln!("Warning: {}: {}", directory.display(), e);
+					self.keep_stored( &directory, &mut scan );
+					continue;
+				}
+			};
+			for dir_entry in dir_entries.flatten() {
+				//As when searching directories for images to compare, symbolic links are only followed with --follow-symlinks
+				let fpath = dir_entry.path();
+				let file_type = match dir_entry.file_type() {
+					Ok(file_type) if file_type.is_symlink() && config.follow_symlinks => match fs::metadata( &fpath ) {
+						Ok(metadata) => metadata.file_type(),
+						Err(_) => continue,
+					},
+					Ok(file_type) => file_type,
+					Err(_) => continue,
+				};
+				let hidden = !config.include_hidden && dir_entry.file_name().as_encoded_bytes().starts_with( b"." );
+				if hidden || crate::is_excluded( &fpath, file_type.is_dir(), config ) {
+					continue;
+				}
+				if file_type.is_dir() {
+					//Only directories whose files are within the maximum depth are read
+					if config.max_depth.is_none_or( |max_depth| depth + 1 < max_depth ) {
+						to_read.push( (fpath, depth + 1) );
+					}
+				}else if file_type.is_file() {
+					if let Ok(metadata) = fs::metadata( &fpath ) {
+						add_file( fpath, &metadata, config, scan_time, &mut scan );
+					}
+				}
+			}
+			scan.directories.insert( directory, modified );
+		}
+		scan
+	}
+
+	/// Keep the images and directories indexed within a directory that can't be read
+	fn keep_stored( &self, directory: &Path, scan: &mut Scan ) {
+		scan.unchanged.extend( self.entries.iter().enumerate().filter( |(_, e)| e.image.image_path.fpath.starts_with( directory ) ).map( |(i, _)| i ) );
+		//The directories are read again on the next update
+		scan.directories.extend( self.directories.keys().filter( |d| d.starts_with( directory ) ).map( |d| (d.clone(), 0) ) );
+	}
+
+	/// Read an index file. Images are part of the existing collection, RAW files are preferred as best if configured.
+	pub fn read( index_path: &Path, config: &ConfigOptions ) -> Result<Index, String> {
+		let read_error = |e: std::io::Error| format!("Error: Failed to read index {}: {}", index_path.display(), e);
+		let mut reader = BufReader::new( File::open( index_path ).map_err( read_error )? );
+
+		let mut magic = [0u8;8];
+		reader.read_exact( &mut magic ).map_err( read_error )?;
+		if &magic != MAGIC {
+			return Err(format!("Error: {} is not a photodedupe index", index_path.display()));
+		}
+		let version = read_u32( &mut reader ).map_err( read_error )?;
+		if version != VERSION {
+			return Err(format!("Error: Index {} was written by a different version of photodedupe, rebuild it with index build", index_path.display()));
+		}
+
+		let frame_policy = match read_u8( &mut reader ).map_err( read_error )? {
+			0 => FramePolicy::First,
+			1 => FramePolicy::All,
+			_ => FramePolicy::Sample,
+		};
+		let mut alpha_background = [0u8;3];
+		reader.read_exact( &mut alpha_background ).map_err( read_error )?;
+		let any_file = read_u8( &mut reader ).map_err( read_error )? != 0;
+		let jpeg_decode = match read_u8( &mut reader ).map_err( read_error )? {
+			0 => JpegDecode::Full,
+			1 => JpegDecode::Scaled,
+			_ => JpegDecode::Thumbnail,
+		};
+		let include_hidden = read_u8( &mut reader ).map_err( read_error )? != 0;
+		let follow_symlinks = read_u8( &mut reader ).map_err( read_error )? != 0;
+		let scan_archives = read_u8( &mut reader ).map_err( read_error )? != 0;
+		let scan_videos = read_u8( &mut reader ).map_err( read_error )? != 0;
+		let max_depth = match read_u64( &mut reader ).map_err( read_error )? {
+			u64::MAX => None,
+			max_depth => Some(max_depth as usize),
+		};
+		let memory_limit = match read_u64( &mut reader ).map_err( read_error )? {
+			0 => None,
+			memory_limit => Some(memory_limit),
+		};
+		let mut exclude_patterns = Vec::new();
+		for _ in 0..read_u32( &mut reader ).map_err( read_error )? {
+			let pattern = read_text( &mut reader ).map_err( read_error )?;
+			match glob::Pattern::new( &pattern ) {
+				Ok(pattern) => exclude_patterns.push( pattern ),
+				Err(_) => return Err(format!("Error: Index {} has an invalid --exclude pattern: {}", index_path.display(), pattern)),
+			}
+		}
+
+		let mut roots = Vec::new();
+		for _ in 0..read_u32( &mut reader ).map_err( read_error )? {
+			roots.push( read_path( &mut reader ).map_err( read_error )? );
+		}
+		let mut directories = BTreeMap::new();
+		for _ in 0..read_u64( &mut reader ).map_err( read_error )? {
+			let directory = read_path( &mut reader ).map_err( read_error )?;
+			directories.insert( directory, read_u64( &mut reader ).map_err( read_error )? );
+		}
+
+		let num_images = read_u64( &mut reader ).map_err( read_error )?;
+		let mut entries = Vec::new();
+		for _ in 0..num_images {
+			let mut image = read_image( &mut reader ).map_err( read_error )?;
+			image.image_path.prefer_as_best = config.prefer_raw && raw::is_raw_file( &image.image_path.fpath );
+			let modified = read_u64( &mut reader ).map_err( read_error )?;
+			let size = read_u64( &mut reader ).map_err( read_error )?;
+			let content_hash = read_u64( &mut reader ).map_err( read_error )?;
+			entries.push( IndexEntry { image, modified, size, content_hash } );
+		}
+
+		Ok(Index { frame_policy, alpha_background, any_file, jpeg_decode, include_hidden, follow_symlinks, max_depth, exclude_patterns, scan_archives, scan_videos, memory_limit, roots, directories, entries })
+	}
+
+	/// Write the index to a file. The file is written alongside and then renamed, so an existing index isn't lost if writing fails.
+	pub fn write( &self, index_path: &Path ) -> Result<(), String> {
+		let write_error = |e: std::io::Error| format!("Error: Failed to write index {}: {}", index_path.display(), e);
+		let mut temp_name = index_path.file_name().unwrap_or_default().to_os_string();
+		temp_name.push( ".tmp" );
+		let temp_path = index_path.with_file_name( temp_name );
+
+		let mut writer = BufWriter::new( File::create( &temp_path ).map_err( write_error )? );
+		writer.write_all( MAGIC ).map_err( write_error )?;
+		writer.write_all( &VERSION.to_le_bytes() ).map_err( write_error )?;
+		let frame_policy : u8 = match self.frame_policy {
+			FramePolicy::First => 0,
+			FramePolicy::All => 1,
+			FramePolicy::Sample => 2,
+		};
+		writer.write_all( &[frame_policy] ).map_err( write_error )?;
+		writer.write_all( &self.alpha_background ).map_err( write_error )?;
+		writer.write_all( &[self.any_file as u8] ).map_err( write_error )?;
+		let jpeg_decode : u8 = match self.jpeg_decode {
+			JpegDecode::Full => 0,
+			JpegDecode::Scaled => 1,
+			JpegDecode::Thumbnail => 2,
+		};
+		writer.write_all( &[jpeg_decode] ).map_err( write_error )?;
+		writer.write_all( &[self.include_hidden as u8, self.follow_symlinks as u8, self.scan_archives as u8, self.scan_videos as u8] ).map_err( write_error )?;
+		writer.write_all( &self.max_depth.map_or( u64::MAX, |d| d as u64 ).to_le_bytes() ).map_err( write_error )?;
+		writer.write_all( &self.memory_limit.unwrap_or(0).to_le_bytes() ).map_err( write_error )?;
+		writer.write_all( &(self.exclude_patterns.len() as u32).to_le_bytes() ).map_err( write_error )?;
+		for pattern in &self.exclude_patterns {
+			write_text( &mut writer, pattern.as_str() ).map_err( write_error )?;
+		}
+		writer.write_all( &(self.roots.len() as u32).to_le_bytes() ).map_err( write_error )?;
+		for root in &self.roots {
+			write_path( &mut writer, root ).map_err( write_error )?;
+		}
+		writer.write_all( &(self.directories.len() as u64).to_le_bytes() ).map_err( write_error )?;
+		for (directory, modified) in &self.directories {
+			write_path( &mut writer, directory ).map_err( write_error )?;
+			writer.write_all( &modified.to_le_bytes() ).map_err( write_error )?;
+		}
+		writer.write_all( &(self.entries.len() as u64).to_le_bytes() ).map_err( write_error )?;
+		for entry in &self.entries {
+			write_image( &mut writer, &entry.image ).map_err( write_error )?;
+			writer.write_all( &entry.modified.to_le_bytes() ).map_err( write_error )?;
+			writer.write_all( &entry.size.to_le_bytes() ).map_err( write_error )?;
+			writer.write_all( &entry.content_hash.to_le_bytes() ).map_err( write_error )?;
+		}
+		writer.into_inner().map_err( |e| write_error( e.into_error() ) )?.sync_all().map_err( write_error )?;
+
+		fs::rename( &temp_path, index_path ).map_err( write_error )
+	}
+}
+
+/// Add a file found when searching to the files to check. With --archives the images inside an archive are added, with the modification time and size of the archive.
+fn add_file( fpath: PathBuf, metadata: &fs::Metadata, config: &ConfigOptions, scan_time: SystemTime, scan: &mut Scan ) {
+	let modified = modified_time( metadata, scan_time );
+	if config.scan_archives && archive::is_archive( &fpath ) {
+		match archive::list_images( &fpath, config ) {
+			Ok(images) => scan.files.extend( images.into_iter().map( |image| (image, modified, metadata.len()) ) ),
+			Err(e) => eprintln!("{}", e),
+		}
+	}else if crate::valid_file_extension( &fpath, config ) || ( config.scan_videos && video::is_video_file( &fpath ) ) {
+		scan.files.push( (fpath, modified, metadata.len()) );
+	}
+}
+
+/// Modification time of a file in nanoseconds since 1970. Returns 0, meaning the file must be checked again, if the time isn't known or the file was modified so recently it could change again without the time changing.
+fn modified_time( metadata: &fs::Metadata, scan_time: SystemTime ) -> u64 {
+	match metadata.modified() {
+		Ok(modified) if modified + RACY_PERIOD < scan_time => modified.duration_since( UNIX_EPOCH ).map( |d| d.as_nanos() as u64 ).unwrap_or(0),
+		_ => 0,
+	}
+}
+
+/// 64 bit FNV-1a hash of the contents of a file. Much quicker than hashing the image, so moved files can be recognised without decoding them.
+fn content_hash( fpath: &Path ) -> std::io::Result<u64> {
+	let mut file = File::open( fpath )?;
+	let mut buffer = vec![ 0u8; 64 * 1024 ];
+	let mut hash : u64 = 0xcbf29ce484222325;
+	loop {
+		let n = file.read( &mut buffer )?;
+		if n == 0 {
+			return Ok(hash);
+		}
+		for &byte in &buffer[..n] {
+			hash = ( hash ^ byte as u64 ).wrapping_mul( 0x100000001b3 );
+		}
+	}
+}
+
+/// The file on disk holding an image, which is the archive for an image inside an archive
+fn file_on_disk( fpath: &Path ) -> &Path {
+	archive::split_member_path( fpath ).map_or( fpath, |(archive_path, _)| archive_path )
+}
+
+/// Write bytes as their length followed by the bytes
+fn write_bytes( writer: &mut impl Write, bytes: &[u8] ) -> std::io::Result<()> {
+	writer.write_all( &(bytes.len() as u32).to_le_bytes() )?;
+	writer.write_all( bytes )
+}
+
+/// Read bytes written by write_bytes. The length is only trusted as far as there are bytes to read, so a corrupt index doesn't allocate a huge buffer.
+fn read_bytes( reader: &mut impl Read ) -> std::io::Result<Vec<u8>> {
+	let length = read_u32( reader )? as u64;
+	let mut bytes = Vec::new();
+	reader.take( length ).read_to_end( &mut bytes )?;
+	if bytes.len() as u64 != length {
+		return Err(std::io::Error::new( std::io::ErrorKind::UnexpectedEof, "index is truncated" ));
+	}
+	Ok(bytes)
+}
+
+/// Write a path as its length followed by its bytes
+fn write_path( writer: &mut impl Write, path: &Path ) -> std::io::Result<()> {
+	write_bytes( writer, &paths::path_to_bytes( path ) )
+}
+
+/// Read a path written by write_path
+fn read_path( reader: &mut impl Read ) -> std::io::Result<PathBuf> {
+	Ok(paths::path_from_bytes( read_bytes( reader )? ))
+}
+
+/// Write text as its length followed by its UTF-8 bytes
+fn write_text( writer: &mut impl Write, text: &str ) -> std::io::Result<()> {
+	write_bytes( writer, text.as_bytes() )
+}
+
+/// Read text written by write_text
+fn read_text( reader: &mut impl Read ) -> std::io::Result<String> {
+	String::from_utf8( read_bytes( reader )? ).map_err( |e| std::io::Error::new( std::io::ErrorKind::InvalidData, e ) )
+}
+
+/// Write the statistics of an image used to compare it
+fn write_image( writer: &mut impl Write, image: &ImageHashAV ) -> std::io::Result<()> {
+	write_path( writer, &image.image_path.fpath )?;
+	writer.write_all( &[image.image_path.is_video as u8] )?;
+	writer.write_all( &image.width.to_le_bytes() )?;
+	writer.write_all( &image.height.to_le_bytes() )?;
+	writer.write_all( &image.file_size.to_le_bytes() )?;
+	writer.write_all( &image.num_pixels.to_le_bytes() )?;
+	writer.write_all( &image.std_dev.to_le_bytes() )?;
+	writer.write_all( &image.grey_hash.to_le_bytes() )?;
+	writer.write_all( &image.low_res )?;
+	writer.write_all( &image.transparency.to_le_bytes() )?;
+	match image.alpha_mask {
+		Some(ref alpha_mask) => {
+			writer.write_all( &[1] )?;
+			writer.write_all( alpha_mask )?;
+		},
+		None => writer.write_all( &[0] )?,
+	}
+	writer.write_all( &(image.frames.len() as u32).to_le_bytes() )?;
+	for frame in &image.frames {
+		writer.write_all( &frame.low_res )?;
+		writer.write_all( &frame.std_dev.to_le_bytes() )?;
+	}
+	Ok(())
+}
+
+/// Read the statistics of an image written by write_image
+fn read_image( reader: &mut impl Read ) -> std::io::Result<ImageHashAV> {
+	let fpath = read_path( reader )?;
+	let is_video = read_u8( reader )? != 0;
+	let width = read_u32( reader )?;
+	let height = read_u32( reader )?;
+	let file_size = read_u64( reader )?;
+	let num_pixels = read_u64( reader )?;
+	let std_dev = read_f32( reader )?;
+	let grey_hash = read_u64( reader )?;
+	let mut low_res = [0u8;192];
+	reader.read_exact( &mut low_res )?;
+	let transparency = read_f32( reader )?;
+	let alpha_mask = match read_u8( reader )? {
+		0 => None,
+		_ => {
+			let mut alpha_mask = [0u8;64];
+			reader.read_exact( &mut alpha_mask )?;
+			Some(alpha_mask)
+		}
+	};
+	let mut frames = Vec::new();
+	for _ in 0..read_u32( reader )? {
+		let mut frame_low_res = [0u8;192];
+		reader.read_exact( &mut frame_low_res )?;
+		frames.push( FrameHash { low_res: frame_low_res, std_dev: read_f32( reader )? } );
+	}
+
+	let image_path = ImagePath { fpath, is_video, ..Default::default() };
+	Ok(ImageHashAV { dupe_group: 0, grey_hash, low_res, width, height, file_size, num_pixels, std_dev, image_path, frames, transparency, alpha_mask })
+}
+
+fn read_u8( reader: &mut impl Read ) -> std::io::Result<u8> {
+	let mut bytes = [0u8;1];
+	reader.read_exact( &mut bytes )?;
+	Ok(bytes[0])
+}
+
+fn read_u32( reader: &mut impl Read ) -> std::io::Result<u32> {
+	let mut bytes = [0u8;4];
+	reader.read_exact( &mut bytes )?;
+	Ok(u32::from_le_bytes( bytes ))
+}
+
+fn read_u64( reader: &mut impl Read ) -> std::io::Result<u64> {
+	let mut bytes = [0u8;8];
+	reader.read_exact( &mut bytes )?;
+	Ok(u64::from_le_bytes( bytes ))
+}
+
+fn read_f32( reader: &mut impl Read ) -> std::io::Result<f32> {
+	Ok(f32::from_bits( read_u32( reader )? ))
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Test an index is written and read back with the same image statistics, and can be compared with new images
+	#[test]
+	fn test_index_round_trip() {
+		let config = crate::get_default_config_options();
+		let image = |fpath: &str| ImageHashAV::new( &ImagePath { fpath: fpath.into(), ..Default::default() }, 0, 0 ).unwrap();
+		let mut index = Index::new( &[ PathBuf::from( "unit_test_images" ) ], &config );
+		index.add( vec![ image( "unit_test_images/cat1_best.jpg" ), image( "unit_test_images/bridge1_best.jpg" ) ] );
+		index.add( vec![ image( "unit_test_images/bridge1_best.jpg" ), image( "unit_test_images/car1_best.jpg" ) ] );
+		assert_eq!( index.entries.len(), 3, "Image at the same path replaced" );
+		assert!( index.entries[0].image.image_path.fpath.is_absolute() );
+		index.entries[2].image.alpha_mask = Some([ 7u8; 64 ]);
+		index.entries[2].image.frames.push( FrameHash { low_res: [ 3u8; 192 ], std_dev: 1.5 } );
+		index.include_hidden = true;
+		index.max_depth = Some(2);
+		index.exclude_patterns = vec![ glob::Pattern::new( "**/@eaDir/**" ).unwrap() ];
+		index.memory_limit = Some(1 << 30);
+
+		let index_path = std::env::temp_dir().join( "photodedupe_index_test.idx" );
+		index.write( &index_path ).unwrap();
+		let read = Index::read( &index_path, &config ).unwrap();
+		std::fs::remove_file( &index_path ).unwrap();
+
+		assert!( read.check_compatible( &config ).is_ok() );
+		assert_eq!( read.roots, index.roots );
+		let mut read_config = crate::get_default_config_options();
+		read.configure( &mut read_config );
+		assert!( read_config.include_hidden && !read_config.follow_symlinks && !read_config.scan_archives && !read_config.scan_videos );
+		assert_eq!( (read_config.max_depth, read_config.memory_limit), (Some(2), Some(1 << 30)) );
+		assert_eq!( read_config.exclude_patterns, index.exclude_patterns );
+		assert_eq!( read.entries.len(), index.entries.len() );
+		for (entry_a, entry_b) in read.entries.iter().zip( index.entries.iter() ) {
+			assert_eq!( (entry_a.modified, entry_a.size, entry_a.content_hash), (entry_b.modified, entry_b.size, entry_b.content_hash) );
+			let (a, b) = (&entry_a.image, &entry_b.image);
+			assert_eq!( a.image_path.fpath, b.image_path.fpath );
+			assert_eq!( (a.grey_hash, a.low_res, a.width, a.height, a.file_size, a.num_pixels), (b.grey_hash, b.low_res, b.width, b.height, b.file_size, b.num_pixels) );
+			assert_eq!( (a.std_dev, a.transparency, a.alpha_mask), (b.std_dev, b.transparency, b.alpha_mask) );
+			assert_eq!( a.frames.len(), b.frames.len() );
+		}
+		assert!( read.entries[0].image.is_dupe( &image( "unit_test_images/cat1_duplicate_1.jpg" ), &config ), "Indexed image compared with a new image" );
+
+		let mut other_config = crate::get_default_config_options();
+		other_config.frame_policy = FramePolicy::All;
+		assert!( read.check_compatible( &other_config ).is_err(), "Hashed with different frames" );
+		let mut thumbnail_config = crate::get_default_config_options();
+		thumbnail_config.jpeg_decode = JpegDecode::Thumbnail;
+		assert!( read.check_compatible( &thumbnail_config ).is_err(), "Hashed from EXIF thumbnails" );
+		assert!( Index::read( Path::new( "unit_test_images/cat1_best.jpg" ), &config ).is_err(), "Not an index" );
+
+		//A corrupt path length fails to read rather than allocating it
+		let mut corrupt = MAGIC.to_vec();
+		corrupt.extend( VERSION.to_le_bytes() );
+		corrupt.extend( [ 0, 255, 255, 255, 0, 1, 0, 0, 0, 0 ] );
+		corrupt.extend( u64::MAX.to_le_bytes() );
+		corrupt.extend( 0u64.to_le_bytes() );
+		corrupt.extend( 0u32.to_le_bytes() );
+		corrupt.extend( 1u32.to_le_bytes() );
+		corrupt.extend( u32::MAX.to_le_bytes() );
+		corrupt.extend( b"/photos" );
+		fs::write( &index_path, corrupt ).unwrap();
+		let error = Index::read( &index_path, &config ).err().unwrap();
+		fs::remove_file( &index_path ).unwrap();
+		assert!( error.starts_with( "Error: Failed to read index" ), "{}", error );
+	}
+
+	/// Test an index update finds added, removed, moved and modified images, only hashing the new and modified images
+	#[test]
+	fn test_index_update() {
+		let dir = std::env::temp_dir().join( "photodedupe_index_update_test" );
+		let _ = fs::remove_dir_all( &dir );
+		fs::create_dir_all( dir.join( "sub" ) ).unwrap();
+		fs::copy( "unit_test_images/cat1_best.jpg", dir.join( "a.jpg" ) ).unwrap();
+		fs::copy( "unit_test_images/bridge1_best.jpg", dir.join( "sub/b.jpg" ) ).unwrap();
+		fs::write( dir.join( "notes.txt" ), "Not an image" ).unwrap();
+
+		//Modified long enough ago that an unchanged directory isn't read again
+		let past = SystemTime::now() - Duration::from_secs(3600);
+		for fpath in [ "a.jpg", "sub/b.jpg", "sub", "" ] {
+			File::open( dir.join( fpath ) ).unwrap().set_modified( past ).unwrap();
+		}
+
+		let config = crate::get_default_config_options();
+		let mut index = Index::new( std::slice::from_ref( &dir ), &config );
+		assert_eq!( index.update( &config, false ), UpdateCounts { added: 2, ..Default::default() } );
+		assert_eq!( index.directories.len(), 2 );
+		assert_eq!( index.update( &config, false ), UpdateCounts { unchanged: 2, ..Default::default() } );
+
+		//Overwritten in place, which doesn't change the directory
+		fs::copy( "unit_test_images/car1_best.jpg", dir.join( "sub/b.jpg" ) ).unwrap();
+		assert_eq!( index.update( &config, false ), UpdateCounts { unchanged: 2, ..Default::default() }, "Unchanged directory not read" );
+		assert_eq!( index.update( &config, true ), UpdateCounts { modified: 1, unchanged: 1, ..Default::default() }, "Found when checking files" );
+
+		fs::rename( dir.join( "sub/b.jpg" ), dir.join( "car.jpg" ) ).unwrap();
+		fs::remove_file( dir.join( "a.jpg" ) ).unwrap();
+		fs::copy( "unit_test_images/cat1_duplicate_1.jpg", dir.join( "sub/c.jpg" ) ).unwrap();
+		assert_eq!( index.update( &config, false ), UpdateCounts { added: 1, removed: 1, moved: 1, ..Default::default() } );
+		let fpaths : Vec<&Path> = index.entries.iter().map( |e| e.image.image_path.fpath.as_path() ).collect();
+		assert_eq!( fpaths, vec![ dir.join( "car.jpg" ), dir.join( "sub/c.jpg" ) ] );
+		assert_eq!( index.entries[0].image.width, ImageHashAV::new( &ImagePath { fpath: "unit_test_images/car1_best.jpg".into(), ..Default::default() }, 0, 0 ).unwrap().width );
+
+		fs::remove_dir_all( &dir ).unwrap();
+	}
+
+	/// Test updates search directories with the options the index was built with, and keep images added from outside the indexed directories
+	#[test]
+	fn test_index_search_options() {
+		let dir = std::env::temp_dir().join( "photodedupe_index_search_test" );
+		let _ = fs::remove_dir_all( &dir );
+		fs::create_dir_all( dir.join( "collection/sub" ) ).unwrap();
+		fs::create_dir_all( dir.join( "collection/skip" ) ).unwrap();
+		fs::copy( "unit_test_images/cat1_best.jpg", dir.join( "collection/a.jpg" ) ).unwrap();
+		fs::copy( "unit_test_images/bridge1_best.jpg", dir.join( "collection/.b.jpg" ) ).unwrap();
+		fs::copy( "unit_test_images/car1_best.jpg", dir.join( "collection/sub/c.jpg" ) ).unwrap();
+		fs::copy( "unit_test_images/cat1_duplicate_1.jpg", dir.join( "collection/skip/d.jpg" ) ).unwrap();
+		fs::copy( "unit_test_images/bridge1_duplicate_1.jpg", dir.join( "outside.jpg" ) ).unwrap();
+		let collection = dir.join( "collection" );
+		File::open( dir.join( "outside.jpg" ) ).unwrap().set_modified( SystemTime::now() - Duration::from_secs(3600) ).unwrap();
+
+		let config = crate::get_default_config_options();
+		let mut index = Index::new( std::slice::from_ref( &collection ), &config );
+		assert_eq!( index.update( &config, false ).added, 3, "Hidden file skipped" );
+
+		let mut config = crate::get_default_config_options();
+		config.include_hidden = true;
+		config.max_depth = Some(1);
+		config.exclude_patterns = vec![ glob::Pattern::new( "**/skip" ).unwrap() ];
+		let mut index = Index::new( std::slice::from_ref( &collection ), &config );
+		assert_eq!( index.update( &config, false ).added, 2, "Hidden file included, subdirectories not searched" );
+
+		//Updating with the options read from the index searches the same files
+		let mut update_config = crate::get_default_config_options();
+		index.configure( &mut update_config );
+		assert_eq!( index.update( &update_config, false ), UpdateCounts { unchanged: 2, ..Default::default() } );
+
+		index.add( vec![ ImageHashAV::new( &ImagePath { fpath: dir.join( "outside.jpg" ), ..Default::default() }, 0, 0 ).unwrap() ] );
+		assert_eq!( index.update( &update_config, false ), UpdateCounts { unchanged: 3, ..Default::default() }, "Image outside the collection kept" );
+		assert_eq!( index.update( &update_config, true ), UpdateCounts { unchanged: 3, ..Default::default() }, "Image outside the collection checked" );
+		fs::remove_file( dir.join( "outside.jpg" ) ).unwrap();
+		assert_eq!( index.update( &update_config, true ), UpdateCounts { unchanged: 2, removed: 1, ..Default::default() }, "Image outside the collection removed" );
+
+		fs::remove_dir_all( &dir ).unwrap();
+	}
+}
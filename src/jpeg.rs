@@ -0,0 +1,198 @@
+use std::io::Cursor;
+use std::path::Path;
+use image::{DynamicImage, ImageBuffer, ImageFormat};
+use exif::{In, Tag};
+use jpeg_decoder::PixelFormat;
+
+use crate::colour;
+use crate::formats::{self, Decoder, FileFormat};
+
+/// How JPEGs are decoded for hashing. Only an 8x8 version of each image is needed, so the full resolution doesn't have to be decoded.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum JpegDecode {
+	/// Decode at full resolution
+	Full,
+	/// Decode at 1/2 or 1/4 scale in the DCT domain, keeping at least REDUCED_MIN_SIZE pixels on the shortest side
+	#[default]
+	Scaled,
+	/// Use the thumbnail embedded in the EXIF data if it is large enough and has the same aspect ratio, otherwise as Scaled
+	Thumbnail,
+}
+
+/// Minimum number of pixels on the shortest side of a reduced resolution image used for hashing.
+/// Smaller images are decoded at full resolution as the 8x8 version of them starts to drift from that of the full image.
+pub const REDUCED_MIN_SIZE : u32 = 256;
+
+/// Largest reduction in scale. The decoder also supports 1/8 but that only uses the DC coefficient of each block, which it rounds down and so shifts the colours.
+const MAX_SCALE_DIVISOR : u32 = 4;
+
+/// Minimum number of pixels on the shortest side of an EXIF thumbnail for it to be used, the usual size is 160x120
+const THUMBNAIL_MIN_SIZE : u32 = 120;
+
+/// Maximum difference between the aspect ratio of an EXIF thumbnail and the image for the thumbnail to be used
+const THUMBNAIL_ASPECT_TOLERANCE : f32 = 0.01;
+
+/// Is the file a JPEG according to its extension
+pub fn is_jpeg_path( fpath: &Path ) -> bool {
+	matches!( formats::format_from_path( fpath ), Some(FileFormat { decoder: Decoder::Image(ImageFormat::Jpeg), .. }) )
+}
+
+/// Decode a JPEG at reduced resolution. Returns the image and the dimensions of the full resolution image.
+/// Returns None if the data is not a JPEG that can be decoded this way (e.g. CMYK) or it would need more memory than the limit, in which case it should be decoded normally.
+pub fn load_reduced( data: &[u8], mode: JpegDecode, memory_limit: Option<u64> ) -> Option<(DynamicImage, (u32, u32))> {
+	if mode == JpegDecode::Full || !data.starts_with( &[ 0xFF, 0xD8, 0xFF ] ) {
+		return None;
+	}
+
+	let mut decoder = jpeg_decoder::Decoder::new( Cursor::new( data ) );
+	if let Some(memory_limit) = memory_limit {
+		decoder.set_max_decoding_buffer_size( memory_limit.try_into().unwrap_or( usize::MAX ) );
+	}
+	decoder.read_info().ok()?;
+	let info = decoder.info()?;
+	let dimensions = (info.width as u32, info.height as u32);
+	if !matches!( info.pixel_format, PixelFormat::L8 | PixelFormat::RGB24 ) {
+		return None;
+	}
+	let icc_profile = decoder.icc_profile();
+
+	if mode == JpegDecode::Thumbnail {
+		if let Some(thumbnail) = decoder.exif_data().and_then( |exif| exif_thumbnail( exif, dimensions ) ) {
+			return Some((colour::normalise( thumbnail, icc_profile.as_deref() ), dimensions));
+		}
+	}
+
+	//Small images gain nothing from the reduced decode, so are left to the usual decoder
+	let divisor = scale_divisor( dimensions );
+	if divisor == 1 {
+		return None;
+	}
+	//The decoder picks the smallest scale that is at least the requested size
+	let requested = |len: u32| len.div_ceil( divisor ).min( u16::MAX as u32 ) as u16;
+	let (width, height) = decoder.scale( requested( dimensions.0 ), requested( dimensions.1 ) ).ok()?;
+	let pixels = decoder.decode().ok()?;
+	let img = match info.pixel_format {
+		PixelFormat::L8 => ImageBuffer::from_raw( width as u32, height as u32, pixels ).map( DynamicImage::ImageLuma8 )?,
+		_ => ImageBuffer::from_raw( width as u32, height as u32, pixels ).map( DynamicImage::ImageRgb8 )?,
+	};
+	Some((colour::normalise( img, icc_profile.as_deref() ), dimensions))
+}
+
+/// The reduction in scale used to decode a JPEG of the given dimensions, 1 if it is decoded at full resolution
+pub fn scale_divisor( dimensions: (u32, u32) ) -> u32 {
+	let shortest = dimensions.0.min( dimensions.1 );
+	let mut divisor = MAX_SCALE_DIVISOR;
+	while divisor > 1 && shortest / divisor < REDUCED_MIN_SIZE {
+		divisor /= 2;
+	}
+	divisor
+}
+
+/// Decode the thumbnail in EXIF data (starting at the TIFF header), if it is large enough and has the same aspect ratio as the image.
+/// Editing software may crop an image without updating its thumbnail, so thumbnails of another shape are ignored.
+fn exif_thumbnail( exif_data: &[u8], dimensions: (u32, u32) ) -> Option<DynamicImage> {
+	let exif = exif::Reader::new().read_raw( exif_data.to_vec() ).ok()?;
+	let offset = exif.get_field( Tag::JPEGInterchangeFormat, In::THUMBNAIL )?.value.get_uint( 0 )? as usize;
+	let length = exif.get_field( Tag::JPEGInterchangeFormatLength, In::THUMBNAIL )?.value.get_uint( 0 )? as usize;
+	let thumbnail = exif.buf().get( offset..offset.checked_add( length )? )?;
+	let img = image::load_from_memory_with_format( thumbnail, ImageFormat::Jpeg ).ok()?;
+
+	let aspect_ratio = dimensions.0 as f32 / dimensions.1 as f32;
+	let thumbnail_aspect_ratio = img.width() as f32 / img.height() as f32;
+	if img.width().min( img.height() ) < THUMBNAIL_MIN_SIZE || ( thumbnail_aspect_ratio / aspect_ratio - 1.0 ).abs() > THUMBNAIL_ASPECT_TOLERANCE {
+		return None;
+	}
+	Some(img)
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use image::imageops::FilterType;
+	use crate::frames::FramePolicy;
+	use crate::imagehash::{ImageHashAV, ImagePath};
+
+	/// Hash a JPEG held in memory
+	fn hash( data: &[u8], jpeg_decode: JpegDecode ) -> ImageHashAV {
+		ImageHashAV::from_data( data, &ImagePath { fpath: "photo.jpg".into(), jpeg_decode, ..Default::default() }, 0, 0, FramePolicy::First ).unwrap()
+	}
+
+	/// Insert EXIF data holding only a thumbnail after the start of image marker of a JPEG
+	fn with_thumbnail( jpeg: &[u8], thumbnail: &[u8] ) -> Vec<u8> {
+		let mut tiff : Vec<u8> = b"II*\0".to_vec();
+		tiff.extend_from_slice( &8u32.to_le_bytes() );
+		//Empty IFD0 pointing to IFD1, which holds the offset and length of the thumbnail
+		tiff.extend_from_slice( &0u16.to_le_bytes() );
+		tiff.extend_from_slice( &14u32.to_le_bytes() );
+		tiff.extend_from_slice( &2u16.to_le_bytes() );
+		for (tag, value) in [ (0x0201u16, 44u32), (0x0202, thumbnail.len() as u32) ] {
+			tiff.extend_from_slice( &tag.to_le_bytes() );
+			tiff.extend_from_slice( &4u16.to_le_bytes() );
+			tiff.extend_from_slice( &1u32.to_le_bytes() );
+			tiff.extend_from_slice( &value.to_le_bytes() );
+		}
+		tiff.extend_from_slice( &0u32.to_le_bytes() );
+		tiff.extend_from_slice( thumbnail );
+
+		let mut data = jpeg[..2].to_vec();
+		data.extend_from_slice( &[ 0xFF, 0xE1 ] );
+		data.extend_from_slice( &( ( 2 + 6 + tiff.len() ) as u16 ).to_be_bytes() );
+		data.extend_from_slice( b"Exif\0\0" );
+		data.extend_from_slice( &tiff );
+		data.extend_from_slice( &jpeg[2..] );
+		data
+	}
+
+	/// Encode an image as a JPEG
+	fn encode_jpeg( img: &image::DynamicImage ) -> Vec<u8> {
+		let mut data = Vec::new();
+		img.write_to( &mut Cursor::new( &mut data ), ImageFormat::Jpeg ).unwrap();
+		data
+	}
+
+	/// Test reduced resolution decoding gives the same statistics as decoding at full resolution
+	#[test]
+	fn test_reduced_hash_stability() {
+		let config = crate::get_default_config_options();
+		let mut num_reduced = 0;
+		for entry in std::fs::read_dir( "unit_test_images" ).unwrap() {
+			let fpath = entry.unwrap().path().to_string_lossy().to_string();
+			if !fpath.ends_with( ".jpg" ) {
+				continue;
+			}
+			let data = std::fs::read( &fpath ).unwrap();
+			let full = hash( &data, JpegDecode::Full );
+			let scaled = hash( &data, JpegDecode::Scaled );
+			assert_eq!( (scaled.width, scaled.height), (full.width, full.height), "Dimensions of the full image kept: {}", fpath );
+			assert!( full.diff_colour( &scaled ) < ImageHashAV::DEFAULT_COLOUR_DIFF_THRESHOLD, "Hash stable: {}", fpath );
+			assert!( full.is_dupe( &scaled, &config ) );
+			if load_reduced( &data, JpegDecode::Scaled, None ).is_some() {
+				num_reduced += 1;
+			}
+		}
+		assert!( num_reduced > 0, "Some test images are large enough to reduce" );
+		assert!( load_reduced( &std::fs::read( "unit_test_images/cat1_best.jpg" ).unwrap(), JpegDecode::Full, None ).is_none() );
+		assert_eq!( [ scale_divisor( (6000, 4000) ), scale_divisor( (800, 600) ), scale_divisor( (500, 1000) ) ], [ 4, 2, 1 ] );
+	}
+
+	/// Test EXIF thumbnails are used if they are large enough and the same shape as the image
+	#[test]
+	fn test_exif_thumbnail() {
+		let img = image::load_from_memory( &std::fs::read( "unit_test_images/book1_best.jpg" ).unwrap() ).unwrap();
+		let other = image::load_from_memory( &std::fs::read( "unit_test_images/car1_best.jpg" ).unwrap() ).unwrap();
+		let jpeg = encode_jpeg( &img );
+
+		//A thumbnail of a different image shows whether it was used
+		let data = with_thumbnail( &jpeg, &encode_jpeg( &other.resize_exact( 180, 120, FilterType::Triangle ) ) );
+		let (thumbnail, dimensions) = load_reduced( &data, JpegDecode::Thumbnail, None ).unwrap();
+		assert_eq!( (thumbnail.width(), thumbnail.height()), (180, 120), "Thumbnail used" );
+		assert_eq!( dimensions, (img.width(), img.height()) );
+		assert!( hash( &data, JpegDecode::Scaled ).diff_colour( &hash( &jpeg, JpegDecode::Scaled ) ) == 0, "Thumbnail only used when asked for" );
+
+		for (width, height) in [ (120, 120), (90, 60) ] {
+			let data = with_thumbnail( &jpeg, &encode_jpeg( &other.resize_exact( width, height, FilterType::Triangle ) ) );
+			let (reduced, _) = load_reduced( &data, JpegDecode::Thumbnail, None ).unwrap();
+			assert_ne!( (reduced.width(), reduced.height()), (width, height), "Thumbnail of the wrong shape or size ignored" );
+		}
+	}
+}
@@ -16,25 +16,46 @@ extern crate clap;
 extern crate walkdir;
 extern crate indicatif;
 
-use std::path::Path;
-use std::ffi::OsStr;
+use std::path::{Path, PathBuf};
 use std::io::{self, BufRead};
-use clap::Parser;
-use std::collections::HashSet;
+use clap::{Parser, Subcommand};
 use std::collections::HashMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::ops::Range;
 use walkdir::{DirEntry, WalkDir};
-use std::sync::mpsc::channel;
-use threadpool::ThreadPool;
 use indicatif::ProgressBar;
+use rayon::prelude::*;
 
 mod imagehash;
 mod image_error;
+mod metadata;
+mod sidecar;
+mod similar;
+mod calibrate;
+mod benchmark;
+mod raw;
+mod formats;
+mod archive;
+mod frames;
+mod video;
+mod colour;
+mod jpeg;
+mod memory;
+mod distance;
+mod paths;
+mod index;
+mod watch;
 
 /// PhotoDedupe: A utility for detecting duplicate photos in a collection of images
 #[derive(Parser, Debug)]
-#[command(version=env!("CARGO_PKG_VERSION"))]
+#[command(version=env!("CARGO_PKG_VERSION"), args_conflicts_with_subcommands = true)]
+#[command(group = clap::ArgGroup::new("list_mode").args(["duplicates", "uniques", "all"]))]
+#[command(group = clap::ArgGroup::new("collections").args(["compare_dir", "collection"]).multiple(true))]
 struct Args {
     
+    #[command(subcommand)]
+    command: Option<Command>,
+    
     /// List only the detected duplicate images. Excludes the highest resolution version of each image. Excludes unique images.
     #[arg(short, long,  required = false, conflicts_with_all = &["uniques", "all"]) ]
     duplicates: bool,
@@ -47,40 +68,345 @@ struct Args {
     #[arg(short, long, required = false, conflicts_with_all = &["uniques", "duplicates"]) ]
     all: bool,
     
-    /// Compares a directory of new images (supplied as the parameter to --compare) with one or more directories comprising an existing image collection (supplied as arguments). Tests whether each of the new images are duplicates of the existing image collection or unique depending on use of either the --duplicates or --uniques options respectively. When used with --duplicates, new images are classified as unique when of higher resolution than the version in the existing image collection. To mark similar images as duplicates in all circumstances (irrespective of resolution), additionally apply the --ignore-resolution option.
+    /// Print paths with escapes for bytes that aren't valid UTF-8 (as \xNN), control characters such as newlines (as \n, \t, \r or \u{NN}) and backslashes (as \\). By default paths are printed as their exact bytes, so that the output can be passed to other programs even where file names aren't UTF-8.
+    #[arg(long, required = false) ]
+    escape_paths: bool,
+    
+    /// Read the list of files and directories from stdin separated by NUL characters rather than newlines, as output by find -print0. Allows file names that contain newlines.
+    #[arg(short = '0', long = "null", required = false) ]
+    null_input: bool,
+    
+    /// End each path output with a NUL character rather than a newline, for use with xargs -0. Requires --duplicates, --uniques or --all.
+    #[arg(long, required = false, requires = "list_mode") ]
+    print0: bool,
+    
+    /// Compares a directory of new images (supplied as the parameter to --compare) with one or more directories comprising an existing image collection (supplied as arguments). Tests whether each of the new images are duplicates of the existing image collection or unique depending on use of either the --duplicates or --uniques options respectively. When used with --duplicates, new images are classified as unique when of higher resolution than the version in the existing image collection. To mark similar images as duplicates in all circumstances (irrespective of resolution), additionally apply the --ignore-resolution option. Repeat the option to compare several directories of new images at once.
     #[arg(short, long="compare", required = false, value_name="directory of new images")]
-    compare_dir: Option<String>,
+    compare_dir: Vec<PathBuf>,
+    
+    /// A named collection of images e.g. --collection archive=/nas/photos. Repeat the option to give more collections, or more directories of the same collection. Collections are given in order of priority, where the first is the existing collection that the others are compared with. As with --compare, images are only listed if they are in a collection other than the first, and an image in a lower priority collection is listed as the duplicate of one in a higher priority collection unless it is of higher resolution. Files and directories given as arguments are a collection with a higher priority than all of the named collections.
+    #[arg(long, required = false, value_name="name=path")]
+    collection: Vec<String>,
     
-    /// When using --compare always mark duplicates even the new image is better quality. Do not mark as unique even if better quality.
-    #[arg(long = "ignore-resolution", required = false, requires="compare_dir" ) ]
+    /// When using --compare or --collection always mark duplicates even the new image is better quality. Do not mark as unique even if better quality.
+    #[arg(long = "ignore-resolution", required = false, requires="collections" ) ]
     always_mark_duplicates: bool,
     
+    /// Compare the new images given with --compare against an index of the existing collection made with the index build command, instead of reading the existing collection. Files and directories given as arguments are also compared.
+    #[arg(long, required = false, requires = "compare_dir", value_name="index file") ]
+    against: Option<PathBuf>,
+    
+    /// Add the new images that are listed as unique (those not already in the collection, or better versions of images in the collection) to the index given with --against.
+    #[arg(long, required = false, requires = "against") ]
+    update_index: bool,
+    
     /// Ignore all images of less than the specified resolution e.g. --min-resolution 300x200 will ignore images if either the width is less than 300 pixels or the height is less than 200 pixels.
     #[arg(long="min-resolution", required=false, value_name="WidthxHeight") ]
     ignore_low_res: Option<String>,
     
-    /// Tests every file to see if it might be an image regardless of file extension. Also allows image files with no extension. The default behaviour is to only test files with common image filename extensions which are jpg,jpeg,png,tif,tiff,gif and webp.
+    /// Tests every file to see if it might be an image regardless of file extension, by checking whether the start of the file identifies a known image format. Also allows image files with no extension. The default behaviour is to only test files with common image filename extensions which are jpg,jpeg,png,tif,tiff,gif,webp,bmp,qoi,pbm,pgm,ppm,pnm,pam,tga and jxl plus the camera RAW formats cr2,nef,nrw,arw,srf,sr2,dng,raf and rw2.
     #[arg(short = 'y', long, required=false) ]
     any_file: bool,
     
+    /// Additional file extensions to test as images, as a comma separated list or by repeating the option e.g. --ext jfif,jpe
+    #[arg(long, required=false, value_delimiter = ',', value_name="extensions") ]
+    ext: Vec<String>,
+    
+    /// File extensions to never test, even if they are image file extensions, as a comma separated list or by repeating the option e.g. --exclude-ext gif,tga
+    #[arg(long, required=false, value_delimiter = ',', value_name="extensions") ]
+    exclude_ext: Vec<String>,
+    
+    /// Skip files and directories with paths matching a glob pattern, e.g. --exclude '**/@eaDir/**' skips Synology thumbnail directories. Repeat the option to give more than one pattern. "**" matches any number of directories and "*" matches within a file or directory name.
+    #[arg(long, required=false, value_name="pattern") ]
+    exclude: Vec<String>,
+    
+    /// Follow symbolic links to files and directories when searching directories. Links that loop back to a directory that contains them are reported and skipped.
+    #[arg(short = 'L', long, required = false) ]
+    follow_symlinks: bool,
+    
+    /// Include hidden files and directories (names starting with a dot), which are skipped by default when searching directories.
+    #[arg(long, required = false) ]
+    hidden: bool,
+    
+    /// Maximum depth of directories to search, where 1 only inspects the files directly inside each directory given (Default: no limit)
+    #[arg(long, required=false, value_name="depth") ]
+    max_depth: Option<usize>,
+    
+    /// Don't search directories on a different filesystem (e.g. a mounted network drive or external disk) than the directory given.
+    #[arg(long, required = false) ]
+    one_file_system: bool,
+    
+    /// Also look for images inside ZIP and TAR archives (.zip, .tar, .tar.gz and .tgz). Images inside an archive are listed as the path of the archive followed by the path inside the archive e.g. photos.zip!/2019/IMG_1234.jpg. Archives inside archives are not searched.
+    #[arg(long, required = false) ]
+    archives: bool,
+    
+    /// Which frames of animated GIF, PNG and WebP images and pages of multi-page TIFFs to compare. "first" compares only the first frame, so an animation is a duplicate of a still of its first frame. "all" compares every frame (up to 1000, with a warning for longer animations), so files are only duplicates if they have the same number of frames and every frame matches. "sample" compares 5 frames spread evenly through each animation, which is quicker to compare and tolerates dropped frames (Default:first)
+    #[arg(long, required=false, value_name="first|all|sample") ]
+    frames: Option<String>,
+    
+    /// Also compare videos (.mp4, .mov and .m4v) such as Live Photos and short clips. Frames are sampled from each video, so that stills which are frames of a video and duplicate copies of a video are found. Requires ffmpeg to be installed.
+    #[arg(long, required = false) ]
+    videos: bool,
+    
+    /// Colour that transparent areas of images (e.g. PNG and WebP with alpha) are placed onto before comparing, either white, black or a hex colour e.g. ff8000. Images that both have transparency must also have similar transparent areas to be duplicates (Default:white)
+    #[arg(long, required=false, value_name="colour") ]
+    alpha_background: Option<String>,
+    
+    /// Compare JPEGs using the thumbnail embedded in their EXIF data where it is large enough and the same shape as the image, which is faster than decoding the image. A thumbnail that wasn't updated when the image was edited may cause false matches.
+    #[arg(long, required = false) ]
+    exif_thumbnails: bool,
+    
+    /// Path to the ffmpeg binary used to read videos, if it is not on the PATH
+    #[arg(long, required = false, requires = "videos", value_name="path") ]
+    ffmpeg: Option<String>,
+    
     /// Only use the colour difference algorithm. This is more accurate but does not perform well with large numbers of images. This algorithm is used by default with 50,000 or fewer images.
     #[arg(long, required = false) ]
     force_colour_diff_only: bool,
     
-    /// Number of CPU threads to use (default is 4). Higher number improves performance if more than 4 CPU threads are available.
+    /// Number of CPU threads to use for hashing and comparing images (default is the number of CPU cores available).
     #[arg(short = 't', long = "threads", required=false, value_name="number of threads") ]
     num_threads: Option<u32>,
     
+    /// Maximum memory used by images being decoded at once, e.g. 512M or 4G (a number alone is in megabytes). The size of each image is estimated from its header, and threads wait to start decoding large images until there is room. Images that need more than the limit on their own are skipped with a warning. (Default: no limit)
+    #[arg(long, required=false, value_name="size") ]
+    memory_limit: Option<String>,
+    
     /// Colour difference threshold. Higher value means more likely to consider images duplicates (Min:0,Max:49000,Default:256)
     #[arg(long, required=false, name="colour-diff-threshold", value_name="threshold" ) ]
     colour_diff_threshold: Option<u32>,
     
+    /// Colour variation threshold. Images with less variation than this (e.g. very dark images) are never considered duplicates. Lower value means more likely to consider images duplicates (Min:0,Max:128,Default:3)
+    #[arg(long, required=false, value_name="threshold" ) ]
+    std_dev_threshold: Option<f32>,
+    
+    /// Find metadata sidecar files belonging to each image (e.g. Lightroom .xmp, Apple .aae, camera .thm and Google Takeout .json files) and list them with the image in the output, so that they can be moved or deleted together.
+    #[arg(long, required = false) ]
+    sidecars: bool,
+    
+    /// Where a camera RAW file (e.g. .cr2, .nef, .arw, .dng, .raf) is a duplicate of other images such as JPEG exports, list the RAW file as the best version regardless of resolution.
+    #[arg(long, required = false) ]
+    prefer_raw: bool,
+    
+    /// Where duplicates are found, write any metadata (e.g. EXIF capture date, GPS location, captions) held by the duplicates but missing from the best (highest resolution) version into an XMP sidecar file next to the best version. Existing sidecar files are never overwritten.
+    #[arg(long, required = false) ]
+    merge_metadata: bool,
+    
+    /// In addition to duplicates, find sets of similar shots such as bursts or near-identical frames of the same scene. Each set is listed separately from the duplicates and ranked by sharpness and exposure, best shot first.
+    #[arg(long, required = false, conflicts_with_all = &["uniques", "duplicates", "all"]) ]
+    similar: bool,
+    
+    /// Colour difference threshold for similar shots. Higher value means more likely to consider images similar (Min:0,Max:49000,Default:2048)
+    #[arg(long, required=false, requires="similar", value_name="threshold" ) ]
+    similar_colour_threshold: Option<u32>,
+    
+    /// Maximum number of bits that the perceptual hashes of similar shots may differ by (Min:0,Max:64,Default:8)
+    #[arg(long, required=false, requires="similar", value_name="bits" ) ]
+    similar_hash_distance: Option<u32>,
+    
+    /// Only consider shots similar if they were captured within the specified number of seconds of each other according to the EXIF capture time. Images without a capture time are never considered similar when this option is used.
+    #[arg(long, required=false, requires="similar", value_name="seconds" ) ]
+    time_window: Option<u64>,
+    
     /// Expects either one or two image file arguments. Where one file is supplied, prints statistics about the file. Where two are supplied prints statistics and information about the differences found between the files.
-    #[arg(short = 'g', long, required = false, conflicts_with_all = &["uniques", "duplicates", "all", "compare_dir"]) ]
+    #[arg(short = 'g', long, required = false, conflicts_with_all = &["uniques", "duplicates", "all", "compare_dir", "collection", "similar"]) ]
     debug: bool,
     
     #[arg(name = "Files/Directories", required = false)]
-    dir_or_file: Option<Vec<String>>
+    dir_or_file: Option<Vec<PathBuf>>
+}
+
+/// Modes of operation other than de-duplication
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Recommend threshold settings by testing a range of settings against images where the duplicates are already known. Images are labelled by file name where the best version is named <name>_best.<ext> and the duplicates <name>_duplicate_<n>.<ext> (as in unit_test_images). Alternatively supply a CSV file of labelled pairs using --pairs. Prints the precision, recall and F1 score of each setting.
+    Calibrate(CalibrateArgs),
+    /// Measure how robust duplicate detection is by generating transformed variants of the source images (rescaled, recompressed, converted to other formats, cropped, rotated, brightened and watermarked) and reporting the proportion of each type of variant detected as a duplicate of its source.
+    Benchmark(BenchmarkArgs),
+    /// Save the hashes of an image collection to an index file, so that new images can be compared with the collection using --compare and --against without reading every image in the collection again.
+    #[command(subcommand)]
+    Index(IndexCommand),
+    /// Watch an inbox directory and report whether each new image is a duplicate of the collection once the file has stopped growing. The collection is hashed once and kept in memory, and new unique images are added to it. Runs until interrupted.
+    Watch(WatchArgs),
+}
+
+/// Subcommands that create and maintain index files
+#[derive(Subcommand, Debug)]
+enum IndexCommand {
+    /// Hash the images in the files and directories given and write them to a new index file, replacing any existing index.
+    Build(IndexBuildArgs),
+    /// Bring an index up to date with the files and directories it was built from, only hashing images that are new or have been modified.
+    Update(IndexUpdateArgs),
+}
+
+/// Options for the index build subcommand
+#[derive(clap::Args, Debug)]
+struct IndexBuildArgs {
+    
+    /// Index file to write
+    #[arg(short, long, required = true, value_name="index file") ]
+    output: PathBuf,
+    
+    /// Number of CPU threads to use (default is the number of CPU cores available).
+    #[arg(short = 't', long = "threads", required=false, value_name="number of threads") ]
+    num_threads: Option<u32>,
+    
+    /// Tests every file to see if it might be an image regardless of file extension, as for the main command.
+    #[arg(short = 'y', long, required=false) ]
+    any_file: bool,
+    
+    /// Which frames of animations to compare, first, all or sample. Images compared with the index must use the same setting. (Default:first)
+    #[arg(long, required=false, value_name="first|all|sample") ]
+    frames: Option<String>,
+    
+    /// Colour that transparent areas of images are placed onto, either white, black or a hex colour. Images compared with the index must use the same setting. (Default:white)
+    #[arg(long, required=false, value_name="colour") ]
+    alpha_background: Option<String>,
+    
+    /// Hash JPEGs from the thumbnail in their EXIF data where it is large enough, as for the main command. Images compared with the index must use the same setting.
+    #[arg(long, required=false) ]
+    exif_thumbnails: bool,
+    
+    /// Skip files and directories with paths matching a glob pattern, as for the main command. Repeat the option to give more than one pattern. Updates skip the same files.
+    #[arg(long, required=false, value_name="pattern") ]
+    exclude: Vec<String>,
+    
+    /// Follow symbolic links to files and directories when searching directories, as for the main command. Updates follow the same links.
+    #[arg(short = 'L', long, required = false) ]
+    follow_symlinks: bool,
+    
+    /// Include hidden files and directories (names starting with a dot), as for the main command. Updates search the same files.
+    #[arg(long, required = false) ]
+    hidden: bool,
+    
+    /// Maximum depth of directories to search, as for the main command. Updates search to the same depth. (Default: no limit)
+    #[arg(long, required=false, value_name="depth") ]
+    max_depth: Option<usize>,
+    
+    /// Also index images inside ZIP and TAR archives, as for the main command. Updates search the same archives.
+    #[arg(long, required = false) ]
+    archives: bool,
+    
+    /// Also index videos (.mp4, .mov and .m4v), as for the main command. Requires ffmpeg to be installed. Updates index the same videos.
+    #[arg(long, required = false) ]
+    videos: bool,
+    
+    /// Maximum memory used by images being decoded at once, e.g. 512M or 4G (a number alone is in megabytes), as for the main command. Updates use the same limit. (Default: no limit)
+    #[arg(long, required=false, value_name="size") ]
+    memory_limit: Option<String>,
+    
+    #[arg(name = "Files/Directories", required = true)]
+    dir_or_file: Vec<PathBuf>
+}
+
+/// Options for the index update subcommand
+#[derive(clap::Args, Debug)]
+struct IndexUpdateArgs {
+    
+    /// Number of CPU threads to use (default is the number of CPU cores available).
+    #[arg(short = 't', long = "threads", required=false, value_name="number of threads") ]
+    num_threads: Option<u32>,
+    
+    /// Also check the images in directories that haven't changed, to find images that have been overwritten in place. Slower as every image file is checked.
+    #[arg(long, required=false) ]
+    check_files: bool,
+    
+    /// Index file to update
+    #[arg(name = "index file", required = true)]
+    index: PathBuf
+}
+
+/// Options for the watch subcommand
+#[derive(clap::Args, Debug)]
+struct WatchArgs {
+    
+    /// The existing collection, either a directory of images or an index file made with index build. Repeat the option to give more directories or index files.
+    #[arg(long, required = true, value_name="collection") ]
+    against: Vec<PathBuf>,
+    
+    /// Print a JSON object on one line for each new image, with the path, the status (duplicate, unique or error) and the image in the collection it is a duplicate of.
+    #[arg(long, required=false) ]
+    json: bool,
+    
+    /// Move new images that are duplicates of the collection into this directory, which is created if it doesn't exist.
+    #[arg(long, required=false, value_name="directory") ]
+    quarantine: Option<PathBuf>,
+    
+    /// Number of milliseconds the size of a new file must stay the same before it is read, so that files still being written aren't read. (Default:1000)
+    #[arg(long, required=false, default_value_t = 1000, value_name="milliseconds") ]
+    settle_time: u64,
+    
+    /// Report new images as duplicates even if they are better quality than the version in the collection.
+    #[arg(long = "ignore-resolution", required = false) ]
+    always_mark_duplicates: bool,
+    
+    /// Escape file names that aren't valid UTF-8 or contain control characters, as for the main command.
+    #[arg(long, required=false) ]
+    escape_paths: bool,
+    
+    /// Maximum memory used by new images being decoded at once, e.g. 512M or 4G (a number alone is in megabytes), as for the main command. Images that need more than the limit on their own are reported as errors. (Default: no limit)
+    #[arg(long, required=false, value_name="size") ]
+    memory_limit: Option<String>,
+    
+    /// Compare JPEGs using the thumbnail embedded in their EXIF data where it is large enough, as for the main command.
+    #[arg(long, required=false) ]
+    exif_thumbnails: bool,
+    
+    /// Number of CPU threads to use (default is the number of CPU cores available).
+    #[arg(short = 't', long = "threads", required=false, value_name="number of threads") ]
+    num_threads: Option<u32>,
+    
+    /// Tests every file to see if it might be an image regardless of file extension, as for the main command.
+    #[arg(short = 'y', long, required=false) ]
+    any_file: bool,
+    
+    /// Which frames of animations to compare, first, all or sample. Must match the setting of any index file used. (Default:first)
+    #[arg(long, required=false, value_name="first|all|sample") ]
+    frames: Option<String>,
+    
+    /// Colour that transparent areas of images are placed onto, either white, black or a hex colour. Must match the setting of any index file used. (Default:white)
+    #[arg(long, required=false, value_name="colour") ]
+    alpha_background: Option<String>,
+    
+    /// Directory that new images arrive in
+    #[arg(name = "inbox directory", required = true)]
+    inbox: PathBuf
+}
+
+/// Options for the calibrate subcommand
+#[derive(clap::Args, Debug)]
+struct CalibrateArgs {
+    
+    /// CSV file of labelled pairs of images with lines of the form: path_a,path_b,label. The label is 1 where the images are duplicates and 0 otherwise. Paths containing commas are enclosed in double quotes. Relative paths are relative to the CSV file.
+    #[arg(long, required = false, value_name="pairs.csv") ]
+    pairs: Option<PathBuf>,
+    
+    /// Number of CPU threads to use (default is the number of CPU cores available).
+    #[arg(short = 't', long = "threads", required=false, value_name="number of threads") ]
+    num_threads: Option<u32>,
+    
+    #[arg(name = "Files/Directories", required_unless_present = "pairs", conflicts_with = "pairs")]
+    dir_or_file: Option<Vec<PathBuf>>
+}
+
+/// Options for the benchmark subcommand
+#[derive(clap::Args, Debug)]
+struct BenchmarkArgs {
+    
+    /// Number of CPU threads to use (default is the number of CPU cores available).
+    #[arg(short = 't', long = "threads", required=false, value_name="number of threads") ]
+    num_threads: Option<u32>,
+    
+    /// Colour difference threshold to benchmark (Min:0,Max:49000,Default:256)
+    #[arg(long, required=false, name="colour-diff-threshold", value_name="threshold" ) ]
+    colour_diff_threshold: Option<u32>,
+    
+    /// Colour variation threshold to benchmark (Min:0,Max:128,Default:3)
+    #[arg(long, required=false, value_name="threshold" ) ]
+    std_dev_threshold: Option<f32>,
+    
+    #[arg(name = "Source Files/Directories", required = true)]
+    dir_or_file: Vec<PathBuf>
 }
 
 fn main() {
@@ -88,28 +414,94 @@ fn main() {
 	//Process command line arguments
 	let matches = Args::parse();
 	
+	//Subcommands have their own options
+	if let Some(ref command) = matches.command {
+		match command {
+			Command::Calibrate(calibrate_args) => calibrate_mode( calibrate_args ),
+			Command::Benchmark(benchmark_args) => benchmark_mode( benchmark_args ),
+			Command::Index(IndexCommand::Build(build_args)) => index_build_mode( build_args ),
+			Command::Index(IndexCommand::Update(update_args)) => index_update_mode( update_args ),
+			Command::Watch(watch_args) => watch_mode( watch_args ),
+		}
+		return;
+	}
+	
 	//Set the configuration options based on the command line
 	match set_config_options( &matches ) {
-		Ok(config) => {
+		Ok(mut config) => {
 			if !matches.debug {
 				//Gather the list of files to inspect
-				match collate_file_list_any_source( &matches, &config ) {
-					Some(mut dedup_file_list) => {
+				match collate_file_list_any_source( &matches ) {
+					Some(main_paths) => {
+						
+						//The images in the index are part of the main collection
+						let mut index = None;
+						if let Some(ref index_path) = config.against_index {
+							match index::Index::read( index_path, &config ).and_then( |i| i.check_compatible( &config ).map( |_| i ) ) {
+								Ok(i) => index = Some(i),
+								Err(e) => {
+									eprintln!("{}", e);
+									return;
+								}
+							}
+						}
+						
+						//The files given as arguments (or on stdin) are the main collection, with the highest priority
+						if !main_paths.is_empty() || index.is_some() {
+							config.collections.insert( 0, imagehash::Collection { name: String::new(), paths: main_paths } );
+						}
+						config.am_comparing = config.collections.len() > 1;
 						
-						//Add in the images from the comparison directory
-						if config.am_comparing {
-							let mut path_list  : Vec<String> = Vec::new();
-							path_list.push(config.compare_dir.clone());
-							let compare_flist = gather_file_list( &path_list, &config, true );
-							dedup_file_list.extend( compare_flist );
+						let mut dedup_file_list = Vec::new();
+						for (priority, collection) in config.collections.iter().enumerate() {
+							dedup_file_list.extend( gather_file_list( &collection.paths, &config, priority as u32 ) );
 						}
+						//A file may be in more than one collection e.g. through a symbolic link
+						dedup_file_list = group_same_files( dedup_file_list );
 
-						//Calculate an image hash for each image and image statistics
-						let results = run_image_hashing( dedup_file_list, &config );
+						//Deduplication is a two step process:
+						//In step one we gather statistics about the image files
+						//In step two we then perform comparisons of the image statistics
+						let mut image_hash_results = hash_images( dedup_file_list, &config );
+						if let Some(ref index) = index {
+							image_hash_results.extend( index.entries.iter().map( |e| e.image.clone() ) );
+						}
+						let results = find_duplicates( image_hash_results, &config );
+						
+						//The new images that are listed as unique are added to the index after the results are written
+						let mut accepted = Vec::new();
+						if config.update_index {
+							accepted = duplicate_groups( &results, &config ).iter().map( |g| &results[g.start] ).filter( |i| i.image_path.collection > 0 ).cloned().collect();
+						}
 						
 						if results.len() > 0 {
+							//Preserve metadata from the duplicates before they are potentially removed
+							if config.merge_metadata {
+								merge_duplicate_metadata( &results, &config );
+							}
+							
+							let mut similar_sets = Vec::new();
+							if config.find_similar {
+								similar_sets = find_similar_shots( &results, &config );
+							}
+							
 							//Write out the list of duplicates per command line options
 							output_results( results, &config );
+							
+							if config.find_similar {
+								output_similar_shots( &similar_sets, &config );
+							}
+						}
+						
+						if let (Some(mut index), Some(index_path)) = (index, config.against_index.as_ref()) {
+							if config.update_index {
+								let num_accepted = accepted.len();
+								index.add( accepted.into_iter().map( |mut i| { i.image_path.collection = 0; i.image_path.always_mark_dupe_compare = false; i } ).collect() );
+								match index.write( index_path ) {
+									Ok(()) => eprintln!("Added {} images to index: {}", num_accepted, index_path.display()),
+									Err(e) => eprintln!("{}", e),
+								}
+							}
 						}
 						
 					},
@@ -134,18 +526,23 @@ fn debug_mode( matches: &Args, config : &imagehash::ConfigOptions ) {
 				if paths.len() < 1 || paths.len() > 2 {
 					eprintln!("Error: Debug mode requires either exactly 1 or 2 paths to images.");
 				}else{
-					match imagehash::ImageHashAV::new( &imagehash::ImagePath{ fpath: paths.first().unwrap().to_string(), is_compare_dir: false, always_mark_dupe_compare: false }, config.min_width, config.min_height  )	{
+					match imagehash::ImageHashAV::new_with_frames( &imagehash::ImagePath{ fpath: paths.first().unwrap().clone(), collection: 0, always_mark_dupe_compare: false, alpha_background: Some(config.alpha_background), jpeg_decode: config.jpeg_decode, memory_limit: config.memory_limit, ..Default::default() }, config.min_width, config.min_height, config.frame_policy  )	{
 						Ok(a) => {
 							eprintln!("Pixel std_dev First:  {} ", a.std_dev );
 							eprintln!("Grey Hash First:  {:x} ", a.grey_hash);
+							eprintln!("Frames compared First:  {} ", a.frames.len() + 1 );
+							eprintln!("Transparency First:  {:.1}% (significant: {})", a.transparency * 100.0, a.alpha_mask.is_some() );
 							
 								
 							if paths.len() > 1 {		
-								match imagehash::ImageHashAV::new( &imagehash::ImagePath{ fpath: paths.get(1).unwrap().to_string(), is_compare_dir: false, always_mark_dupe_compare: false }, config.min_width, config.min_height ) {
+								match imagehash::ImageHashAV::new_with_frames( &imagehash::ImagePath{ fpath: paths.get(1).unwrap().clone(), collection: 0, always_mark_dupe_compare: false, alpha_background: Some(config.alpha_background), jpeg_decode: config.jpeg_decode, memory_limit: config.memory_limit, ..Default::default() }, config.min_width, config.min_height, config.frame_policy ) {
 									Ok(b) => {
 										eprintln!("Grey Hash Second: {:x} ", b.grey_hash);
 										eprintln!("Are grey hashes identical?: {}", (b.grey_hash == a.grey_hash) );
 										eprintln!("Pixel std_dev Second: {} ", b.std_dev );
+										eprintln!("Frames compared Second:  {} ", b.frames.len() + 1 );
+										eprintln!("Transparency Second:  {:.1}% (significant: {})", b.transparency * 100.0, b.alpha_mask.is_some() );
+										eprintln!("Are alpha masks similar?: {} ", a.has_similar_alpha_mask( &b, config ));
 										eprintln!("Pixel colour difference: {} ", a.diff_colour( &b ));
 										eprintln!("Are apect ratios similar?: {:?} ", a.has_similar_aspect_ratio( &b ));
 										eprintln!("Are both images duplicates?:  {} ", b.is_dupe(&a, &config) );
@@ -170,6 +567,254 @@ fn debug_mode( matches: &Args, config : &imagehash::ConfigOptions ) {
 		  
 }
 
+/// Sweep the threshold settings against a labelled set of images and recommend the best settings
+fn calibrate_mode( args: &CalibrateArgs ) {
+	
+	let mut config = get_default_config_options();
+	if let Err(e) = init_threads( &mut config, args.num_threads ) {
+		eprintln!("{}", e);
+		return;
+	}
+	
+	let (images, pairs) = match (&args.pairs, &args.dir_or_file) {
+		(Some(csv_path), _) => {
+			match calibrate::pairs_from_csv( csv_path ) {
+				Ok((paths, path_pairs)) => {
+					let image_paths = paths.into_iter().map( |p| imagehash::ImagePath { fpath: p, ..Default::default() } ).collect();
+					let images = hash_images( image_paths, &config );
+					let pairs = calibrate::Pairs::Listed( calibrate::index_pairs( &images, &path_pairs ) );
+					(images, pairs)
+				},
+				Err(e) => {
+					eprintln!("{}", e);
+					return;
+				}
+			}
+		},
+		(None, Some(paths)) => {
+			let mut images = hash_images( gather_file_list( paths, &config, 0 ), &config );
+			//Sort so that the output doesn't depend on the order the threads finished
+			images.sort_by( |a, b| a.image_path.fpath.cmp( &b.image_path.fpath ) );
+			let image_paths : Vec<PathBuf> = images.iter().map( |i| i.image_path.fpath.clone() ).collect();
+			let pairs = calibrate::Pairs::from_file_names( &image_paths );
+			(images, pairs)
+		},
+		(None, None) => return,	//Prevented by clap
+	};
+	
+	let (num_pairs, num_duplicate_pairs) = pairs.count();
+	if num_duplicate_pairs == 0 {
+		eprintln!("Error: No labelled duplicates found. Name the images <name>_best.<ext> and <name>_duplicate_<n>.<ext> or supply --pairs.");
+		return;
+	}
+	
+	let points = calibrate::sweep( &images, &pairs );
+	calibrate::print_report( &points, &config, num_duplicate_pairs, num_pairs );
+}
+
+/// Generate transformed variants of the source images and report how many of each are detected as duplicates
+fn benchmark_mode( args: &BenchmarkArgs ) {
+	
+	let mut config = get_default_config_options();
+	if let Err(e) = init_threads( &mut config, args.num_threads ) {
+		eprintln!("{}", e);
+		return;
+	}
+	if let Some(colour_diff_threshold) = args.colour_diff_threshold {
+		if colour_diff_threshold > 49000 {
+			eprintln!("colour_diff_threshold must be between 0 - 49000 inclusive.");
+			return;
+		}
+		config.colour_difference_threshold = colour_diff_threshold as u64;
+	}
+	if let Some(std_dev_threshold) = args.std_dev_threshold {
+		if !(0.0..=128.0).contains( &std_dev_threshold ) {
+			eprintln!("std-dev-threshold must be between 0 - 128 inclusive.");
+			return;
+		}
+		config.std_dev_threshold = std_dev_threshold;
+	}
+	
+	let sources : Vec<PathBuf> = gather_file_list( &args.dir_or_file, &config, 0 ).into_iter().map( |p| p.fpath ).collect();
+	if sources.is_empty() {
+		eprintln!("Error: Didn't find any image files to test");
+		return;
+	}
+	
+	let result = benchmark::run_benchmark( sources.clone(), &benchmark::default_transforms(), &config );
+	benchmark::print_report( &result );
+	benchmark::print_decode_report( &benchmark::benchmark_decoding( &sources, &config ) );
+}
+
+/// Configuration for subcommands that hash images, with the options that change how images are hashed. Initialises the thread pool.
+fn hashing_config_options( num_threads: Option<u32>, any_file: bool, frames: &Option<String>, alpha_background: &Option<String> ) -> Result<imagehash::ConfigOptions, String> {
+	
+	let mut config = get_default_config_options();
+	init_threads( &mut config, num_threads )?;
+	if any_file {
+		config.only_known_file_extensions = false;
+	}
+	if let Some(policy_name) = frames {
+		match frames::FramePolicy::from_name( policy_name ) {
+			Some(frame_policy) => config.frame_policy = frame_policy,
+			None => return Err("Parameter passed to --frames option should be one of first, all or sample.".to_string()),
+		}
+	}
+	if let Some(colour) = alpha_background {
+		match parse_colour( colour ) {
+			Some(background) => config.alpha_background = background,
+			None => return Err("Parameter passed to --alpha-background option should be white, black or a hex colour e.g. ff8000.".to_string()),
+		}
+	}
+	Ok(config)
+}
+
+/// Set the maximum depth, exclude patterns and memory limit used when searching directories and hashing images
+fn search_config_options( config: &mut imagehash::ConfigOptions, max_depth: Option<usize>, exclude: &[String], memory_limit: &Option<String> ) -> Result<(), String> {
+	if let Some(max_depth) = max_depth {
+		if max_depth < 1 {
+			return Err("max-depth must be greater than 0".to_string());
+		}
+		config.max_depth = Some(max_depth);
+	}
+	for pattern in exclude {
+		match glob::Pattern::new( pattern ) {
+			Ok(pattern) => config.exclude_patterns.push( pattern ),
+			Err(e) => return Err(format!("Parameter passed to --exclude option \"{}\" is not a valid glob pattern: {}", pattern, e)),
+		}
+	}
+	if let Some(size) = memory_limit {
+		match memory::parse_size( size ) {
+			Some(bytes) => config.memory_limit = Some(bytes),
+			None => return Err("Parameter passed to --memory-limit option should be a size greater than 0 e.g. 512M or 4G.".to_string()),
+		}
+	}
+	Ok(())
+}
+
+/// Hash the images in a collection and write them to an index file
+fn index_build_mode( args: &IndexBuildArgs ) {
+	
+	let mut config = match hashing_config_options( args.num_threads, args.any_file, &args.frames, &args.alpha_background ) {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("{}", e);
+			return;
+		}
+	};
+	if args.exif_thumbnails {
+		config.jpeg_decode = jpeg::JpegDecode::Thumbnail;
+	}
+	config.follow_symlinks = args.follow_symlinks;
+	config.include_hidden = args.hidden;
+	config.scan_archives = args.archives;
+	config.scan_videos = args.videos;
+	if let Err(e) = search_config_options( &mut config, args.max_depth, &args.exclude, &args.memory_limit ) {
+		eprintln!("{}", e);
+		return;
+	}
+	
+	//Building an index is updating an empty one
+	let mut index = index::Index::new( &args.dir_or_file, &config );
+	index.update( &config, false );
+	match index.write( &args.output ) {
+		Ok(()) => eprintln!("Indexed {} images into: {}", index.entries.len(), args.output.display()),
+		Err(e) => eprintln!("{}", e),
+	}
+}
+
+/// Watch an inbox directory and report whether each new image is a duplicate of the collection
+fn watch_mode( args: &WatchArgs ) {
+	
+	let mut config = match hashing_config_options( args.num_threads, args.any_file, &args.frames, &args.alpha_background ) {
+		Ok(config) => config,
+		Err(e) => {
+			eprintln!("{}", e);
+			return;
+		}
+	};
+	config.always_mark_duplicates = args.always_mark_duplicates;
+	config.escape_paths = args.escape_paths;
+	if args.exif_thumbnails {
+		config.jpeg_decode = jpeg::JpegDecode::Thumbnail;
+	}
+	if let Some(ref size) = args.memory_limit {
+		match memory::parse_size( size ) {
+			Some(bytes) => config.memory_limit = Some(bytes),
+			None => {
+				eprintln!("Parameter passed to --memory-limit option should be a size greater than 0 e.g. 512M or 4G.");
+				return;
+			}
+		}
+	}
+	
+	//Paths are made absolute so that files in the quarantine, which may be inside the inbox, are recognised
+	let inbox = std::path::absolute( &args.inbox ).unwrap_or_else( |_| args.inbox.clone() );
+	let quarantine = args.quarantine.as_ref().map( |q| std::path::absolute( q ).unwrap_or_else( |_| q.clone() ) );
+	if !inbox.is_dir() {
+		eprintln!("Error: Inbox is not a directory: {}", inbox.display());
+		return;
+	}
+	if let Some(ref quarantine) = quarantine {
+		if let Err(e) = std::fs::create_dir_all( quarantine ) {
+			eprintln!("Error: Failed to create quarantine directory {}: {}", quarantine.display(), e);
+			return;
+		}
+	}
+	
+	//The collection is given as index files or directories of images, which are hashed once
+	let mut images = Vec::new();
+	let mut collection_paths = Vec::new();
+	for path in &args.against {
+		if index::Index::is_index( path ) {
+			match index::Index::read( path, &config ).and_then( |i| i.check_compatible( &config ).map( |_| i ) ) {
+				Ok(index) => images.extend( index.entries.into_iter().map( |e| e.image ) ),
+				Err(e) => {
+					eprintln!("{}", e);
+					return;
+				}
+			}
+		}else{
+			collection_paths.push( path.clone() );
+		}
+	}
+	if !collection_paths.is_empty() {
+		images.extend( hash_images( gather_file_list( &collection_paths, &config, 0 ), &config ) );
+	}
+	
+	let signatures = watch::Signatures::new( images );
+	eprintln!("Watching {} for new images to compare with {} images", inbox.display(), signatures.len());
+	let options = watch::WatchOptions { json: args.json, quarantine, settle_time: std::time::Duration::from_millis( args.settle_time ) };
+	if let Err(e) = watch::watch( &inbox, signatures, &options, &config ) {
+		eprintln!("{}", e);
+	}
+}
+
+/// Update an index with the changes to the files and directories it was built from
+fn index_update_mode( args: &IndexUpdateArgs ) {
+	
+	let mut config = get_default_config_options();
+	if let Err(e) = init_threads( &mut config, args.num_threads ) {
+		eprintln!("{}", e);
+		return;
+	}
+	
+	let mut index = match index::Index::read( &args.index, &config ) {
+		Ok(index) => index,
+		Err(e) => {
+			eprintln!("{}", e);
+			return;
+		}
+	};
+	index.configure( &mut config );
+	
+	let counts = index.update( &config, args.check_files );
+	match index.write( &args.index ) {
+		Ok(()) => eprintln!("Added: {}, Removed: {}, Modified: {}, Moved: {}, Unchanged: {}", counts.added, counts.removed, counts.modified, counts.moved, counts.unchanged),
+		Err(e) => eprintln!("{}", e),
+	}
+}
+
 /// Returns a command line configuration options object with a set of reasonable defaults configured
 fn get_default_config_options() -> imagehash::ConfigOptions {
 	return imagehash::ConfigOptions { colour_difference_threshold: imagehash::ImageHashAV::DEFAULT_COLOUR_DIFF_THRESHOLD, 
@@ -177,18 +822,61 @@ fn get_default_config_options() -> imagehash::ConfigOptions {
 												alg_flip_threshold : imagehash::ImageHashAV::DEFAULT_ALG_FLIP_THRESHOLD,
 												alg_colour_diff_only : false,
 												only_known_file_extensions : true,
+												include_extensions : Vec::new(),
+												exclude_extensions : Vec::new(),
+												follow_symlinks : false,
+												include_hidden : false,
+												max_depth : None,
+												exclude_patterns : Vec::new(),
+												one_file_system : false,
+												scan_archives : false,
+												frame_policy : frames::FramePolicy::First,
+												scan_videos : false,
+												ffmpeg_path : None,
+												alpha_background : imagehash::ImageHashAV::DEFAULT_ALPHA_BACKGROUND,
+												jpeg_decode : jpeg::JpegDecode::Scaled,
 												only_list_duplicates : false,
 												only_list_uniques : false,
 												list_all : false,
-												num_threads : 4,
-												compare_dir : "".to_string(),
+												escape_paths : false,
+												path_terminator : b'\n',
+												num_threads : default_num_threads(),
+												memory_limit : None,
+												collections : Vec::new(),
 												am_comparing : false,
 												always_mark_duplicates : false,
+												against_index : None,
+												update_index : false,
 												min_width: 0,
-												min_height : 0,							
+												min_height : 0,
+												merge_metadata : false,
+												include_sidecars : false,
+												prefer_raw : false,
+												find_similar : false,
+												similar_colour_threshold : imagehash::ImageHashAV::DEFAULT_SIMILAR_COLOUR_DIFF_THRESHOLD,
+												similar_hash_distance : imagehash::ImageHashAV::DEFAULT_SIMILAR_HASH_DISTANCE,
+												similar_time_window : None,
 									};
 }
 
+/// Number of threads used by default, one per CPU core available to the process
+fn default_num_threads() -> u32 {
+	std::thread::available_parallelism().map( |n| n.get() as u32 ).unwrap_or(4)
+}
+
+/// Set the number of threads given with --threads and size the global thread pool that hashes and compares images to match.
+/// The pool can only be set up once, later calls have no effect on it.
+fn init_threads( config : &mut imagehash::ConfigOptions, num_threads : Option<u32> ) -> Result<(), String> {
+	if let Some(num_threads) = num_threads {
+		if num_threads < 1 {
+			return Err("Number of threads must be greater than 0".to_string());
+		}
+		config.num_threads = num_threads;
+	}
+	let _ = rayon::ThreadPoolBuilder::new().num_threads( config.num_threads as usize ).build_global();
+	Ok(())
+}
+
 /// Converts configuration options set on the command line with the Clap module into the internal configuration options object
 fn set_config_options( matches : &Args ) -> Result<imagehash::ConfigOptions,String> {
 	
@@ -197,22 +885,39 @@ fn set_config_options( matches : &Args ) -> Result<imagehash::ConfigOptions,Stri
 	config.only_list_duplicates = matches.duplicates;
 	config.only_list_uniques = matches.uniques;
 	config.list_all = matches.all;
+	config.escape_paths = matches.escape_paths;
+	if matches.print0 {
+		config.path_terminator = b'\0';
+	}
 	config.alg_colour_diff_only = matches.force_colour_diff_only;
 	config.always_mark_duplicates = matches.always_mark_duplicates;
+	config.against_index = matches.against.clone();
+	config.update_index = matches.update_index;
+	config.merge_metadata = matches.merge_metadata;
+	config.include_sidecars = matches.sidecars;
+	config.prefer_raw = matches.prefer_raw;
+	config.scan_archives = matches.archives;
+	config.scan_videos = matches.videos;
+	if matches.exif_thumbnails {
+		config.jpeg_decode = jpeg::JpegDecode::Thumbnail;
+	}
+	config.ffmpeg_path = matches.ffmpeg.clone();
+	config.find_similar = matches.similar;
+	config.similar_time_window = matches.time_window;
 	
 	if matches.any_file {
 		config.only_known_file_extensions = false;
 	}
+	
+	config.include_extensions = normalise_extensions( &matches.ext );
+	config.exclude_extensions = normalise_extensions( &matches.exclude_ext );
+	
+	config.follow_symlinks = matches.follow_symlinks;
+	config.include_hidden = matches.hidden;
+	config.one_file_system = matches.one_file_system;
+	search_config_options( &mut config, matches.max_depth, &matches.exclude, &matches.memory_limit )?;
 
-	match matches.num_threads {
-		Some(num_threads) => {
-			if num_threads < 1 {
-				return Err("Number of threads must be greater than 0".to_string());
-			}
-			
-			config.num_threads = num_threads;
-		}, None => {}
-	}
+	init_threads( &mut config, matches.num_threads )?;
 	
 	match matches.colour_diff_threshold {
 		Some(colour_diff_threshold) =>  {
@@ -222,18 +927,65 @@ fn set_config_options( matches : &Args ) -> Result<imagehash::ConfigOptions,Stri
 			config.colour_difference_threshold = colour_diff_threshold as u64;
 		}, None => {}
 	}
+	
+	if let Some(std_dev_threshold) = matches.std_dev_threshold {
+		if !(0.0..=128.0).contains( &std_dev_threshold ) {
+			return Err("std-dev-threshold must be between 0 - 128 inclusive.".to_string());
+		}
+		config.std_dev_threshold = std_dev_threshold;
+	}
+	
+	if let Some(similar_colour_threshold) = matches.similar_colour_threshold {
+		if similar_colour_threshold > 49000 {
+			return Err("similar-colour-threshold must be between 0 - 49000 inclusive.".to_string());
+		}
+		config.similar_colour_threshold = similar_colour_threshold as u64;
+	}
+	
+	if let Some(similar_hash_distance) = matches.similar_hash_distance {
+		if similar_hash_distance > 64 {
+			return Err("similar-hash-distance must be between 0 - 64 inclusive.".to_string());
+		}
+		config.similar_hash_distance = similar_hash_distance;
+	}
 
 
-	match &matches.compare_dir {
-		Some(ref c_dir) => {
-			let dir_test = Path::new(&c_dir);
-			if dir_test.is_dir() || dir_test.is_file() {
-				config.compare_dir = c_dir.to_string();
-				config.am_comparing  = true;
-			}else{
-				return Err(format!("Option to --compare \"{}\" is not a valid directory or file.", c_dir));
+	//Collections are in order of priority, followed by the new images to compare
+	for collection in &matches.collection {
+		let (name, c_path) = match collection.split_once( '=' ) {
+			Some((name, c_path)) if !name.is_empty() && !c_path.is_empty() => (name, PathBuf::from( c_path )),
+			_ => return Err(format!("Parameter passed to --collection option \"{}\" should be a name and a path e.g. archive=/nas/photos.", collection)),
+		};
+		if !( c_path.is_dir() || c_path.is_file() ) {
+			return Err(format!("Option to --collection \"{}\" is not a valid directory or file.", c_path.display()));
+		}
+		match config.collections.iter_mut().find( |c| c.name == name ) {
+			Some(existing) => existing.paths.push( c_path ),
+			None => config.collections.push( imagehash::Collection { name: name.to_string(), paths: vec![ c_path ] } ),
+		}
+	}
+	
+	if !matches.compare_dir.is_empty() {
+		for c_dir in &matches.compare_dir {
+			if !( c_dir.is_dir() || c_dir.is_file() ) {
+				return Err(format!("Option to --compare \"{}\" is not a valid directory or file.", c_dir.display()));
 			}
-		}, None => {}	//If the string is missing it should be caught by clap
+		}
+		config.collections.push( imagehash::Collection { name: String::new(), paths: matches.compare_dir.clone() } );
+	}
+	
+	if let Some(ref policy_name) = matches.frames {
+		match frames::FramePolicy::from_name( policy_name ) {
+			Some(frame_policy) => config.frame_policy = frame_policy,
+			None => return Err("Parameter passed to --frames option should be one of first, all or sample.".to_string()),
+		}
+	}
+	
+	if let Some(ref colour) = matches.alpha_background {
+		match parse_colour( colour ) {
+			Some(background) => config.alpha_background = background,
+			None => return Err("Parameter passed to --alpha-background option should be white, black or a hex colour e.g. ff8000.".to_string()),
+		}
 	}
 	
 	match &matches.ignore_low_res {
@@ -255,6 +1007,14 @@ fn set_config_options( matches : &Args ) -> Result<imagehash::ConfigOptions,Stri
 	
 }
 
+/// Convert file extensions from the command line to lower case without a leading dot
+fn normalise_extensions( extensions: &[String] ) -> Vec<String> {
+	extensions.iter()
+		.map( |e| e.trim().trim_start_matches('.').to_lowercase() )
+		.filter( |e| !e.is_empty() )
+		.collect()
+}
+
 /// Given a string of the format "widthxheight", extract the width and height as integers
 fn extract_width_and_height(s: &str) -> Option<(u32, u32)> {
     let parts: Vec<&str> = s.split('x').collect();
@@ -269,35 +1029,51 @@ fn extract_width_and_height(s: &str) -> Option<(u32, u32)> {
     }
 }
 
-/// Determines a list of image file paths that the utility is going to compare
-fn collate_file_list_any_source( matches: &Args, config: &imagehash::ConfigOptions ) -> Option<Vec<imagehash::ImagePath>> {
+/// Parse a colour given as white, black or a hex colour e.g. ff8000 or #ff8000
+fn parse_colour( s: &str ) -> Option<[u8;3]> {
+	match s.to_lowercase().as_str() {
+		"white" => Some([255, 255, 255]),
+		"black" => Some([0, 0, 0]),
+		hex => {
+			let hex = hex.trim_start_matches('#');
+			if hex.len() != 6 || !hex.is_ascii() {
+				return None;
+			}
+			let channel = |i: usize| u8::from_str_radix( &hex[i*2..i*2+2], 16 ).ok();
+			Some([ channel(0)?, channel(1)?, channel(2)? ])
+		}
+	}
+}
+
+/// Determines the list of files and directories of the main collection, from the command line or stdin. Stdin isn't read when the images are all in named collections or an index.
+fn collate_file_list_any_source( matches: &Args ) -> Option<Vec<PathBuf>> {
 	
 	match gather_file_list_from_cmd_line( &matches ) {
-		Some( st_files ) => {
-			return Some(gather_file_list( &st_files, &config, false ));
-		},
-		None => {
-			let st_files = gather_file_list_from_stdin( )?;
-			return Some(gather_file_list( &st_files, &config, false ));
-			
-		},
+		Some( st_files ) => Some(st_files),
+		None if !matches.collection.is_empty() || matches.against.is_some() => Some(Vec::new()),
+		None => gather_file_list_from_stdin( matches.null_input ),
 	}
 	
 }
 
-/// Gather a list of image file paths passed in on stdin
-fn gather_file_list_from_stdin( ) -> Option<Vec<String>> {
-	let mut path_list  : Vec<String> = Vec::new();
+/// Gather a list of image file paths passed in on stdin, one per line or separated by NUL characters
+fn gather_file_list_from_stdin( null_separated : bool ) -> Option<Vec<PathBuf>> {
+	read_path_list( io::stdin().lock(), null_separated )
+}
+
+/// Read a list of paths, one per line or separated by NUL characters
+fn read_path_list( reader : impl BufRead, null_separated : bool ) -> Option<Vec<PathBuf>> {
+	let mut path_list  : Vec<PathBuf> = Vec::new();
 	
-	let stdin = io::stdin();
-    	for line in stdin.lock().lines() {
+	//Read as bytes as paths aren't necessarily UTF-8
+	let separator = if null_separated { b'\0' } else { b'\n' };
+    	for line in reader.split(separator) {
 			match line {
 				Ok(line) => {
-					let line_trimmed  = line.trim();
+					//Also handles Windows style linefeeds. NUL separated paths are used exactly as given.
+					let line_trimmed  = if null_separated { line.as_slice() } else { line.trim_ascii() };
 					if !line_trimmed.is_empty() {
-						//Handle Windows style linefeeds
-						let crlf_cleaned_line = line_trimmed.trim_end_matches('\r');
-						path_list.push( crlf_cleaned_line.to_string() )
+						path_list.push( paths::path_from_bytes( line_trimmed.to_vec() ) )
 					}
 				},
 				Err(e) => {
@@ -316,14 +1092,14 @@ fn gather_file_list_from_stdin( ) -> Option<Vec<String>> {
 
 
 /// Read the command line arguments and generate a complete list of files to be traversed
-fn gather_file_list_from_cmd_line( matches: &Args ) -> Option<Vec<String>> {
-	let mut path_list  : Vec<String> = Vec::new();
+fn gather_file_list_from_cmd_line( matches: &Args ) -> Option<Vec<PathBuf>> {
+	let mut path_list  : Vec<PathBuf> = Vec::new();
 	
 	match &matches.dir_or_file {
 		
 		Some(ref paths) => {
 			for file_or_dir in paths {
-				path_list.push( file_or_dir.to_string() );
+				path_list.push( file_or_dir.clone() );
 			}
 			
 			if path_list.len() < 1 {
@@ -339,161 +1115,256 @@ fn gather_file_list_from_cmd_line( matches: &Args ) -> Option<Vec<String>> {
 	
 }
 
-/// Determines if a specific file path has one of an allowed list of image file extensions
+/// Determines if a file should be tested as an image. Files with the extension of a supported format or an extension added with --ext are tested, unless excluded with --exclude-ext.
+/// With --any-file, other files are tested if the start of the file identifies it as an image.
 fn valid_file_extension( fpath: &Path, config: &imagehash::ConfigOptions ) -> bool {
+	valid_file_extension_or_sniff( fpath, config, || formats::sniff_image( fpath ) )
+}
+
+/// As valid_file_extension, where the start of the file is tested by the function given e.g. for a file inside an archive, only called if needed
+fn valid_file_extension_or_sniff( fpath: &Path, config: &imagehash::ConfigOptions, sniff: impl FnOnce() -> bool ) -> bool {
 	
-   	//List of known image file extensions
-	let known_extensions: HashSet<&str> = [ "jpg", "jpeg", "png", "tif", "tiff", "gif", "webp" ].iter().cloned().collect();
+	let extension = fpath.extension().and_then( |e| e.to_str() ).map( |e| e.to_lowercase() );
 	
-	//If any-file is not set, only tests a limited list of file extensions
-	if config.only_known_file_extensions {
-		match fpath.extension().and_then(OsStr::to_str) {
-			Some(extension)=>{
-				let ext_lower = extension.to_lowercase();
-				if !known_extensions.contains(&ext_lower.as_str()) {
-					return false;
-				}
-			},
-			None => return false,
+	if let Some(ref extension) = extension {
+		if config.exclude_extensions.contains( extension ) {
+			return false;
+		}
+		if config.include_extensions.contains( extension ) {
+			return true;
 		}
 	}
 	
-	return true;
+	if formats::format_from_path( fpath ).is_some() {
+		return true;
+	}
 	
+	//Reading the magic number is much cheaper than attempting to decode every file
+	!config.only_known_file_extensions && sniff()
 }
 
 /// Recusively inspects directories and extracts all of the files found
-fn gather_file_list( path_list : &Vec<String>, config: &imagehash::ConfigOptions, am_comparing : bool ) -> Vec<imagehash::ImagePath> {
+fn gather_file_list( path_list : &Vec<PathBuf>, config: &imagehash::ConfigOptions, collection : u32 ) -> Vec<imagehash::ImagePath> {
   	   	
-   	let mut dedup_file_list = HashSet::new();
+   	//Sorted so that the images are always processed in the same order
+   	let mut dedup_file_list = BTreeSet::new();
 	let mut output_image_paths  : Vec<imagehash::ImagePath> = Vec::new();
    	    
 	for file_or_dir in path_list {
-		let fod_test = Path::new(file_or_dir);
+		let fod_test = file_or_dir.as_path();
 		if fod_test.exists() {
-			if fod_test.is_file() {
-				if valid_file_extension( &fod_test, &config ) {
-					dedup_file_list.insert( file_or_dir.to_string() );
+			if fod_test.is_file() && !is_excluded( fod_test, false, config ) {
+				if config.scan_archives && archive::is_archive( fod_test ) {
+					add_archive_images( fod_test, config, &mut dedup_file_list );
+				}else if valid_file_extension( fod_test, config ) || ( config.scan_videos && video::is_video_file( fod_test ) ) {
+					dedup_file_list.insert( file_or_dir.clone() );
 				}
 			}
 			//If the command line argument is a directory, then recursively traverse it
 			if fod_test.is_dir() {
-				let recurse_dir = WalkDir::new(file_or_dir).into_iter();
-				for entry in recurse_dir.filter_entry(|e| !dir_filter(e)) {
-					let entry_u = entry.unwrap();
+				let mut walker = WalkDir::new(file_or_dir).follow_links( config.follow_symlinks ).same_file_system( config.one_file_system );
+				if let Some(max_depth) = config.max_depth {
+					walker = walker.max_depth( max_depth );
+				}
+				for entry in walker.into_iter().filter_entry(|e| !dir_filter(e, config)) {
+					let entry_u = match entry {
+						Ok(entry_u) => entry_u,
+						Err(e) => {
+							//e.g. a directory that can't be read or a symbolic link that loops
+							eprintln!("Warning: {}", e);
+							continue;
+						}
+					};
 					let path = entry_u.path();
-					if path.exists() && path.is_file() {
-						if valid_file_extension( &path, &config ) {
-							dedup_file_list.insert( path.to_str().unwrap().to_owned() );
+					//Symbolic links are only read when following them, when the file type is that of the target
+					if entry_u.file_type().is_file() {
+						if config.scan_archives && archive::is_archive( path ) {
+							add_archive_images( path, config, &mut dedup_file_list );
+						}else if valid_file_extension( path, config ) || ( config.scan_videos && video::is_video_file( path ) ) {
+							dedup_file_list.insert( path.to_path_buf() );
 						}
 						
 					}
 				}
 			}
 		}else{
-			eprintln!("ERROR: Failed to read: {}", file_or_dir);
+			eprintln!("ERROR: Failed to read: {}", file_or_dir.display());
 		}
 	}
 	
+	let mut sidecar_finder = sidecar::SidecarFinder::default();
+	
 	for path in dedup_file_list {
-		let mut always_mark : bool = false;
-		if am_comparing {
-			always_mark = config.always_mark_duplicates;
+		//Images in lower priority collections are always duplicates of higher priority ones with --ignore-resolution
+		let always_mark = collection > 0 && config.always_mark_duplicates;
+		
+		let mut sidecars : Vec<PathBuf> = Vec::new();
+		if config.include_sidecars {
+			sidecars = sidecar_finder.find_sidecars( &path );
 		}
-		output_image_paths.push( imagehash::ImagePath { fpath: path, is_compare_dir: am_comparing, always_mark_dupe_compare: always_mark } );
+		
+		let prefer_as_best = config.prefer_raw && raw::is_raw_file( &path );
+		let is_video = config.scan_videos && video::is_video_file( &path );
+		
+		output_image_paths.push( imagehash::ImagePath { fpath: path, collection, always_mark_dupe_compare: always_mark, prefer_as_best, is_video, alpha_background: Some(config.alpha_background), jpeg_decode: config.jpeg_decode, memory_limit: config.memory_limit, sidecars, aliases: Vec::new() } );
 	}
 
-	return output_image_paths;
-			
+	group_same_files( output_image_paths )
+}
+
+/// Group paths that lead to the same file, such as hard links, symbolic links, bind mounts or the same file given by two different paths, so that each file is only compared once.
+/// The first path to each file is kept and the others are recorded as its aliases.
+fn group_same_files( image_paths: Vec<imagehash::ImagePath> ) -> Vec<imagehash::ImagePath> {
+	let mut grouped : Vec<imagehash::ImagePath> = Vec::new();
+	let mut index_by_identity : HashMap<paths::FileIdentity, usize> = HashMap::new();
+	
+	for image_path in image_paths {
+		//Images inside archives aren't files so have no identity
+		if let Some(identity) = paths::file_identity( &image_path.fpath ) {
+			if let Some(&first) = index_by_identity.get( &identity ) {
+				grouped[first].aliases.push( image_path.fpath );
+				grouped[first].aliases.extend( image_path.aliases );
+				continue;
+			}
+			index_by_identity.insert( identity, grouped.len() );
+		}
+		grouped.push( image_path );
+	}
+	
+	grouped
+}
+
+/// Adds the images found inside an archive to the file list, reporting archives that can't be read
+fn add_archive_images( archive_path: &Path, config: &imagehash::ConfigOptions, dedup_file_list: &mut BTreeSet<PathBuf> ) {
+	match archive::list_images( archive_path, config ) {
+		Ok(images) => dedup_file_list.extend( images ),
+		Err(e) => eprintln!("{}", e),
+	}
+}
+
+/// Filter to ignore files and directories excluded with --exclude and, unless --hidden is used, invisible files that start with a dot
+fn dir_filter(entry: &DirEntry, config: &imagehash::ConfigOptions) -> bool {
+	//The directory given is always searched, even if hidden (e.g. ".")
+	if entry.depth() == 0 {
+		return false;
+	}
+	let hidden = !config.include_hidden && entry.file_name().as_encoded_bytes().starts_with(b".");
+	hidden || is_excluded( entry.path(), entry.file_type().is_dir(), config )
+}
+
+/// Whether a path matches any of the --exclude patterns. Directories are also tested with a trailing separator, so that **/dir/** excludes the whole directory.
+fn is_excluded( path: &Path, is_dir: bool, config: &imagehash::ConfigOptions ) -> bool {
+	let options = glob::MatchOptions { case_sensitive: true, require_literal_separator: true, require_literal_leading_dot: false };
+	config.exclude_patterns.iter().any( |pattern| {
+		pattern.matches_path_with( path, options ) || ( is_dir && pattern.matches_path_with( &path.join( "" ), options ) )
+	})
 }
 
-/// Filter to ignore invisible files that start with a dot
-fn dir_filter(entry: &DirEntry) -> bool {
-    entry.file_name()
-         .to_str()
-         .map(|s| s.starts_with("."))
-         .unwrap_or(false)
+/// Compares the statistics of images (step two of deduplication) and returns them ordered with possible (but not confirmed) duplicates grouped together
+fn find_duplicates( mut image_hash_results: Vec<imagehash::ImageHashAV>, config : &imagehash::ConfigOptions ) -> Vec<imagehash::ImageHashAV> {
+	
+	let total_images_successfully_processed = image_hash_results.len() as u64;
+	
+	//Now move onto step two and compare the image statistics
+	
+	//Use this algorithm on small image sets - often a little more accurate but doesn't scale well
+	if (total_images_successfully_processed <= config.alg_flip_threshold) || config.alg_colour_diff_only {
+		colour_n_square_check( &mut image_hash_results, config );
+	}else{
+		//Use this considerably faster algorithm on larger image sets. "Large" is defined by config.alg_flip_threshold
+		eprintln!("Warn: Using less accurate comparison algorithm due to the number of images.");
+		hamming_check( &mut image_hash_results, config );
+	}
+
+	//Sort the grey hashes to group the matches with their putative duplicates
+	image_hash_results.sort();
+
+	image_hash_results
 }
 
-/// Accepts a list of file paths and returns an ordered list of metadata with possible (but not confirmed) duplicates grouped together
-fn run_image_hashing( dedup_file_list: Vec<imagehash::ImagePath>, config : &imagehash::ConfigOptions ) -> Vec<imagehash::ImageHashAV> {
+/// Calculates the statistics of each image on n threads (step one of deduplication). Images that fail to load are reported on stderr and excluded.
+fn hash_images( dedup_file_list: Vec<imagehash::ImagePath>, config : &imagehash::ConfigOptions ) -> Vec<imagehash::ImageHashAV> {
 	
 	let mut image_hash_results: Vec<imagehash::ImageHashAV> = Vec::new();
 	let mut error_list : Vec<image_error::MyImageError> = Vec::new();
-	let mut num_threads : usize = config.num_threads as usize;
 	let file_list_size: u64 = dedup_file_list.len() as u64;
 	let min_w  = config.min_width;
 	let min_h = config.min_height;
+	let frame_policy = config.frame_policy;
 	
 	if file_list_size == 0 {
 		eprintln!("No images found.");
 		return image_hash_results;
 	}
 	
-	//If there are few images, use only one thread per image
-	if file_list_size < num_threads as u64 {
-		num_threads = file_list_size as usize;
-	} 
+	//Images inside an archive are hashed together on one thread per archive so the archive is only read through once
+	let mut archive_members : BTreeMap<PathBuf, Vec<imagehash::ImagePath>> = BTreeMap::new();
+	let mut files : Vec<imagehash::ImagePath> = Vec::new();
+	for f in dedup_file_list {
+		match archive::split_member_path( &f.fpath ) {
+			Some((archive_path, _)) => archive_members.entry( archive_path.to_path_buf() ).or_default().push( f ),
+			None => files.push( f ),
+		}
+	}
 	
-	//Deduplication is a two step process:
-	//In step one we gather statistics about the image files
-	//In step two we then perform comparisons of the image statistics
+	//Large images wait for memory to be free before they are decoded, if there is a memory limit
+	let memory_budget = config.memory_limit.map( memory::MemoryBudget::new );
 	
-	//Calculate the image hashes on n threads
-	//The number of threads can be set using a command line option
-	let pool = ThreadPool::new(num_threads);
+	//Videos are only read if a decoder (ffmpeg) is available
+	let video_decoder = if files.iter().any( |f| f.is_video ) { video::find_decoder( config ) } else { None };
 	
-	let (tx, rx) = channel();
-	for f in dedup_file_list {
-		let tx = tx.clone();
-		pool.execute(move|| {
-			tx.send(imagehash::ImageHashAV::new( &f, min_w, min_h )).unwrap();
-		});
-	}
-	drop(tx);
+	let hash_file = |f: &imagehash::ImagePath| {
+		if f.is_video {
+			return match video_decoder {
+				Some(ref decoder) => video::hash_video( decoder.as_ref(), f, min_w, min_h, memory_budget.as_ref() ),
+				None => Err(image_error::MyImageError::FileError(format!("Error: Skipped video as ffmpeg was not found: {}", f.fpath.display()))),
+			};
+		}
+		let _reservation = match memory_budget.as_ref().map( |budget| budget.reserve( memory::estimate_decoded_size( f, frame_policy ).unwrap_or(0), &f.fpath ) ) {
+			Some(Err(e)) => return Err(e),
+			reservation => reservation,
+		};
+		imagehash::ImageHashAV::new_with_frames( f, min_w, min_h, frame_policy )
+	};
 
-	//Perform step one: gather statistics
+	//Perform step one: gather statistics on all threads, the results are collected in the order of the file list
 	//Draw a progress bar for the user.
 	let progress_bar = ProgressBar::new(file_list_size);
+	let file_results = files.par_iter().map( |f| {
+		let result = hash_file( f );
+		progress_bar.inc(1);
+		result
+	});
+	let archive_results = archive_members.into_par_iter().flat_map_iter( |(archive_path, members)| {
+		let num_members = members.len() as u64;
+		let results = archive::hash_members( &archive_path, members, min_w, min_h, frame_policy, memory_budget.as_ref() );
+		progress_bar.inc(num_members);
+		results
+	});
+	let results : Vec<Result<imagehash::ImageHashAV, image_error::MyImageError>> = file_results.chain( archive_results ).collect();
+	progress_bar.finish_and_clear();
 	
-	let mut total_images_successfully_processed : u64 = 0;
-	
-	//Collate the output of the threads
-	for t_result in rx.into_iter(){
+	for t_result in results {
 		match t_result {
 			Ok(img_result)=> {
 				image_hash_results.push( img_result );
-				total_images_successfully_processed +=1;
 			}
 			Err(e)=>{
 				//Store the errors to print later, as printing them live disrupts the progress bar
 				error_list.push( e )
 			}	
 		}
-		progress_bar.inc(1);
-		
 	}
-	progress_bar.finish_and_clear();
 		
 	//Print any errors that ocurred while producing the hashes
+	let num_over_limit = error_list.iter().filter( |e| matches!( e, image_error::MyImageError::MemoryLimit(_) ) ).count();
 	for e in error_list {
 		eprintln!("{}", e.to_string());
 	}
-	
-	//Now move onto step two and compare the image statistics
-	
-	//Use this algorithm on small image sets - often a little more accurate but doesn't scale well
-	if (total_images_successfully_processed <= config.alg_flip_threshold) || config.alg_colour_diff_only {
-		colour_n_square_check( &mut image_hash_results, &config );
-	}else{
-		//Use this considerably faster algorithm on larger image sets. "Large" is defined by config.alg_flip_threshold
-		eprintln!("Warn: Using less accurate comparison algorithm due to the number of images.");
-		hamming_check( &mut image_hash_results, &config );
+	if num_over_limit > 0 {
+		eprintln!("Warning: {} images were skipped as they need more memory than --memory-limit allows", num_over_limit);
 	}
-
-	//Sort the grey hashes to group the matches with their putative duplicates
-	image_hash_results.sort();
-
+	
 	return image_hash_results;
 	
 }
@@ -509,67 +1380,93 @@ fn run_image_hashing( dedup_file_list: Vec<imagehash::ImagePath>, config : &imag
 
 fn hamming_check( image_hash_results : &mut Vec<imagehash::ImageHashAV>, config : &imagehash::ConfigOptions ){
 	
-	let mut all_hash_codes = HashMap::new();
-	
 	image_hash_results.sort_by(|a, b| b.num_pixels.cmp(&a.num_pixels));
-
-	for imagehasher in image_hash_results.iter_mut() {
-		if all_hash_codes.len() == 0 {
-			all_hash_codes.insert( imagehasher.dupe_group, imagehasher );
-		}else{
-			if !all_hash_codes.contains_key( &imagehasher.dupe_group ){
-				let test_hash = imagehasher.dupe_group;
-				//Try every variation of the current hash with 1 bit flipped
-				//Check if flipping one bit would cause a match
-				let mut putative_match_hash : u64 = 0;
-				let mut last_putative_size : u64 = 0;
-				for n in 0..64 {
-					let flipped_bit_hash = ((1 as u64) << n) ^ test_hash;
-					if all_hash_codes.contains_key( &flipped_bit_hash ) {
-						//Only accept the bit flip if the colour check also matches and the aspect ratios are similar
-						if imagehasher.is_dupe( all_hash_codes.get( &flipped_bit_hash ).unwrap(), &config ) {					
-							//Prefer the match with the largest number of pixels
-							if imagehasher.num_pixels > last_putative_size{
-								//Set the hash to be the same as the one with the flipped bit
-								last_putative_size = all_hash_codes.get( &flipped_bit_hash ).unwrap().num_pixels;
-								putative_match_hash = flipped_bit_hash
-							}
-						}
-					}
+	
+	//Index the images by hash, each list of images is in sorted order
+	let mut images_by_hash : HashMap<u64, Vec<usize>> = HashMap::new();
+	for (i, imagehasher) in image_hash_results.iter().enumerate() {
+		images_by_hash.entry( imagehasher.dupe_group ).or_default().push( i );
+	}
+	
+	//Query the index on all threads. For every hash with 1 bit flipped, compare the image with the first earlier image that has that hash.
+	//Gives (flipped hash, earlier image, is duplicate) in the order of the bit flipped.
+	let images = &*image_hash_results;
+	let flipped_matches : Vec<Vec<(u64, usize, bool)>> = (0..images.len()).into_par_iter().map( |i| {
+		let mut found = Vec::new();
+		for n in 0..64 {
+			let flipped_bit_hash = (1u64 << n) ^ images[i].dupe_group;
+			if let Some(&first) = images_by_hash.get( &flipped_bit_hash ).and_then( |indexes| indexes.first() ) {
+				if first < i {
+					found.push( (flipped_bit_hash, first, images[i].is_dupe( &images[first], config )) );
 				}
-				
-				if last_putative_size > 0 && putative_match_hash > 0 {
-					imagehasher.dupe_group = putative_match_hash;
-				}else{
-					all_hash_codes.insert( test_hash, imagehasher );
+			}
+		}
+		found
+	}).collect();
+	
+	//Assign the groups in order of size, as each image can only join the group of a larger image that started its own group
+	let mut all_hash_codes : HashMap<u64, usize> = HashMap::new();
+	for (i, found) in flipped_matches.into_iter().enumerate() {
+		let test_hash = image_hash_results[i].dupe_group;
+		if all_hash_codes.contains_key( &test_hash ) {
+			continue;
+		}
+		//Check if flipping one bit would cause a match
+		let mut putative_match_hash : u64 = 0;
+		let mut last_putative_size : u64 = 0;
+		for (flipped_bit_hash, first, first_is_dupe) in found {
+			if let Some(&group_start) = all_hash_codes.get( &flipped_bit_hash ) {
+				//The group is almost always started by the first image with the hash, which was already compared
+				let is_dupe = if group_start == first { first_is_dupe } else { image_hash_results[i].is_dupe( &image_hash_results[group_start], config ) };
+				//Only accept the bit flip if the colour check also matches and the aspect ratios are similar
+				//Prefer the match with the largest number of pixels
+				if is_dupe && image_hash_results[i].num_pixels > last_putative_size {
+					//Set the hash to be the same as the one with the flipped bit
+					last_putative_size = image_hash_results[group_start].num_pixels;
+					putative_match_hash = flipped_bit_hash;
 				}
-				
 			}
-			
+		}
+		
+		if last_putative_size > 0 && putative_match_hash > 0 {
+			image_hash_results[i].dupe_group = putative_match_hash;
+		}else{
+			all_hash_codes.insert( test_hash, i );
 		}
 	}
 }
 
 /// Determine if images might be duplicates by using an n^2 scaling method (compares every image against every other) 
- 
+///
+/// The comparisons are shared between all threads, then the groups are assigned in the order the pairs of images appear so that the result doesn't depend on the number of threads.
 fn colour_n_square_check( image_hash_results : &mut Vec<imagehash::ImageHashAV>, config : &imagehash::ConfigOptions ){
 	
 	for i in 0..image_hash_results.len(){
 		image_hash_results[i].dupe_group = 0;
 	}
 	
-	let mut dgroup : u64 = 1;
-	
-	let mut dupes_groups : HashMap<usize,u64> = HashMap::new();
+	let num_images = image_hash_results.len();
 	
 	//Display a 2nd progress bar as this can take a long time
-	let progress_bar = ProgressBar::new(image_hash_results.len() as u64);
+	let progress_bar = ProgressBar::new(num_images as u64);
+	
+	//For each image find the later images it is a duplicate of
+	let images = &*image_hash_results;
+	let dupes_found : Vec<Vec<usize>> = (0..num_images).into_par_iter().map( |i| {
+		let found = ((i+1)..num_images).filter( |&j| images[i].is_dupe( &images[j], config ) ).collect();
+		progress_bar.inc(1);
+		found
+	}).collect();
 	
-	for i in 0..image_hash_results.len() {
+	progress_bar.finish_and_clear();
+	
+	let mut dupes_groups : HashMap<usize,u64> = HashMap::new();
+	
+	for (i, found) in dupes_found.into_iter().enumerate() {
 		
-		for j in (i+1)..image_hash_results.len() {	
-						
-					if image_hash_results[i].is_dupe( &image_hash_results[j], &config ) {
+		for j in found {
+					//Each pair of images has its own group number, counting the pairs in order from 1
+					let dgroup : u64 = ( i * (num_images - 1) - i * i.saturating_sub(1) / 2 + j - i ) as u64;
 						
 						if dupes_groups.contains_key(&i) {
 							let d : u64 = *dupes_groups.get( &i ).unwrap();
@@ -584,56 +1481,156 @@ fn colour_n_square_check( image_hash_results : &mut Vec<imagehash::ImageHashAV>,
 							image_hash_results[i].dupe_group  = dgroup;
 							image_hash_results[j].dupe_group  = dgroup;
 						}
-						
-					}	
-			dgroup +=1;
 		}
+	}
+}
 
-		progress_bar.inc(1)
+/// Splits the sorted results into groups of duplicates. The first image of each group is the best version, unique images form a group of one.
+fn duplicate_groups( image_hash_results : &[imagehash::ImageHashAV], config : &imagehash::ConfigOptions ) -> Vec<Range<usize>> {
+	
+	let mut groups : Vec<Range<usize>> = Vec::new();
+	let mut group_start : usize = 0;
+	
+	while group_start < image_hash_results.len() {
+		let best = &image_hash_results[group_start];
+		let mut next = group_start + 1;
+		while next < image_hash_results.len() && image_hash_results[next].dupe_group == best.dupe_group && best.is_dupe( &image_hash_results[next], config ) {
+			next += 1;
+		}
+		groups.push( group_start..next );
+		group_start = next;
 	}
 	
-	progress_bar.finish_and_clear();
+	groups
+}
+
+/// For each group of duplicates, write the metadata held by the duplicates but missing from the best version into an XMP sidecar for the best version
+fn merge_duplicate_metadata( image_hash_results : &[imagehash::ImageHashAV], config : &imagehash::ConfigOptions ) {
+	
+	for group in duplicate_groups( image_hash_results, config ) {
+		if group.len() > 1 {
+			let best = &image_hash_results[group.start];
+			let duplicate_paths : Vec<&Path> = image_hash_results[group.start+1..group.end].iter().map( |d| d.image_path.fpath.as_path() ).collect();
+			match metadata::merge_into_sidecar( &best.image_path.fpath, &duplicate_paths ) {
+				Ok(Some(sidecar)) => eprintln!("Merged metadata from duplicates into: {}", sidecar.display()),
+				Ok(None) => {},
+				Err(e) => eprintln!("{}", e),
+			}
+		}
+	}
+}
+
+/// Find sets of similar shots among the best version of each image. Each set is ranked by sharpness and exposure, best shot first.
+fn find_similar_shots( image_hash_results : &[imagehash::ImageHashAV], config : &imagehash::ConfigOptions ) -> Vec<Vec<(PathBuf, similar::ShotQuality)>> {
+	
+	//Only the best version of each image is considered, the duplicates are reported separately
+	let best_versions : Vec<&imagehash::ImageHashAV> = duplicate_groups( image_hash_results, config ).iter().map( |g| &image_hash_results[g.start] ).collect();
+	let mut ranked_sets = Vec::new();
+	
+	for set in similar::find_similar_sets( &best_versions, config ) {
+		let paths : Vec<PathBuf> = set.iter().map( |i| best_versions[*i].image_path.fpath.clone() ).collect();
+		let mut ranked : Vec<(PathBuf, similar::ShotQuality)> = Vec::new();
+		for (path, quality) in paths.iter().zip( similar::measure_quality_all( &paths, config ) ) {
+			match quality {
+				Ok(quality) => ranked.push( (path.clone(), quality) ),
+				Err(e) => eprintln!("{}", e),
+			}
+		}
+		ranked.sort_by( |a, b| b.1.score().total_cmp( &a.1.score() ) );
+		if ranked.len() > 1 {
+			ranked_sets.push( ranked );
+		}
+	}
+	
+	ranked_sets
+}
+
+/// Print each set of similar shots, best shot first
+fn output_similar_shots( similar_sets : &[Vec<(PathBuf, similar::ShotQuality)>], config : &imagehash::ConfigOptions ) {
+	for set in similar_sets {
+		println!("Similar shots:");
+		for (rank, (path, quality)) in set.iter().enumerate() {
+			paths::print_path_line( &format!("\tRank {} (sharpness: {:.1}, exposure: {:.2}): ", rank+1, quality.sharpness, quality.exposure), path, config );
+		}
+	}
+	eprintln!("Sets of similar shots: {}", similar_sets.len());
+}
+
+/// The name of the collection an image is in, shown after its resolution, or nothing if the collection isn't named
+fn collection_label( image_path : &imagehash::ImagePath, config : &imagehash::ConfigOptions ) -> String {
+	match config.collections.get( image_path.collection as usize ) {
+		Some(collection) if !collection.name.is_empty() => format!(" [{}]", collection.name),
+		_ => String::new(),
+	}
+}
+
+/// Print the other paths to the same file as an image, one per line, each preceded by the prefix
+fn print_aliases( image_path : &imagehash::ImagePath, prefix : &str, config : &imagehash::ConfigOptions ) {
+	for alias in &image_path.aliases {
+		paths::print_path_line( prefix, alias, config );
+	}
+}
+
+/// Print the sidecar files of an image, one per line, each preceded by the prefix
+fn print_sidecars( image_path : &imagehash::ImagePath, prefix : &str, config : &imagehash::ConfigOptions ) {
+	for sidecar in &image_path.sidecars {
+		paths::print_path_line( prefix, sidecar, config );
+	}
 }
 
 /// Print the detected duplicates based on preferneces specified in command line options
 fn output_results( image_hash_results : Vec<imagehash::ImageHashAV> , config : &imagehash::ConfigOptions  ){
 
-	let mut last_unique_ih: imagehash::ImageHashAV = imagehash::ImageHashAV { dupe_group: 0, grey_hash: 0, low_res: [0;192], width: 0, height: 0, num_pixels: 0, std_dev : 0f32, file_size: 0, image_path: imagehash::ImagePath{ fpath: "".to_string(), is_compare_dir: false, always_mark_dupe_compare: false } };
+	let mut last_unique_ih: imagehash::ImageHashAV = imagehash::ImageHashAV { dupe_group: 0, grey_hash: 0, low_res: [0;192], width: 0, height: 0, num_pixels: 0, std_dev : 0f32, file_size: 0, image_path: imagehash::ImagePath{ fpath: "".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() }, frames: Vec::new(), transparency: 0f32, alpha_mask: None };
 	let mut printed_uniq_header : bool = false;
 	let mut not_first_it = false;
 		
 	let mut num_unique_images : u64 = 0;
 	let mut num_dupe_images : u64 = 0;
+	let num_aliases = image_hash_results.iter().map( |i| i.image_path.aliases.len() ).sum::<usize>();
 	
-	
+	//Other paths to the same file are only listed where the output isn't a list of files to act upon (--duplicates and --uniques), as removing an alias may remove the file itself
 	for imagehasher in image_hash_results {
 
 		if not_first_it && imagehasher.dupe_group == last_unique_ih.dupe_group && 
 			last_unique_ih.is_dupe( &imagehasher, &config )  {			
 			if config.list_all {
-				println!("\tDuplicate: {}", imagehasher.image_path.fpath );
+				paths::print_path_line( "\tDuplicate: ", &imagehasher.image_path.fpath, config );
+				print_aliases( &imagehasher.image_path, "\t\tSame file: ", config );
+				print_sidecars( &imagehasher.image_path, "\t\tSidecar: ", config );
 			}else if config.only_list_duplicates {
-				//If using --compare, only report the duplicate if it is in the comparison dir
-				if (!config.am_comparing) || imagehasher.image_path.is_compare_dir {
-					println!("{}", imagehasher.image_path.fpath );
+				//If using --compare or --collection, only report the duplicate if it isn't in the first collection
+				if (!config.am_comparing) || imagehasher.image_path.collection > 0 {
+					paths::print_path_line( "", &imagehasher.image_path.fpath, config );
+					print_sidecars( &imagehasher.image_path, "", config );
 				}
 			}else if !config.only_list_uniques {
-				//If using --compare, only report if the best or duplicate is in the comparison dir
-				if (!config.am_comparing) || last_unique_ih.image_path.is_compare_dir || imagehasher.image_path.is_compare_dir {
+				//If using --compare or --collection, only report if the best or duplicate isn't in the first collection
+				if (!config.am_comparing) || last_unique_ih.image_path.collection > 0 || imagehasher.image_path.collection > 0 {
 					if !printed_uniq_header {
-						println!("Best({}x{}): {}", last_unique_ih.width, last_unique_ih.height, last_unique_ih.image_path.fpath );
+						paths::print_path_line( &format!("Best({}x{}){}: ", last_unique_ih.width, last_unique_ih.height, collection_label( &last_unique_ih.image_path, config )), &last_unique_ih.image_path.fpath, config );
+						print_aliases( &last_unique_ih.image_path, "\tSame file: ", config );
+						print_sidecars( &last_unique_ih.image_path, "\tSidecar: ", config );
 						printed_uniq_header = true;
 					}
-					println!("\tDuplicate({}x{}): {}", imagehasher.width, imagehasher.height, imagehasher.image_path.fpath );
+					paths::print_path_line( &format!("\tDuplicate({}x{}){}: ", imagehasher.width, imagehasher.height, collection_label( &imagehasher.image_path, config )), &imagehasher.image_path.fpath, config );
+					print_aliases( &imagehasher.image_path, "\t\tSame file: ", config );
+					print_sidecars( &imagehasher.image_path, "\t\tSidecar: ", config );
 				}
 			}
 			num_dupe_images+=1;
 		}else{
 			printed_uniq_header = false;
 			if config.only_list_uniques || config.list_all {
-				//If using --compare, only report the unique image if it is in the comparison dir
-				if (!config.am_comparing) || imagehasher.image_path.is_compare_dir {
-					println!("{}", imagehasher.image_path.fpath );
+				//If using --compare or --collection, only report the unique image if it isn't in the first collection
+				if (!config.am_comparing) || imagehasher.image_path.collection > 0 {
+					paths::print_path_line( "", &imagehasher.image_path.fpath, config );
+					if config.list_all {
+						print_aliases( &imagehasher.image_path, "\tSame file: ", config );
+						print_sidecars( &imagehasher.image_path, "\tSidecar: ", config );
+					}else{
+						print_sidecars( &imagehasher.image_path, "", config );
+					}
 				}
 			}
 						
@@ -645,6 +1642,9 @@ fn output_results( image_hash_results : Vec<imagehash::ImageHashAV> , config : &
 	
 	if (!config.only_list_duplicates) && (!config.only_list_uniques) && (!config.list_all) && (!config.am_comparing) {
 		eprintln!("Unique Images: {} Duplicates: {}", num_unique_images, num_dupe_images);
+		if num_aliases > 0 {
+			eprintln!("Other paths to the same files: {}", num_aliases);
+		}
 	}
 
 }
@@ -654,12 +1654,21 @@ fn output_results( image_hash_results : Vec<imagehash::ImageHashAV> , config : &
 mod tests {	
     use super::*;
     
+	/// Test background colours are parsed from the command line
+	#[test]
+	fn test_parse_colour() {
+		assert_eq!( parse_colour( "White" ), Some([255, 255, 255]) );
+		assert_eq!( parse_colour( "#ff8000" ), Some([255, 128, 0]) );
+		assert_eq!( parse_colour( "ff80" ), None );
+		assert_eq!( parse_colour( "gg8000" ), None );
+	}
+	
 	/// Tests that the n square check identifies three images that should be duplicates as duplicates
 	#[test]
 	fn test_n_square_check() {
-		let best = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/cat1_best.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
-		let dupe = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/cat1_duplicate_1.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
-		let dupe2 = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/cat1_duplicate_2.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
+		let best = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/cat1_best.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
+		let dupe = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/cat1_duplicate_1.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
+		let dupe2 = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/cat1_duplicate_2.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
 		let mut images = vec![ dupe, best, dupe2 ];
 		
 		colour_n_square_check( &mut images, &get_default_config_options() );
@@ -675,9 +1684,9 @@ mod tests {
 	/// Tests that when using the hamming method images are identified as duplicates
 	#[test]
 	fn test_hamming() {
-		let best = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_best.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
-		let dupe = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_duplicate_1.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
-		let dupe2 = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_duplicate_2.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
+		let best = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_best.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
+		let dupe = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_duplicate_1.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
+		let dupe2 = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_duplicate_2.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
 		let mut images = vec![ dupe2, best, dupe ];
 		
 		hamming_check( &mut images, &get_default_config_options() );
@@ -695,9 +1704,9 @@ mod tests {
 		//Test the --compare option
 
 		//Put the highest resolution image in the compare directory and used the --always-mark-duplicates option
-		let best = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_best.jpg".to_string(), is_compare_dir:true, always_mark_dupe_compare: true },0,0 ).unwrap();
+		let best = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_best.jpg".into(), collection: 1, always_mark_dupe_compare: true, ..Default::default() },0,0 ).unwrap();
 		//Lower resolution image
-		let dupe = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_duplicate_1.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
+		let dupe = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_duplicate_1.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
 		let mut images = vec![ best, dupe ];
 
 		colour_n_square_check( &mut images, &get_default_config_options() );
@@ -710,14 +1719,14 @@ mod tests {
 		assert_eq!( images[0].dupe_group, images[1].dupe_group, "Images have same dupe group" );
 
 		//Test that they are ordered such as the highest resolution image is lower down because it is in the comparison directory. This forces identification as a duplicate even though it is better quality
-		assert_eq!( images[0].image_path.fpath, "unit_test_images/car1_duplicate_1.jpg", "Duplicate should be top of the list because not in the compare directory." );
-		assert_eq!( images[1].image_path.fpath, "unit_test_images/car1_best.jpg", "Best image should be second on the list because is in the compare directory." );
+		assert_eq!( images[0].image_path.fpath, Path::new( "unit_test_images/car1_duplicate_1.jpg" ), "Duplicate should be top of the list because not in the compare directory." );
+		assert_eq!( images[1].image_path.fpath, Path::new( "unit_test_images/car1_best.jpg" ), "Best image should be second on the list because is in the compare directory." );
 
 		
 		//Test that when images are identical the one in the compare directory should sort last when using -always-mark-duplicates option
-		let t2_best = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/book1_best.jpg".to_string(), is_compare_dir:true, always_mark_dupe_compare: true },0,0 ).unwrap();
-		let t2_dupe1 = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/book1_best.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
-		let t2_dupe2 = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/book1_best.jpg".to_string(), is_compare_dir:false, always_mark_dupe_compare: false },0,0 ).unwrap();
+		let t2_best = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/book1_best.jpg".into(), collection: 1, always_mark_dupe_compare: true, ..Default::default() },0,0 ).unwrap();
+		let t2_dupe1 = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/book1_best.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
+		let t2_dupe2 = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/book1_best.jpg".into(), collection: 0, always_mark_dupe_compare: false, ..Default::default() },0,0 ).unwrap();
 		let mut t2_images = vec![ t2_best, t2_dupe1, t2_dupe2 ];
 
 		hamming_check( &mut t2_images, &get_default_config_options() );
@@ -729,10 +1738,184 @@ mod tests {
 		assert_ne!( t2_images[2].dupe_group, 0, "Dupe group is not zero" );
 		assert_eq!( t2_images[0].dupe_group, t2_images[1].dupe_group, "Images have same dupe group" );
 		assert_eq!( t2_images[0].dupe_group, t2_images[2].dupe_group, "Images have same dupe group" );
-		assert_eq!( t2_images[0].image_path.is_compare_dir, false, "The 1st image is not in the compare directory" );
-		assert_eq!( t2_images[1].image_path.is_compare_dir, false, "The 2nd image is not in the compare directory" );
-		assert_eq!( t2_images[2].image_path.is_compare_dir, true, "The image in the compare directory is last in the sort group" );
+		assert_eq!( t2_images[0].image_path.collection, 0, "The 1st image is not in the compare directory" );
+		assert_eq!( t2_images[1].image_path.collection, 0, "The 2nd image is not in the compare directory" );
+		assert_eq!( t2_images[2].image_path.collection, 1, "The image in the compare directory is last in the sort group" );
+	}
+
+	/// Tests the extension include and exclude lists and identifying images by content with --any-file
+	#[test]
+	fn test_extension_filters() {
+		let mut config = get_default_config_options();
+		config.include_extensions = normalise_extensions( &[".JFIF".to_string()] );
+		config.exclude_extensions = normalise_extensions( &["gif".to_string()] );
+
+		assert!( valid_file_extension( Path::new("a/photo.JPG"), &config ), "Known extension" );
+		assert!( valid_file_extension( Path::new("a/photo.jfif"), &config ), "Included extension" );
+		assert!( !valid_file_extension( Path::new("a/anim.gif"), &config ), "Excluded extension" );
+		assert!( !valid_file_extension( Path::new("unit_test_images/Image-Credits-Licenses.md"), &config ), "Unknown extension" );
+
+		config.only_known_file_extensions = false;
+		assert!( !valid_file_extension( Path::new("unit_test_images/Image-Credits-Licenses.md"), &config ), "Text file is not an image with --any-file" );
+		assert!( !valid_file_extension( Path::new("unit_test_images/car5_duplicate_1.gif"), &config ), "Excluded extension with --any-file" );
 	}
 	
-}
+	/// Tests that images in higher priority collections are preferred, and that named collections and several --compare directories are read from the command line
+	#[test]
+	fn test_collections() {
+		let args = Args::try_parse_from( [ "photodedupe", "--collection", "archive=unit_test_images", "--collection", "phone=src", "--collection", "archive=docs", "-c", "benches", "-c", "debian" ] ).unwrap();
+		let config = set_config_options( &args ).unwrap();
+		let collections : Vec<(&str, usize)> = config.collections.iter().map( |c| (c.name.as_str(), c.paths.len()) ).collect();
+		assert_eq!( collections, vec![ ("archive", 2), ("phone", 1), ("", 2) ], "Collections in order of priority, with the --compare directories last" );
+		assert!( Args::try_parse_from( [ "photodedupe", "--collection", "unit_test_images" ] ).is_ok_and( |args| set_config_options( &args ).is_err() ), "Collection must be named" );
+
+		//The lowest resolution image is in the highest priority collection
+		let images_in = |always_mark: bool| [ ("car1_best.jpg", 2), ("car1_duplicate_1.jpg", 1), ("car1_duplicate_2.jpg", 0) ].iter().map( |(name, collection)| {
+			imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: Path::new( "unit_test_images" ).join( name ), collection: *collection, always_mark_dupe_compare: always_mark && *collection > 0, ..Default::default() },0,0 ).unwrap()
+		}).collect::<Vec<imagehash::ImageHashAV>>();
+		let order = |mut images: Vec<imagehash::ImageHashAV>| {
+			colour_n_square_check( &mut images, &get_default_config_options() );
+			images.sort();
+			images.iter().map( |i| i.image_path.collection ).collect::<Vec<u32>>()
+		};
+		assert_eq!( order( images_in( false ) ), vec![ 2, 1, 0 ], "Highest resolution is best" );
+		assert_eq!( order( images_in( true ) ), vec![ 0, 1, 2 ], "With --ignore-resolution the highest priority collection is best" );
+	}
+	
+	/// Tests that an image preferred as best (e.g. a RAW file with --prefer-raw) sorts first even though it is lower resolution
+	#[test]
+	fn test_prefer_as_best() {
+		let best = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_best.jpg".into(), ..Default::default() },0,0 ).unwrap();
+		let preferred = imagehash::ImageHashAV::new( &imagehash::ImagePath { fpath: "unit_test_images/car1_duplicate_1.jpg".into(), prefer_as_best: true, ..Default::default() },0,0 ).unwrap();
+		let mut images = vec![ best, preferred ];
 
+		colour_n_square_check( &mut images, &get_default_config_options() );
+		images.sort();
+
+		assert_eq!( images[0].dupe_group, images[1].dupe_group, "Images have same dupe group" );
+		assert_eq!( images[0].image_path.fpath, Path::new( "unit_test_images/car1_duplicate_1.jpg" ), "Preferred image is first despite lower resolution" );
+	}
+	
+	/// Tests that images with file names that aren't UTF-8 are found and compared, and their paths kept unchanged
+	#[cfg(unix)]
+	#[test]
+	fn test_non_utf8_paths() {
+		let dir = std::env::temp_dir().join( "photodedupe_non_utf8_test" );
+		let _ = std::fs::remove_dir_all( &dir );
+		std::fs::create_dir_all( &dir ).unwrap();
+		let latin1_path = dir.join( paths::path_from_bytes( b"caf\xe9.jpg".to_vec() ) );
+		let invalid_path = dir.join( paths::path_from_bytes( b"\xff\xfe.jpg".to_vec() ) );
+		std::fs::copy( "unit_test_images/cat1_best.jpg", &latin1_path ).unwrap();
+		std::fs::copy( "unit_test_images/cat1_duplicate_1.jpg", &invalid_path ).unwrap();
+
+		let config = get_default_config_options();
+		let file_list = gather_file_list( &vec![ dir.clone() ], &config, 0 );
+		assert_eq!( file_list.len(), 2, "Both images found" );
+		let images = find_duplicates( hash_images( file_list, &config ), &config );
+		assert_eq!( images.len(), 2, "Both images hashed" );
+		assert!( images[0].is_dupe( &images[1], &config ), "Images are duplicates" );
+		assert_eq!( images[0].image_path.fpath, latin1_path, "Best version path unchanged" );
+		assert_eq!( images[1].image_path.fpath, invalid_path, "Duplicate path unchanged" );
+
+		std::fs::remove_dir_all( &dir ).unwrap();
+	}
+	
+	/// Tests the directory traversal options: hidden files, excludes, maximum depth and following symbolic links
+	#[cfg(unix)]
+	#[test]
+	fn test_directory_traversal() {
+		let dir = std::env::temp_dir().join( "photodedupe_traversal_test" );
+		let _ = std::fs::remove_dir_all( &dir );
+		for sub_dir in [ ".hidden", "@eaDir", "2019/summer" ] {
+			std::fs::create_dir_all( dir.join( sub_dir ) ).unwrap();
+		}
+		for file in [ "a.jpg", ".hidden/b.jpg", "@eaDir/c.jpg", "2019/summer/d.jpg" ] {
+			std::fs::write( dir.join( file ), b"" ).unwrap();
+		}
+		std::os::unix::fs::symlink( dir.join( "a.jpg" ), dir.join( "linked.jpg" ) ).unwrap();
+		std::os::unix::fs::symlink( &dir, dir.join( "2019/loop" ) ).unwrap();
+
+		let found = |config: &imagehash::ConfigOptions| {
+			gather_file_list( &vec![ dir.clone() ], config, 0 ).into_iter()
+				.map( |p| p.fpath.strip_prefix( &dir ).unwrap().to_str().unwrap().to_string() ).collect::<Vec<String>>()
+		};
+		let mut config = get_default_config_options();
+		assert_eq!( found( &config ), vec![ "2019/summer/d.jpg", "@eaDir/c.jpg", "a.jpg" ], "Hidden files and symbolic links skipped by default" );
+		config.include_hidden = true;
+		assert_eq!( found( &config ), vec![ ".hidden/b.jpg", "2019/summer/d.jpg", "@eaDir/c.jpg", "a.jpg" ], "Hidden files included" );
+		config.include_hidden = false;
+		config.exclude_patterns = vec![ glob::Pattern::new( "**/@eaDir/**" ).unwrap(), glob::Pattern::new( "**/summer" ).unwrap() ];
+		assert_eq!( found( &config ), vec![ "a.jpg" ], "Excluded directories skipped" );
+		config.exclude_patterns = Vec::new();
+		config.max_depth = Some(1);
+		assert_eq!( found( &config ), vec![ "a.jpg" ], "Only files directly inside the directory" );
+		config.max_depth = None;
+		config.follow_symlinks = true;
+		assert_eq!( found( &config ), vec![ "2019/summer/d.jpg", "@eaDir/c.jpg", "a.jpg" ], "Loop skipped" );
+		let file_list = gather_file_list( &vec![ dir.clone() ], &config, 0 );
+		assert_eq!( file_list[2].aliases, vec![ dir.join( "linked.jpg" ) ], "Linked file found as the same file" );
+
+		std::fs::remove_dir_all( &dir ).unwrap();
+	}
+	
+	/// Tests that the same file reached through a hard link, a symbolic link and a second path is only compared once
+	#[cfg(unix)]
+	#[test]
+	fn test_same_file_grouped() {
+		let dir = std::env::temp_dir().join( "photodedupe_same_file_test" );
+		let _ = std::fs::remove_dir_all( &dir );
+		std::fs::create_dir_all( dir.join( "originals" ) ).unwrap();
+		std::fs::copy( "unit_test_images/cat1_best.jpg", dir.join( "originals/cat.jpg" ) ).unwrap();
+		std::fs::copy( "unit_test_images/cat1_duplicate_1.jpg", dir.join( "cat_small.jpg" ) ).unwrap();
+		std::fs::hard_link( dir.join( "originals/cat.jpg" ), dir.join( "hard_link.jpg" ) ).unwrap();
+		std::os::unix::fs::symlink( dir.join( "originals" ), dir.join( "linked" ) ).unwrap();
+
+		let mut config = get_default_config_options();
+		config.follow_symlinks = true;
+		let file_list = gather_file_list( &vec![ dir.clone(), dir.join( "originals/../originals/cat.jpg" ) ], &config, 0 );
+		assert_eq!( file_list.len(), 2, "Each file listed once" );
+		let cat = file_list.iter().find( |p| !p.aliases.is_empty() ).unwrap();
+		assert_eq!( cat.fpath, dir.join( "hard_link.jpg" ), "First path kept" );
+		assert_eq!( cat.aliases, vec![ dir.join( "linked/cat.jpg" ), dir.join( "originals/../originals/cat.jpg" ), dir.join( "originals/cat.jpg" ) ], "Other paths are aliases" );
+
+		let images = find_duplicates( hash_images( file_list, &config ), &config );
+		assert_eq!( images.len(), 2, "Same file not compared with itself" );
+		assert!( images[0].is_dupe( &images[1], &config ), "Visual duplicate still found" );
+
+		std::fs::remove_dir_all( &dir ).unwrap();
+	}
+	
+	/// Tests paths are read one per line, or NUL separated so that names can contain newlines
+	#[test]
+	fn test_read_path_list() {
+		let lines = read_path_list( &b"photos/a.jpg\r\n\n  photos/b c.jpg \n"[..], false ).unwrap();
+		assert_eq!( lines, vec![ PathBuf::from( "photos/a.jpg" ), PathBuf::from( "photos/b c.jpg" ) ], "Blank lines and whitespace removed" );
+		let nul_separated = read_path_list( &b"photos/IMG_1\n2.jpg\0 photos/b.jpg\0"[..], true ).unwrap();
+		assert_eq!( nul_separated, vec![ PathBuf::from( "photos/IMG_1\n2.jpg" ), PathBuf::from( " photos/b.jpg" ) ], "Paths used exactly as given" );
+		assert_eq!( read_path_list( &b"\0\0"[..], true ), None, "No paths" );
+	}
+	
+	/// Tests that hashing and both grouping methods give the same results whatever the number of threads
+	#[test]
+	fn test_thread_count_independent() {
+		let config = get_default_config_options();
+		let file_list = gather_file_list( &vec!["unit_test_images".into()], &config, 0 );
+		let groups = |num_threads: usize| {
+			let pool = rayon::ThreadPoolBuilder::new().num_threads( num_threads ).build().unwrap();
+			pool.install( || {
+				let images = hash_images( file_list.clone(), &config );
+				let mut colour_diff = images.clone();
+				colour_n_square_check( &mut colour_diff, &config );
+				let mut hamming = images.clone();
+				hamming_check( &mut hamming, &config );
+				let paths = images.iter().map( |i| i.image_path.fpath.clone() ).collect::<Vec<PathBuf>>();
+				let dupe_groups = |results: &Vec<imagehash::ImageHashAV>| results.iter().map( |i| (i.image_path.fpath.clone(), i.dupe_group) ).collect::<HashMap<PathBuf, u64>>();
+				(paths, dupe_groups( &colour_diff ), dupe_groups( &hamming ))
+			})
+		};
+		let (paths, colour_diff, hamming) = groups( 1 );
+		assert!( colour_diff.values().any( |g| *g != 0 ), "Duplicates found" );
+		assert_eq!( groups( 4 ), (paths, colour_diff, hamming), "Same results on more threads" );
+	}
+
+}
@@ -0,0 +1,194 @@
+use std::fs;
+use std::io::{BufRead, Cursor, Read, Seek};
+use std::path::Path;
+use std::sync::{Arc, Condvar, Mutex};
+use image::{ImageDecoder, ImageFormat, ImageReader, Limits};
+
+use crate::formats;
+use crate::frames::{self, FramePolicy};
+use crate::imagehash::ImagePath;
+use crate::image_error::MyImageError;
+use crate::jpeg::{self, JpegDecode};
+use crate::raw;
+
+/// Parse a memory size such as 512M or 4G. A number without a suffix is in megabytes.
+pub fn parse_size( size: &str ) -> Option<u64> {
+	let size = size.trim();
+	let (number, multiplier) = match size.chars().last()?.to_ascii_uppercase() {
+		'K' => (&size[..size.len()-1], 1u64 << 10),
+		'M' => (&size[..size.len()-1], 1 << 20),
+		'G' => (&size[..size.len()-1], 1 << 30),
+		'T' => (&size[..size.len()-1], 1 << 40),
+		_ => (size, 1 << 20),
+	};
+	number.trim().parse::<u64>().ok()?.checked_mul( multiplier ).filter( |bytes| *bytes > 0 )
+}
+
+/// Limits for the image decoders that refuse to allocate more than the memory limit, if there is one
+pub fn decoder_limits( memory_limit: Option<u64> ) -> Limits {
+	let mut limits = Limits::default();
+	if memory_limit.is_some() {
+		limits.max_alloc = memory_limit;
+	}
+	limits
+}
+
+/// The error for an image that is skipped as a decoder refused to allocate more than the memory limit
+pub fn limit_error( fpath: &Path ) -> MyImageError {
+	MyImageError::MemoryLimit(format!("Warning: Skipped image as decoding it would exceed the memory limit: {}", fpath.display()))
+}
+
+/// Read all of a file e.g. one inside an archive, failing with a MemoryLimit error rather than reading more than the memory limit
+pub fn read_to_limit( reader: impl Read, memory_limit: Option<u64>, fpath: &Path ) -> Result<Vec<u8>, MyImageError> {
+	let limit = memory_limit.unwrap_or( u64::MAX );
+	let mut data = Vec::new();
+	if reader.take( limit.saturating_add( 1 ) ).read_to_end( &mut data ).is_err() {
+		return Err(MyImageError::FileError(format!("Error: Failed to read image file: {}", fpath.display())));
+	}
+	if data.len() as u64 > limit {
+		return Err(MyImageError::MemoryLimit(format!("Warning: Skipped image as it is larger than the memory limit: {}", fpath.display())));
+	}
+	Ok(data)
+}
+
+/// Estimate the memory needed to hash an image from its header, without decoding it.
+/// Allows for the decoded pixels and a converted 8 bit copy of them, for each frame kept by the frame policy.
+/// Camera RAW files and images that may have several frames are read into memory to size their preview or count their frames.
+/// Returns None if the header can't be read.
+pub fn estimate_decoded_size( im_path: &ImagePath, frame_policy: FramePolicy ) -> Option<u64> {
+	let reader = ImageReader::open( &im_path.fpath ).ok()?.with_guessed_format().ok()?;
+	let may_have_frames = frame_policy != FramePolicy::First && matches!( reader.format(), Some(ImageFormat::Gif | ImageFormat::Png | ImageFormat::WebP | ImageFormat::Tiff) );
+	if raw::is_raw_file( &im_path.fpath ) || may_have_frames {
+		return estimate_decoded_size_of_data( &fs::read( &im_path.fpath ).ok()?, im_path, frame_policy );
+	}
+	estimate_from_reader( reader, im_path )
+}
+
+/// Estimate the memory needed to hash an image already read into memory e.g. from inside an archive
+pub fn estimate_decoded_size_of_data( data: &[u8], im_path: &ImagePath, frame_policy: FramePolicy ) -> Option<u64> {
+	//RAW files are decoded from their preview while the whole file is held in memory
+	if raw::is_raw_file( &im_path.fpath ) {
+		let encoded = raw::find_preview( data ).unwrap_or( data );
+		let decoded = estimate_from_reader( ImageReader::new( Cursor::new( encoded ) ).with_guessed_format().ok()?, im_path )?;
+		return Some(decoded + data.len() as u64);
+	}
+	let frame_size = estimate_from_reader( ImageReader::new( Cursor::new( data ) ).with_guessed_format().ok()?, im_path )?;
+	Some(frame_size * frames_kept( data, &im_path.fpath, frame_policy ) as u64)
+}
+
+/// Estimate the memory needed to hash the frames sampled from a video.
+/// Each frame is held as the PPM output by the decoder and decoded to RGB, with an 8 bit copy made while it is hashed.
+pub fn estimate_video_size( dimensions: (u32, u32), num_frames: usize ) -> u64 {
+	let num_pixels = dimensions.0 as u64 * dimensions.1 as u64;
+	num_pixels * 6 * num_frames as u64 + num_pixels * 4
+}
+
+/// The number of frames of an image kept under the frame policy, or 1 if the frames can't be counted
+fn frames_kept( data: &[u8], fpath: &Path, frame_policy: FramePolicy ) -> usize {
+	if frame_policy == FramePolicy::First {
+		return 1;
+	}
+	match frames::count_frames( data, fpath ) {
+		Some(num_frames) => frame_policy.frame_indexes( num_frames.min( frames::MAX_FRAMES ) ).len().max( 1 ),
+		None => 1,
+	}
+}
+
+fn estimate_from_reader<R: BufRead + Seek>( reader: ImageReader<R>, im_path: &ImagePath ) -> Option<u64> {
+	let decoder = formats::into_decoder( reader ).ok()?;
+	let (width, height) = decoder.dimensions();
+	let divisor = if jpeg::is_jpeg_path( &im_path.fpath ) && im_path.jpeg_decode != JpegDecode::Full { jpeg::scale_divisor( (width, height) ) as u64 } else { 1 };
+	let num_pixels = ( width as u64 ).div_ceil( divisor ) * ( height as u64 ).div_ceil( divisor );
+	Some(num_pixels * ( decoder.color_type().bytes_per_pixel() as u64 + 4 ))
+}
+
+/// Limits the total estimated size of the images being decoded at once. Threads wait for memory to be released before starting a decode that would exceed the budget.
+pub struct MemoryBudget {
+	/// Total bytes that may be reserved at once
+	limit : u64,
+	/// Bytes currently reserved
+	reserved : Mutex<u64>,
+	released : Condvar,
+}
+
+/// Memory reserved from a budget, returned to the budget when dropped
+pub struct Reservation {
+	budget : Arc<MemoryBudget>,
+	bytes : u64,
+}
+
+impl MemoryBudget {
+
+	pub fn new( limit: u64 ) -> Arc<MemoryBudget> {
+		Arc::new( MemoryBudget { limit, reserved: Mutex::new( 0 ), released: Condvar::new() } )
+	}
+
+	/// Reserve memory to decode an image, waiting until enough is free. Images larger than the whole budget are skipped with a MemoryLimit error.
+	pub fn reserve( self: &Arc<MemoryBudget>, bytes: u64, fpath: &Path ) -> Result<Reservation, MyImageError> {
+		if bytes > self.limit {
+			return Err(MyImageError::MemoryLimit(format!("Warning: Skipped image as decoding it needs about {}MB, more than the memory limit: {}", bytes.div_ceil( 1 << 20 ), fpath.display())));
+		}
+		let mut reserved = self.reserved.lock().unwrap();
+		while *reserved + bytes > self.limit {
+			reserved = self.released.wait( reserved ).unwrap();
+		}
+		*reserved += bytes;
+		Ok(Reservation { budget: Arc::clone( self ), bytes })
+	}
+}
+
+impl Drop for Reservation {
+	fn drop( &mut self ) {
+		*self.budget.reserved.lock().unwrap() -= self.bytes;
+		self.budget.released.notify_all();
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use std::sync::mpsc::channel;
+	use std::thread;
+	use std::time::Duration;
+
+	/// Test sizes are parsed, estimated from headers and reserved within the budget
+	#[test]
+	fn test_memory_budget() {
+		assert_eq!( parse_size( "512M" ), Some(512 << 20) );
+		assert_eq!( parse_size( "2g" ), Some(2 << 30) );
+		assert_eq!( parse_size( "100" ), Some(100 << 20) );
+		assert_eq!( parse_size( "0" ), None );
+		assert_eq!( parse_size( "lots" ), None );
+
+		//2048x1365 RGB, decoded at quarter scale
+		let book = ImagePath { fpath: "unit_test_images/book1_best.jpg".into(), ..Default::default() };
+		assert_eq!( estimate_decoded_size( &book, FramePolicy::All ), Some(512 * 342 * 7), "A still has one frame" );
+		assert_eq!( estimate_decoded_size( &ImagePath { jpeg_decode: JpegDecode::Full, ..book.clone() }, FramePolicy::First ), Some(2048 * 1365 * 7) );
+
+		//The decoders refuse to allocate more than the limit
+		assert!( matches!( crate::imagehash::load_image_from_file_with_limit( &book.fpath, Some(1 << 18) ), Err(MyImageError::MemoryLimit(_)) ) );
+		assert!( matches!( crate::imagehash::ImageHashAV::new( &ImagePath { memory_limit: Some(1 << 18), ..book.clone() }, 0, 0 ), Err(MyImageError::MemoryLimit(_)) ) );
+		assert!( crate::imagehash::ImageHashAV::new( &ImagePath { memory_limit: Some(64 << 20), ..book.clone() }, 0, 0 ).is_ok() );
+
+		//Reads stop at the limit
+		assert_eq!( read_to_limit( [ 0u8; 100 ].as_slice(), Some(100), Path::new( "a.jpg" ) ).unwrap().len(), 100 );
+		assert!( matches!( read_to_limit( [ 0u8; 100 ].as_slice(), Some(99), Path::new( "a.jpg" ) ), Err(MyImageError::MemoryLimit(_)) ) );
+
+		let budget = MemoryBudget::new( 100 );
+		assert!( matches!( budget.reserve( 101, Path::new( "huge.jpg" ) ), Err(MyImageError::MemoryLimit(_)) ), "Image larger than the budget skipped" );
+		let first = budget.reserve( 60, Path::new( "a.jpg" ) ).unwrap();
+
+		//A second large image waits until the first is released
+		let (tx, rx) = channel();
+		let waiting_budget = Arc::clone( &budget );
+		let waiting = thread::spawn( move || {
+			let _second = waiting_budget.reserve( 60, Path::new( "b.jpg" ) ).unwrap();
+			tx.send( () ).unwrap();
+		});
+		assert!( rx.recv_timeout( Duration::from_millis( 200 ) ).is_err(), "Second reservation waits" );
+		drop(first);
+		assert!( rx.recv_timeout( Duration::from_secs( 10 ) ).is_ok(), "Second reservation made once the first is released" );
+		waiting.join().unwrap();
+		assert_eq!( *budget.reserved.lock().unwrap(), 0 );
+	}
+}
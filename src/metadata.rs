@@ -0,0 +1,623 @@
+extern crate exif;
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io::Cursor;
+use std::path::{Path, PathBuf};
+use exif::{In, Tag, Value};
+
+use crate::image_error::MyImageError;
+use crate::archive;
+use crate::sidecar;
+
+/// The value of a single XMP property
+#[derive(Clone, Debug, PartialEq)]
+pub enum MetaValue {
+	/// A plain text property e.g. exif:DateTimeOriginal
+	Simple(String),
+	/// A language alternative, only the default language is kept e.g. dc:description
+	Alt(String),
+	/// An ordered list e.g. dc:creator
+	Seq(Vec<String>),
+	/// An unordered list e.g. dc:subject
+	Bag(Vec<String>),
+}
+
+/// Metadata fields read from an image, keyed by XMP property name (prefix:Name)
+#[derive(Default, Debug)]
+pub struct ImageMetadata {
+	/// The properties found in the image
+	pub fields : BTreeMap<String, MetaValue>,
+	/// Namespace URIs for each property prefix
+	pub namespaces : BTreeMap<String, String>,
+}
+
+/// How an EXIF tag is converted into an XMP property
+enum ExifKind {
+	Text,
+	Date,
+	Alt,
+	Seq,
+	Rational,
+	Integer,
+	GpsCoordinate(Tag),
+}
+
+/// EXIF tags that are carried across to the sidecar and the XMP property each maps to
+const EXIF_TO_XMP : [(Tag, &str, ExifKind); 17] = [
+	(Tag::DateTimeOriginal, "exif:DateTimeOriginal", ExifKind::Date),
+	(Tag::DateTimeDigitized, "exif:DateTimeDigitized", ExifKind::Date),
+	(Tag::Make, "tiff:Make", ExifKind::Text),
+	(Tag::Model, "tiff:Model", ExifKind::Text),
+	(Tag::LensModel, "exifEX:LensModel", ExifKind::Text),
+	(Tag::ImageDescription, "dc:description", ExifKind::Alt),
+	(Tag::Artist, "dc:creator", ExifKind::Seq),
+	(Tag::Copyright, "dc:rights", ExifKind::Alt),
+	(Tag::UserComment, "exif:UserComment", ExifKind::Alt),
+	(Tag::ExposureTime, "exif:ExposureTime", ExifKind::Rational),
+	(Tag::FNumber, "exif:FNumber", ExifKind::Rational),
+	(Tag::FocalLength, "exif:FocalLength", ExifKind::Rational),
+	(Tag::PhotographicSensitivity, "exif:ISOSpeedRatings", ExifKind::Integer),
+	(Tag::GPSLatitude, "exif:GPSLatitude", ExifKind::GpsCoordinate(Tag::GPSLatitudeRef)),
+	(Tag::GPSLongitude, "exif:GPSLongitude", ExifKind::GpsCoordinate(Tag::GPSLongitudeRef)),
+	(Tag::GPSAltitude, "exif:GPSAltitude", ExifKind::Rational),
+	(Tag::GPSAltitudeRef, "exif:GPSAltitudeRef", ExifKind::Integer),
+];
+
+/// Namespaces of the prefixes produced when converting EXIF fields
+const KNOWN_NAMESPACES : [(&str, &str); 6] = [
+	("dc", "http://purl.org/dc/elements/1.1/"),
+	("exif", "http://ns.adobe.com/exif/1.0/"),
+	("exifEX", "http://cipa.jp/exif/1.0/"),
+	("tiff", "http://ns.adobe.com/tiff/1.0/"),
+	("xmp", "http://ns.adobe.com/xap/1.0/"),
+	("photoshop", "http://ns.adobe.com/photoshop/1.0/"),
+];
+
+/// Properties that describe a particular encoding of the image rather than the photo itself, so are never copied between files.
+/// Matched on the name without the prefix as some tools write these under a non-standard namespace.
+const FILE_SPECIFIC_PROPERTIES : [&str; 17] = [ "Orientation", "ImageWidth", "ImageLength", "XResolution", "YResolution",
+	"ResolutionUnit", "YCbCrPositioning", "BitsPerSample", "Compression", "PixelXDimension", "PixelYDimension", "ColorSpace",
+	"ExifVersion", "FlashPixVersion", "format", "MetadataDate", "ICCProfile" ];
+
+/// Namespace prefixes whose properties are specific to a particular file or edit
+const FILE_SPECIFIC_PREFIXES : [&str; 4] = [ "xmpMM", "crs", "stEvt", "stRef" ];
+
+impl ImageMetadata {
+
+	/// Read the EXIF fields and any embedded XMP packet from an image file
+	pub fn read_from_file( fpath: &Path ) -> Result<ImageMetadata, MyImageError> {
+
+		let data = match archive::read_file( fpath, None ) {
+			Ok(data) => data,
+			Err(_) => {
+				return Err(MyImageError::FileError(format!("Error: Failed to read metadata from: {}", fpath.display())));
+			}
+		};
+
+		let mut metadata = ImageMetadata::default();
+
+		//Images without EXIF are common so a failure here is not an error
+		if let Ok(exif_data) = exif::Reader::new().read_from_container( &mut Cursor::new( &data ) ) {
+			metadata.add_exif( &exif_data );
+		}
+
+		if let Some(packet) = find_xmp_packet( &data ) {
+			metadata.add_xmp( &packet );
+		}
+
+		Ok(metadata)
+	}
+
+	/// Convert the EXIF fields of interest to XMP properties. Existing properties take precedence.
+	fn add_exif( &mut self, exif_data: &exif::Exif ) {
+		for (tag, name, kind) in EXIF_TO_XMP.iter() {
+			if self.fields.contains_key( *name ) {
+				continue;
+			}
+
+			if let Some(field) = exif_data.get_field( *tag, In::PRIMARY ) {
+				let converted = match kind {
+					ExifKind::Text => exif_ascii( &field.value ).map( MetaValue::Simple ),
+					ExifKind::Date => exif_ascii( &field.value ).and_then( |d| exif_date_to_xmp( &d ) ).map( MetaValue::Simple ),
+					ExifKind::Alt => exif_text( &field.value ).map( MetaValue::Alt ),
+					ExifKind::Seq => exif_ascii( &field.value ).map( |s| MetaValue::Seq( vec![s] ) ),
+					ExifKind::Rational => exif_rational( &field.value ).map( MetaValue::Simple ),
+					ExifKind::Integer => field.value.get_uint(0).map( |v| MetaValue::Simple( v.to_string() ) ),
+					ExifKind::GpsCoordinate(ref_tag) => {
+						let hemisphere = exif_data.get_field( *ref_tag, In::PRIMARY ).and_then( |f| exif_ascii( &f.value ) );
+						exif_gps_coordinate( &field.value, hemisphere ).map( MetaValue::Simple )
+					},
+				};
+
+				if let Some(value) = converted {
+					self.set_field( name, value );
+				}
+			}
+		}
+	}
+
+	/// Add the properties found in an XMP packet. Existing properties take precedence.
+	fn add_xmp( &mut self, packet: &str ) {
+		let (fields, namespaces) = parse_xmp( packet );
+		for (name, value) in fields {
+			self.fields.entry( name ).or_insert( value );
+		}
+		for (prefix, uri) in namespaces {
+			self.namespaces.entry( prefix ).or_insert( uri );
+		}
+	}
+
+	/// The time the photo was taken in seconds since 1970, ignoring any timezone. Taken from the EXIF or XMP DateTimeOriginal.
+	pub fn capture_time( &self ) -> Option<i64> {
+		match self.fields.get( "exif:DateTimeOriginal" ) {
+			Some(MetaValue::Simple(date)) => xmp_date_to_seconds( date ),
+			_ => None,
+		}
+	}
+
+	/// Set a property, registering the namespace if the prefix is a well known one
+	fn set_field( &mut self, name: &str, value: MetaValue ) {
+		if let Some(prefix) = property_prefix( name ) {
+			if let Some((_, uri)) = KNOWN_NAMESPACES.iter().find( |(p,_)| *p == prefix ) {
+				self.namespaces.entry( prefix.to_string() ).or_insert( uri.to_string() );
+			}
+		}
+		self.fields.insert( name.to_string(), value );
+	}
+
+	/// Returns the properties found in the duplicates that are missing from this (the best) image.
+	/// Where several duplicates have the same property, the first duplicate supplied is used.
+	pub fn missing_from( &self, duplicates: &[ImageMetadata] ) -> ImageMetadata {
+		let mut missing = ImageMetadata::default();
+		for dupe in duplicates {
+			for (name, value) in &dupe.fields {
+				if self.fields.contains_key( name ) || missing.fields.contains_key( name ) || is_file_specific( name ) {
+					continue;
+				}
+
+				//Properties are only usable if the namespace of the prefix is known
+				if let Some(prefix) = property_prefix( name ) {
+					if let Some(uri) = dupe.namespaces.get( prefix ) {
+						missing.namespaces.entry( prefix.to_string() ).or_insert( uri.clone() );
+						missing.fields.insert( name.clone(), value.clone() );
+					}
+				}
+			}
+		}
+		missing
+	}
+
+	/// Serialise the properties as an XMP sidecar document
+	pub fn to_xmp( &self ) -> String {
+		let mut xmp = String::new();
+		xmp.push_str( "<?xpacket begin=\"\u{feff}\" id=\"W5M0MpCehiHzreSzNTczkc9d\"?>\n" );
+		xmp.push_str( "<x:xmpmeta xmlns:x=\"adobe:ns:meta/\">\n" );
+		xmp.push_str( " <rdf:RDF xmlns:rdf=\"http://www.w3.org/1999/02/22-rdf-syntax-ns#\">\n" );
+		xmp.push_str( "  <rdf:Description rdf:about=\"\"" );
+		for (prefix, uri) in &self.namespaces {
+			xmp.push_str( &format!( "\n    xmlns:{}=\"{}\"", prefix, escape_xml( uri ) ) );
+		}
+		xmp.push_str( ">\n" );
+
+		for (name, value) in &self.fields {
+			match value {
+				MetaValue::Simple(text) => {
+					xmp.push_str( &format!( "   <{}>{}</{}>\n", name, escape_xml( text ), name ) );
+				},
+				MetaValue::Alt(text) => {
+					xmp.push_str( &format!( "   <{}>\n    <rdf:Alt>\n     <rdf:li xml:lang=\"x-default\">{}</rdf:li>\n    </rdf:Alt>\n   </{}>\n", name, escape_xml( text ), name ) );
+				},
+				MetaValue::Seq(items) | MetaValue::Bag(items) => {
+					let container = if let MetaValue::Seq(_) = value { "rdf:Seq" } else { "rdf:Bag" };
+					xmp.push_str( &format!( "   <{}>\n    <{}>\n", name, container ) );
+					for item in items {
+						xmp.push_str( &format!( "     <rdf:li>{}</rdf:li>\n", escape_xml( item ) ) );
+					}
+					xmp.push_str( &format!( "    </{}>\n   </{}>\n", container, name ) );
+				},
+			}
+		}
+
+		xmp.push_str( "  </rdf:Description>\n </rdf:RDF>\n</x:xmpmeta>\n<?xpacket end=\"w\"?>\n" );
+		xmp
+	}
+}
+
+/// The path of the XMP sidecar for an image e.g. photo.jpg has the sidecar photo.xmp.
+/// Where another image has the same name apart from its extension (e.g. photo.jpg and photo.cr2) the full name is used e.g. photo.jpg.xmp, so that they don't share a sidecar.
+pub fn sidecar_path( image_path: &Path ) -> PathBuf {
+	if sidecar::stem_is_shared( image_path ) {
+		let mut full_name = image_path.as_os_str().to_os_string();
+		full_name.push( ".xmp" );
+		PathBuf::from( full_name )
+	}else{
+		image_path.with_extension( "xmp" )
+	}
+}
+
+/// Write the metadata that the duplicates hold but the best image lacks into an XMP sidecar next to the best image.
+/// Returns the path written, or None if there was nothing to add. An existing sidecar is never overwritten.
+pub fn merge_into_sidecar( best_path: &Path, duplicate_paths: &[&Path] ) -> Result<Option<PathBuf>, MyImageError> {
+
+	//Archives are only read, a sidecar can't be written inside one
+	if archive::split_member_path( best_path ).is_some() {
+		return Err(MyImageError::FileError(format!("Warning: Not merging metadata as the image is inside an archive: {}", best_path.display())));
+	}
+
+	let best = ImageMetadata::read_from_file( best_path )?;
+	let mut duplicates = Vec::new();
+	for path in duplicate_paths {
+		//One unreadable duplicate shouldn't stop the metadata of the others being merged
+		match ImageMetadata::read_from_file( path ) {
+			Ok(metadata) => duplicates.push( metadata ),
+			Err(_) => eprintln!("Warning: Skipped merging metadata from unreadable duplicate: {}", path.display()),
+		}
+	}
+
+	let missing = best.missing_from( &duplicates );
+	if missing.fields.is_empty() {
+		return Ok(None);
+	}
+
+	let xmp_path = sidecar_path( best_path );
+	if xmp_path.exists() {
+		return Err(MyImageError::FileError(format!("Warning: Not merging metadata as sidecar already exists: {}", xmp_path.display())));
+	}
+
+	if fs::write( &xmp_path, missing.to_xmp() ).is_err() {
+		return Err(MyImageError::FileError(format!("Error: Failed to write metadata sidecar: {}", xmp_path.display())));
+	}
+
+	Ok(Some(xmp_path))
+}
+
+/// Is the property one that should not be copied from another version of the image
+fn is_file_specific( name: &str ) -> bool {
+	match name.split_once(':') {
+		Some((prefix, local_name)) => FILE_SPECIFIC_PREFIXES.contains( &prefix ) || FILE_SPECIFIC_PROPERTIES.contains( &local_name ),
+		None => true,
+	}
+}
+
+/// The namespace prefix of a property name e.g. "dc" for "dc:creator"
+fn property_prefix( name: &str ) -> Option<&str> {
+	name.split_once(':').map( |(prefix,_)| prefix )
+}
+
+/// The first string of an EXIF ASCII field
+fn exif_ascii( value: &Value ) -> Option<String> {
+	if let Value::Ascii(ref strings) = value {
+		if let Some(first) = strings.first() {
+			let text = String::from_utf8_lossy( first ).trim().to_string();
+			if !text.is_empty() {
+				return Some(text);
+			}
+		}
+	}
+	None
+}
+
+/// Text from either an ASCII field or a UserComment style field with an 8 byte character code prefix
+fn exif_text( value: &Value ) -> Option<String> {
+	if let Value::Undefined(ref bytes, _) = value {
+		//Only the ASCII character code is supported
+		if bytes.len() > 8 && bytes.starts_with( b"ASCII\0\0\0" ) {
+			let text = String::from_utf8_lossy( &bytes[8..] ).trim_end_matches('\0').trim().to_string();
+			if !text.is_empty() {
+				return Some(text);
+			}
+		}
+		return None;
+	}
+	exif_ascii( value )
+}
+
+/// An EXIF rational formatted in the XMP style e.g. "1/250"
+fn exif_rational( value: &Value ) -> Option<String> {
+	match value {
+		Value::Rational(ref r) => r.first().map( |r| format!("{}/{}", r.num, r.denom) ),
+		Value::SRational(ref r) => r.first().map( |r| format!("{}/{}", r.num, r.denom) ),
+		_ => None,
+	}
+}
+
+/// Convert an EXIF date "YYYY:MM:DD HH:MM:SS" to the XMP form "YYYY-MM-DDTHH:MM:SS"
+fn exif_date_to_xmp( date: &str ) -> Option<String> {
+	let (day, time) = date.split_once(' ')?;
+	let day_parts : Vec<&str> = day.split(':').collect();
+	if day_parts.len() != 3 || day_parts.iter().any( |p| p.parse::<u32>().is_err() ) {
+		return None;
+	}
+	Some(format!("{}-{}-{}T{}", day_parts[0], day_parts[1], day_parts[2], time))
+}
+
+/// Convert an XMP date "YYYY-MM-DDTHH:MM:SS" to seconds since 1970. Fractions of a second and timezones are ignored.
+fn xmp_date_to_seconds( date: &str ) -> Option<i64> {
+	let (day, time) = date.split_once('T')?;
+	let day_parts : Vec<i64> = day.split('-').map( |p| p.parse::<i64>() ).collect::<Result<_,_>>().ok()?;
+	let time_parts : Vec<i64> = time.get(..8)?.split(':').map( |p| p.parse::<i64>() ).collect::<Result<_,_>>().ok()?;
+	if day_parts.len() != 3 || time_parts.len() != 3 {
+		return None;
+	}
+
+	//Days since 1970 from the civil date (Howard Hinnant's algorithm)
+	let (year, month, day) = (day_parts[0], day_parts[1], day_parts[2]);
+	let shifted_year = if month <= 2 { year - 1 } else { year };
+	let era = shifted_year.div_euclid( 400 );
+	let year_of_era = shifted_year - era * 400;
+	let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+	let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+	let days = era * 146097 + day_of_era - 719468;
+
+	Some(days * 86400 + time_parts[0] * 3600 + time_parts[1] * 60 + time_parts[2])
+}
+
+/// Convert EXIF degrees, minutes and seconds to the XMP GPS coordinate form "DDD,MM.mmmmmmK"
+fn exif_gps_coordinate( value: &Value, hemisphere: Option<String> ) -> Option<String> {
+	if let Value::Rational(ref dms) = value {
+		if dms.len() == 3 && dms.iter().all( |r| r.denom != 0 ) {
+			let degrees = dms[0].to_f64().trunc();
+			let minutes = (dms[0].to_f64() - degrees) * 60.0 + dms[1].to_f64() + dms[2].to_f64() / 60.0;
+			return Some(format!("{},{:.6}{}", degrees as u32, minutes, hemisphere?));
+		}
+	}
+	None
+}
+
+/// Locate an XMP packet embedded in the raw bytes of a file
+fn find_xmp_packet( data: &[u8] ) -> Option<String> {
+	let start_tag = b"<x:xmpmeta";
+	let end_tag = b"</x:xmpmeta>";
+	let start = data.windows( start_tag.len() ).position( |w| w == start_tag )?;
+	let length = data[start..].windows( end_tag.len() ).position( |w| w == end_tag )?;
+	Some(String::from_utf8_lossy( &data[start..start+length+end_tag.len()] ).to_string())
+}
+
+/// A tag found while scanning XML
+struct XmlTag {
+	name : String,
+	attributes : Vec<(String,String)>,
+	is_end : bool,
+	is_empty : bool,
+}
+
+/// Parse the tag starting just after a '<'. Returns the tag and the number of bytes consumed.
+fn parse_xml_tag( text: &str ) -> Option<(XmlTag, usize)> {
+	let close = text.find('>')?;
+	let mut inner = &text[..close];
+	let is_end = inner.starts_with('/');
+	let is_empty = inner.ends_with('/');
+	inner = inner.trim_start_matches('/').trim_end_matches('/');
+
+	let name_end = inner.find( char::is_whitespace ).unwrap_or( inner.len() );
+	let name = inner[..name_end].to_string();
+
+	//Split the remainder into name="value" pairs, accepting either quote style
+	let mut attributes = Vec::new();
+	let mut rest = &inner[name_end..];
+	while let Some(eq) = rest.find('=') {
+		let attr_name = rest[..eq].trim().to_string();
+		let after = rest[eq+1..].trim_start();
+		let quote = after.chars().next()?;
+		if quote != '"' && quote != '\'' {
+			break;
+		}
+		let value_end = after[1..].find(quote)?;
+		attributes.push( (attr_name, unescape_xml( &after[1..value_end+1] )) );
+		rest = &after[value_end+2..];
+	}
+
+	Some((XmlTag{ name, attributes, is_end, is_empty }, close+1))
+}
+
+/// Extract simple properties and RDF containers from an XMP packet.
+/// Nested structures are not supported and are ignored.
+fn parse_xmp( packet: &str ) -> (BTreeMap<String,MetaValue>, BTreeMap<String,String>) {
+	let mut fields = BTreeMap::new();
+	let mut namespaces = BTreeMap::new();
+
+	//The property currently being read, the container type and the values found so far
+	let mut property : Option<(String, Option<String>, Vec<String>)> = None;
+	let mut depth_in_property = 0;
+	let mut text = String::new();
+	let mut rest = packet;
+
+	while let Some(lt) = rest.find('<') {
+		text.push_str( &rest[..lt] );
+		rest = &rest[lt+1..];
+
+		//Skip comments and processing instructions
+		if rest.starts_with("!--") || rest.starts_with('?') {
+			let terminator = if rest.starts_with('?') { "?>" } else { "-->" };
+			match rest.find( terminator ) {
+				Some(end) => { rest = &rest[end+terminator.len()..]; continue; },
+				None => break,
+			}
+		}
+
+		let (tag, consumed) = match parse_xml_tag( rest ) {
+			Some(parsed) => parsed,
+			None => break,
+		};
+		rest = &rest[consumed..];
+
+		for (attr_name, attr_value) in &tag.attributes {
+			if let Some(prefix) = attr_name.strip_prefix("xmlns:") {
+				namespaces.insert( prefix.to_string(), attr_value.clone() );
+			}
+		}
+
+		if tag.name == "rdf:Description" && !tag.is_end {
+			//Properties can be written as attributes of the description
+			for (attr_name, attr_value) in &tag.attributes {
+				if !attr_name.starts_with("xmlns:") && !attr_name.starts_with("rdf:") && attr_name.contains(':') {
+					fields.insert( attr_name.clone(), MetaValue::Simple( attr_value.clone() ) );
+				}
+			}
+		}else if let Some((ref name, ref mut container, ref mut values)) = property {
+			if tag.is_end && depth_in_property == 0 && tag.name == *name {
+				let value = match container.as_deref() {
+					Some("rdf:Alt") => values.first().cloned().map( MetaValue::Alt ),
+					Some("rdf:Seq") => Some(MetaValue::Seq( values.clone() )),
+					Some("rdf:Bag") => Some(MetaValue::Bag( values.clone() )),
+					Some(_) => None,
+					None => {
+						let simple = unescape_xml( text.trim() );
+						if simple.is_empty() { None } else { Some(MetaValue::Simple( simple )) }
+					}
+				};
+				if let Some(value) = value {
+					fields.insert( name.clone(), value );
+				}
+				property = None;
+			}else if tag.is_end {
+				if tag.name == "rdf:li" && !text.trim().is_empty() {
+					values.push( unescape_xml( text.trim() ) );
+				}
+				depth_in_property -= 1;
+			}else if !tag.is_empty {
+				if container.is_none() {
+					*container = Some( tag.name.clone() );
+				}
+				depth_in_property += 1;
+			}
+		}else if !tag.is_end && !tag.is_empty && tag.name.contains(':') && !tag.name.starts_with("rdf:") && !tag.name.starts_with("x:") {
+			property = Some( (tag.name.clone(), None, Vec::new()) );
+			depth_in_property = 0;
+		}
+
+		text.clear();
+	}
+
+	(fields, namespaces)
+}
+
+/// Escape text for inclusion in XML
+fn escape_xml( text: &str ) -> String {
+	text.replace('&', "&amp;").replace('<', "&lt;").replace('>', "&gt;").replace('"', "&quot;")
+}
+
+/// Replace the standard XML entities and numeric character references
+fn unescape_xml( text: &str ) -> String {
+	let mut output = String::new();
+	let mut rest = text;
+	while let Some(amp) = rest.find('&') {
+		output.push_str( &rest[..amp] );
+		rest = &rest[amp..];
+		let end = match rest.find(';') {
+			Some(end) => end,
+			None => break,
+		};
+		let entity = &rest[1..end];
+		let decoded = match entity {
+			"amp" => Some('&'),
+			"lt" => Some('<'),
+			"gt" => Some('>'),
+			"quot" => Some('"'),
+			"apos" => Some('\''),
+			_ if entity.starts_with("#x") => u32::from_str_radix( &entity[2..], 16 ).ok().and_then( char::from_u32 ),
+			_ if entity.starts_with('#') => entity[1..].parse::<u32>().ok().and_then( char::from_u32 ),
+			_ => None,
+		};
+		match decoded {
+			Some(c) => { output.push(c); rest = &rest[end+1..]; },
+			None => { output.push('&'); rest = &rest[1..]; },
+		}
+	}
+	output.push_str( rest );
+	output
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Test that XMP written as elements, attributes and containers is read correctly
+	#[test]
+	fn test_parse_xmp() {
+		let packet = "<x:xmpmeta xmlns:x='adobe:ns:meta/'><rdf:RDF xmlns:rdf='http://www.w3.org/1999/02/22-rdf-syntax-ns#'>
+			<rdf:Description rdf:about='' xmlns:xmp='http://ns.adobe.com/xap/1.0/' xmp:Rating='5'>
+			<xmp:Label>Book &amp; Pen</xmp:Label>
+			</rdf:Description>
+			<rdf:Description xmlns:dc='http://purl.org/dc/elements/1.1/'>
+			<dc:creator><rdf:Seq><rdf:li>Ann</rdf:li><rdf:li>Bob</rdf:li></rdf:Seq></dc:creator>
+			<dc:title><rdf:Alt><rdf:li xml:lang='x-default'>A title</rdf:li></rdf:Alt></dc:title>
+			</rdf:Description></rdf:RDF></x:xmpmeta>";
+
+		let (fields, namespaces) = parse_xmp( packet );
+		assert_eq!( fields.get("xmp:Rating"), Some(&MetaValue::Simple("5".to_string())), "Attribute property read" );
+		assert_eq!( fields.get("xmp:Label"), Some(&MetaValue::Simple("Book & Pen".to_string())), "Element property read and unescaped" );
+		assert_eq!( fields.get("dc:creator"), Some(&MetaValue::Seq(vec!["Ann".to_string(), "Bob".to_string()])), "Seq container read" );
+		assert_eq!( fields.get("dc:title"), Some(&MetaValue::Alt("A title".to_string())), "Alt container read" );
+		assert_eq!( namespaces.get("dc").map( |s| s.as_str() ), Some("http://purl.org/dc/elements/1.1/"), "Namespace recorded" );
+	}
+
+	/// Test that only metadata missing from the best image is taken from the duplicates
+	#[test]
+	fn test_missing_from() {
+		let best = ImageMetadata::read_from_file( Path::new( "unit_test_images/book4_best.jpg" ) ).unwrap();
+		let dupe = ImageMetadata::read_from_file( Path::new( "unit_test_images/book4_duplicate_1.jpg" ) ).unwrap();
+
+		assert!( dupe.fields.contains_key("exif:Artist"), "Duplicate has an artist in its XMP" );
+
+		let missing = best.missing_from( &[dupe] );
+		for name in missing.fields.keys() {
+			assert!( !best.fields.contains_key( name ), "Property already in the best image is not merged" );
+			assert!( !is_file_specific( name ), "File specific property is not merged" );
+		}
+		assert!( missing.fields.contains_key("exif:Artist"), "Artist is merged" );
+
+		//Resolution is read from XMP as tiff:XResolution
+		let mut resized = ImageMetadata::default();
+		resized.set_field( "tiff:XResolution", MetaValue::Simple("72/1".to_string()) );
+		resized.set_field( "dc:title", MetaValue::Alt("Bookshelf".to_string()) );
+		let missing = best.missing_from( &[resized] );
+		assert!( !missing.fields.contains_key("tiff:XResolution"), "Resolution is not merged" );
+		assert!( missing.fields.contains_key("dc:title"), "Title is merged" );
+	}
+
+	/// Test that a sidecar written from merged metadata can be read back
+	#[test]
+	fn test_xmp_round_trip() {
+		let mut metadata = ImageMetadata::default();
+		metadata.set_field( "exif:DateTimeOriginal", MetaValue::Simple("2020-12-29T14:40:07".to_string()) );
+		metadata.set_field( "dc:description", MetaValue::Alt("Sunset <over> the bay".to_string()) );
+		metadata.set_field( "dc:subject", MetaValue::Bag(vec!["sea".to_string(), "sky".to_string()]) );
+
+		let (fields, _) = parse_xmp( &metadata.to_xmp() );
+		assert_eq!( fields, metadata.fields, "Sidecar properties match those written" );
+	}
+
+	/// Test images with the same name apart from their extension are given sidecars of their own
+	#[test]
+	fn test_sidecar_path() {
+		let dir = std::env::temp_dir().join( "photodedupe_sidecar_path_test" );
+		let _ = fs::remove_dir_all( &dir );
+		fs::create_dir_all( &dir ).unwrap();
+		for name in [ "IMG_1.jpg", "IMG_1.cr2", "photo.jpg", "photo.xmp" ] {
+			fs::write( dir.join( name ), "" ).unwrap();
+		}
+
+		assert_eq!( sidecar_path( &dir.join( "photo.jpg" ) ), dir.join( "photo.xmp" ) );
+		assert_eq!( sidecar_path( &dir.join( "IMG_1.jpg" ) ), dir.join( "IMG_1.jpg.xmp" ), "Shares its name with a RAW file" );
+		assert_eq!( sidecar_path( &dir.join( "IMG_1.cr2" ) ), dir.join( "IMG_1.cr2.xmp" ) );
+
+		fs::write( dir.join( "IMG_1.jpg.xmp" ), "" ).unwrap();
+		assert_eq!( sidecar::SidecarFinder::default().find_sidecars( &dir.join( "IMG_1.jpg" ) ), vec![ dir.join( "IMG_1.jpg.xmp" ) ], "Found as the sidecar of the image" );
+		assert_eq!( sidecar_path( &dir.join( "IMG_1.jpg" ) ), dir.join( "IMG_1.jpg.xmp" ), "Sidecar doesn't count as another image" );
+		fs::remove_dir_all( &dir ).unwrap();
+	}
+
+	/// Test conversion of EXIF values to their XMP representations
+	#[test]
+	fn test_exif_conversion() {
+		assert_eq!( exif_date_to_xmp("2020:12:29 14:40:07"), Some("2020-12-29T14:40:07".to_string()) );
+		assert_eq!( exif_date_to_xmp("not a date"), None );
+		assert_eq!( xmp_date_to_seconds("1970-01-02T00:00:01"), Some(86401) );
+		assert_eq!( xmp_date_to_seconds("2020-12-29T14:40:07.55+01:00"), Some(1609252807) );
+
+		let latitude = Value::Rational(vec![ exif::Rational{ num: 51, denom: 1 }, exif::Rational{ num: 30, denom: 1 }, exif::Rational{ num: 30, denom: 1 } ]);
+		assert_eq!( exif_gps_coordinate( &latitude, Some("N".to_string()) ), Some("51,30.500000N".to_string()) );
+	}
+}
@@ -0,0 +1,117 @@
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use crate::imagehash::ConfigOptions;
+
+/// Convert bytes read as text (e.g. a line of stdin) into a path.
+/// On Unix any bytes are a valid path, elsewhere paths must be Unicode so invalid UTF-8 is replaced.
+pub fn path_from_bytes( bytes: Vec<u8> ) -> PathBuf {
+	#[cfg(unix)]
+	{
+		use std::os::unix::ffi::OsStringExt;
+		PathBuf::from( std::ffi::OsString::from_vec( bytes ) )
+	}
+	#[cfg(not(unix))]
+	{
+		PathBuf::from( String::from_utf8_lossy( &bytes ).into_owned() )
+	}
+}
+
+/// The bytes of a path, the reverse of path_from_bytes.
+/// On Unix these are the exact bytes of the path, elsewhere the path as UTF-8.
+pub fn path_to_bytes( path: &Path ) -> Vec<u8> {
+	#[cfg(unix)]
+	{
+		path.as_os_str().as_encoded_bytes().to_vec()
+	}
+	#[cfg(not(unix))]
+	{
+		path.to_string_lossy().into_owned().into_bytes()
+	}
+}
+
+/// Identifies a file regardless of the path used to reach it, so that hard links, symbolic links and bind mounts of the same file are recognised.
+/// The device and inode number of the file on Unix, elsewhere its canonical path.
+#[cfg(unix)]
+pub type FileIdentity = (u64, u64);
+#[cfg(not(unix))]
+pub type FileIdentity = PathBuf;
+
+/// The identity of the file at a path, or None if the file can't be read (e.g. images inside archives)
+pub fn file_identity( path: &Path ) -> Option<FileIdentity> {
+	#[cfg(unix)]
+	{
+		use std::os::unix::fs::MetadataExt;
+		let metadata = std::fs::metadata( path ).ok()?;
+		Some(( metadata.dev(), metadata.ino() ))
+	}
+	#[cfg(not(unix))]
+	{
+		std::fs::canonicalize( path ).ok()
+	}
+}
+
+/// Escape a path so that it is printable UTF-8 on one line. Bytes that aren't UTF-8 are written as \xNN, control characters as \n, \t, \r or \u{NN} and backslashes are doubled.
+pub fn escape_path( path: &Path ) -> String {
+	let mut escaped = String::new();
+	for chunk in path.as_os_str().as_encoded_bytes().utf8_chunks() {
+		for c in chunk.valid().chars() {
+			match c {
+				'\\' => escaped.push_str( "\\\\" ),
+				'\n' => escaped.push_str( "\\n" ),
+				'\t' => escaped.push_str( "\\t" ),
+				'\r' => escaped.push_str( "\\r" ),
+				c if c.is_control() => escaped.push_str( &format!("\\u{{{:x}}}", c as u32) ),
+				c => escaped.push( c ),
+			}
+		}
+		for byte in chunk.invalid() {
+			escaped.push_str( &format!("\\x{:02x}", byte) );
+		}
+	}
+	escaped
+}
+
+/// Write a line made of a prefix and a path, ended with the terminator (a newline or NUL). The path is written as its exact bytes unless escaping, so file names that aren't UTF-8 can be passed on to other programs unchanged.
+pub fn write_path_line( out: &mut impl Write, prefix: &str, path: &Path, escape: bool, terminator: u8 ) -> io::Result<()> {
+	out.write_all( prefix.as_bytes() )?;
+	if escape {
+		out.write_all( escape_path( path ).as_bytes() )?;
+	}else{
+		out.write_all( path.as_os_str().as_encoded_bytes() )?;
+	}
+	out.write_all( &[terminator] )
+}
+
+/// Print a line made of a prefix and a path to stdout, as println! would, escaped and terminated as configured
+pub fn print_path_line( prefix: &str, path: &Path, config: &ConfigOptions ) {
+	if let Err(e) = write_path_line( &mut io::stdout().lock(), prefix, path, config.escape_paths, config.path_terminator ) {
+		panic!("failed printing to stdout: {}", e);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Test paths are written unchanged or escaped, including file names that aren't UTF-8
+	#[test]
+	fn test_path_output() {
+		assert_eq!( escape_path( Path::new( "photos/café.jpg" ) ), "photos/café.jpg", "Unicode kept" );
+		assert_eq!( escape_path( Path::new( "a\\b\nc.jpg" ) ), "a\\\\b\\nc.jpg" );
+
+		#[cfg(unix)]
+		{
+			let latin1 = path_from_bytes( b"photos/caf\xe9.jpg".to_vec() );
+			assert_eq!( escape_path( &latin1 ), "photos/caf\\xe9.jpg", "Latin-1 byte escaped" );
+
+			let mut out = Vec::new();
+			write_path_line( &mut out, "\tDuplicate: ", &latin1, false, b'\n' ).unwrap();
+			assert_eq!( out, b"\tDuplicate: photos/caf\xe9.jpg\n", "Bytes written unchanged" );
+
+			let mut out = Vec::new();
+			write_path_line( &mut out, "", &path_from_bytes( b"IMG_1\n2.jpg".to_vec() ), false, b'\0' ).unwrap();
+			assert_eq!( out, b"IMG_1\n2.jpg\0", "Newline in name kept and path ended with NUL" );
+		}
+	}
+}
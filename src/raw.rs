@@ -0,0 +1,336 @@
+use std::collections::HashSet;
+use std::fs;
+use std::io::Cursor;
+use std::path::Path;
+use image::{DynamicImage, ImageError, ImageFormat, ImageReader};
+
+use crate::colour;
+use crate::image_error::MyImageError;
+use crate::memory;
+
+/// File extensions of camera RAW formats that contain a full size JPEG preview (or are a DNG that can be decoded directly)
+pub const RAW_EXTENSIONS : [&str; 9] = [ "cr2", "nef", "nrw", "arw", "srf", "sr2", "dng", "raf", "rw2" ];
+
+/// Maximum depth of nested IFDs followed, guards against malformed files
+const MAX_IFD_DEPTH : usize = 4;
+
+/// Is the file a camera RAW file according to its extension
+pub fn is_raw_file( fpath: &Path ) -> bool {
+	match fpath.extension().and_then( |e| e.to_str() ) {
+		Some(ext) => RAW_EXTENSIONS.contains( &ext.to_lowercase().as_str() ),
+		None => false,
+	}
+}
+
+/// Open a camera RAW file by decoding its largest embedded JPEG preview.
+/// DNG files without a JPEG preview are decoded directly as TIFF.
+/// Fails with a MemoryLimit error if decoding would allocate more than the limit in bytes.
+pub fn load_raw_file( fpath: &Path, memory_limit: Option<u64> ) -> Result<DynamicImage, MyImageError> {
+	let data = match fs::read( fpath ) {
+		Ok(data) => data,
+		Err(_) => return Err(MyImageError::FileError(format!("Error: Failed to read image file: {}", fpath.display()))),
+	};
+	load_raw_data( &data, fpath, memory_limit )
+}
+
+/// Decode a camera RAW file already read into memory, the path is used in error messages.
+/// The preview is converted to sRGB using its colour profile, as other images are.
+pub fn load_raw_data( data: &[u8], fpath: &Path, memory_limit: Option<u64> ) -> Result<DynamicImage, MyImageError> {
+	let (encoded, format, error) = match find_preview( data ) {
+		Some(preview) => (preview, ImageFormat::Jpeg, "Error: Failed to decode preview in RAW file"),
+		None => (data, ImageFormat::Tiff, "Error: No usable preview found in RAW file"),
+	};
+	match colour::decode_with_profile( ImageReader::with_format( Cursor::new( encoded ), format ), memory::decoder_limits( memory_limit ) ) {
+		Ok((img, icc_profile)) => Ok(colour::normalise( img, icc_profile.as_deref() )),
+		Err(ImageError::Limits(_)) => Err(memory::limit_error( fpath )),
+		Err(_) => Err(MyImageError::DecodeFail(format!("{}: {}", error, fpath.display()))),
+	}
+}
+
+/// Find the largest embedded JPEG preview in a RAW file. Returns the bytes of the JPEG.
+pub fn find_preview( data: &[u8] ) -> Option<&[u8]> {
+	let mut candidates : Vec<(usize, usize)> = Vec::new();
+
+	//Fujifilm RAF has a fixed header pointing to the JPEG
+	if data.starts_with( b"FUJIFILMCCD-RAW" ) && data.len() >= 92 {
+		let offset = u32::from_be_bytes( [data[84], data[85], data[86], data[87]] ) as usize;
+		let length = u32::from_be_bytes( [data[88], data[89], data[90], data[91]] ) as usize;
+		candidates.push( (offset, length) );
+	}else if let Some(tiff) = TiffReader::new( data ) {
+		let mut visited = HashSet::new();
+		tiff.collect_previews( tiff.first_ifd(), 0, &mut visited, &mut candidates );
+	}
+
+	//Pick the largest candidate that is a JPEG the image crate can decode (lossless JPEG is used for raw sensor data and is skipped)
+	let mut best : Option<(&[u8], u64)> = None;
+	for (offset, length) in candidates {
+		let end = match offset.checked_add( length ) {
+			Some(end) if end <= data.len() => end,
+			_ => continue,
+		};
+		let jpeg = &data[offset..end];
+		if let Some((width, height)) = jpeg_dimensions( jpeg ) {
+			let num_pixels = width as u64 * height as u64;
+			if best.is_none_or( |(_, best_pixels)| num_pixels > best_pixels ) {
+				best = Some((jpeg, num_pixels));
+			}
+		}
+	}
+	best.map( |(jpeg, _)| jpeg )
+}
+
+/// Read the dimensions from the frame header of a baseline or progressive JPEG. Returns None for other kinds of JPEG or invalid data.
+fn jpeg_dimensions( jpeg: &[u8] ) -> Option<(u16, u16)> {
+	if !jpeg.starts_with( &[0xFF, 0xD8] ) {
+		return None;
+	}
+	let mut i = 2;
+	while i + 4 <= jpeg.len() {
+		if jpeg[i] != 0xFF {
+			return None;
+		}
+		let marker = jpeg[i+1];
+		//Fill bytes and markers without a length
+		if marker == 0xFF {
+			i += 1;
+			continue;
+		}
+		if marker == 0x01 || (0xD0..=0xD7).contains( &marker ) {
+			i += 2;
+			continue;
+		}
+		let segment_length = u16::from_be_bytes( [jpeg[i+2], jpeg[i+3]] ) as usize;
+		match marker {
+			//Baseline, extended sequential and progressive
+			0xC0..=0xC2 => {
+				if i + 9 > jpeg.len() {
+					return None;
+				}
+				let height = u16::from_be_bytes( [jpeg[i+5], jpeg[i+6]] );
+				let width = u16::from_be_bytes( [jpeg[i+7], jpeg[i+8]] );
+				return Some((width, height));
+			},
+			//Other frame types e.g. lossless
+			0xC3 | 0xC5..=0xC7 | 0xC9..=0xCB | 0xCD..=0xCF => return None,
+			//Start of scan without a frame header
+			0xDA => return None,
+			_ => {},
+		}
+		i += 2 + segment_length;
+	}
+	None
+}
+
+/// Minimal reader for the TIFF structure used by most RAW formats
+struct TiffReader<'a> {
+	data : &'a [u8],
+	little_endian : bool,
+}
+
+impl<'a> TiffReader<'a> {
+
+	/// Check the TIFF header. Accepts the variants of the magic number used by Panasonic and Olympus as well as the standard one.
+	fn new( data: &'a [u8] ) -> Option<TiffReader<'a>> {
+		if data.len() < 8 {
+			return None;
+		}
+		let little_endian = match &data[0..2] {
+			b"II" => true,
+			b"MM" => false,
+			_ => return None,
+		};
+		let reader = TiffReader { data, little_endian };
+		match reader.u16_at( 2 )? {
+			42 | 0x55 | 0x4F52 | 0x5352 => Some(reader),
+			_ => None,
+		}
+	}
+
+	fn u16_at( &self, offset: usize ) -> Option<u16> {
+		let bytes = [ *self.data.get( offset )?, *self.data.get( offset + 1 )? ];
+		Some( if self.little_endian { u16::from_le_bytes( bytes ) } else { u16::from_be_bytes( bytes ) } )
+	}
+
+	fn u32_at( &self, offset: usize ) -> Option<u32> {
+		let bytes = [ *self.data.get( offset )?, *self.data.get( offset + 1 )?, *self.data.get( offset + 2 )?, *self.data.get( offset + 3 )? ];
+		Some( if self.little_endian { u32::from_le_bytes( bytes ) } else { u32::from_be_bytes( bytes ) } )
+	}
+
+	fn first_ifd( &self ) -> usize {
+		self.u32_at( 4 ).unwrap_or(0) as usize
+	}
+
+	/// The values of an IFD entry of SHORT, LONG or IFD type
+	fn entry_values( &self, entry: usize ) -> Vec<u32> {
+		let field_type = self.u16_at( entry + 2 ).unwrap_or(0);
+		let count = self.u32_at( entry + 4 ).unwrap_or(0) as usize;
+		let size = match field_type {
+			3 => 2,
+			4 | 13 => 4,
+			_ => return Vec::new(),
+		};
+		//The count is read from the file so may be corrupt
+		let values_offset = match count.checked_mul( size ) {
+			Some(length) if length <= 4 => entry + 8,
+			Some(_) => self.u32_at( entry + 8 ).unwrap_or(0) as usize,
+			None => return Vec::new(),
+		};
+		let mut values = Vec::new();
+		for n in 0..count.min(64) {
+			let value = if size == 2 { self.u16_at( values_offset + n * 2 ).map( |v| v as u32 ) } else { self.u32_at( values_offset + n * 4 ) };
+			match value {
+				Some(value) => values.push( value ),
+				None => break,
+			}
+		}
+		values
+	}
+
+	/// Collect the offset and length of every JPEG stored in an IFD, the IFDs that follow it and any sub IFDs
+	fn collect_previews( &self, ifd: usize, depth: usize, visited: &mut HashSet<usize>, candidates: &mut Vec<(usize, usize)> ) {
+		let mut ifd = ifd;
+		while ifd != 0 && depth <= MAX_IFD_DEPTH && visited.insert( ifd ) {
+			let num_entries = match self.u16_at( ifd ) {
+				Some(num_entries) => num_entries as usize,
+				None => return,
+			};
+
+			let mut jpeg_offset = None;
+			let mut jpeg_length = None;
+			let mut strip_offsets = Vec::new();
+			let mut strip_lengths = Vec::new();
+			for n in 0..num_entries {
+				let entry = ifd + 2 + n * 12;
+				match self.u16_at( entry ) {
+					//JPEGInterchangeFormat and JPEGInterchangeFormatLength
+					Some(0x0201) => jpeg_offset = self.entry_values( entry ).first().copied(),
+					Some(0x0202) => jpeg_length = self.entry_values( entry ).first().copied(),
+					//StripOffsets and StripByteCounts, a single strip may be a JPEG (e.g. the preview in CR2)
+					Some(0x0111) => strip_offsets = self.entry_values( entry ),
+					Some(0x0117) => strip_lengths = self.entry_values( entry ),
+					//Panasonic JpgFromRaw stored as undefined data
+					Some(0x002E) => {
+						let length = self.u32_at( entry + 4 ).unwrap_or(0);
+						let offset = self.u32_at( entry + 8 ).unwrap_or(0);
+						candidates.push( (offset as usize, length as usize) );
+					},
+					//SubIFDs e.g. the previews in NEF and DNG
+					Some(0x014A) => {
+						for sub_ifd in self.entry_values( entry ) {
+							self.collect_previews( sub_ifd as usize, depth + 1, visited, candidates );
+						}
+					},
+					_ => {},
+				}
+			}
+
+			if let (Some(offset), Some(length)) = (jpeg_offset, jpeg_length) {
+				candidates.push( (offset as usize, length as usize) );
+			}
+			if strip_offsets.len() == 1 && strip_lengths.len() == 1 {
+				candidates.push( (strip_offsets[0] as usize, strip_lengths[0] as usize) );
+			}
+
+			ifd = self.u32_at( ifd + 2 + num_entries * 12 ).unwrap_or(0) as usize;
+		}
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use image::codecs::jpeg::JpegEncoder;
+	use image::ImageEncoder;
+
+	/// Encode a test image as a JPEG of the given size
+	fn test_jpeg( width: u32, height: u32 ) -> Vec<u8> {
+		let img = crate::imagehash::load_image_from_file( Path::new( "unit_test_images/bridge1_best.jpg" ) ).unwrap();
+		let mut jpeg = Vec::new();
+		img.resize_exact( width, height, image::imageops::FilterType::Triangle ).write_to( &mut Cursor::new( &mut jpeg ), ImageFormat::Jpeg ).unwrap();
+		jpeg
+	}
+
+	/// Build a little endian TIFF based RAW with a small thumbnail referenced by IFD0 and a larger preview in a sub IFD
+	fn test_tiff_raw( thumbnail: &[u8], preview: &[u8] ) -> Vec<u8> {
+		let ifd0 = 8;
+		let sub_ifd = ifd0 + 2 + 3 * 12 + 4;
+		let thumbnail_offset = sub_ifd + 2 + 2 * 12 + 4;
+		let preview_offset = thumbnail_offset + thumbnail.len();
+
+		let mut data : Vec<u8> = Vec::new();
+		data.extend_from_slice( b"II" );
+		data.extend_from_slice( &42u16.to_le_bytes() );
+		data.extend_from_slice( &(ifd0 as u32).to_le_bytes() );
+
+		let entry = |data: &mut Vec<u8>, tag: u16, field_type: u16, value: u32| {
+			data.extend_from_slice( &tag.to_le_bytes() );
+			data.extend_from_slice( &field_type.to_le_bytes() );
+			data.extend_from_slice( &1u32.to_le_bytes() );
+			data.extend_from_slice( &value.to_le_bytes() );
+		};
+
+		data.extend_from_slice( &3u16.to_le_bytes() );
+		entry( &mut data, 0x014A, 4, sub_ifd as u32 );
+		entry( &mut data, 0x0201, 4, thumbnail_offset as u32 );
+		entry( &mut data, 0x0202, 4, thumbnail.len() as u32 );
+		data.extend_from_slice( &0u32.to_le_bytes() );
+
+		data.extend_from_slice( &2u16.to_le_bytes() );
+		entry( &mut data, 0x0111, 4, preview_offset as u32 );
+		entry( &mut data, 0x0117, 4, preview.len() as u32 );
+		data.extend_from_slice( &0u32.to_le_bytes() );
+
+		data.extend_from_slice( thumbnail );
+		data.extend_from_slice( preview );
+		data
+	}
+
+	/// Test the largest preview is found in a TIFF based RAW file
+	#[test]
+	fn test_tiff_preview() {
+		let thumbnail = test_jpeg( 160, 120 );
+		let preview = test_jpeg( 768, 576 );
+		let data = test_tiff_raw( &thumbnail, &preview );
+
+		let found = find_preview( &data ).unwrap();
+		assert_eq!( found, preview.as_slice(), "Largest preview found" );
+		assert_eq!( jpeg_dimensions( found ), Some((768, 576)) );
+
+		//The memory needed is estimated from the preview, and the preview is decoded within the memory limit
+		let im_path = crate::imagehash::ImagePath { fpath: "photo.dng".into(), ..Default::default() };
+		assert_eq!( memory::estimate_decoded_size_of_data( &data, &im_path, crate::frames::FramePolicy::First ), Some(768 * 576 * 7 + data.len() as u64) );
+		assert!( matches!( load_raw_data( &data, Path::new( "photo.dng" ), Some(1 << 16) ), Err(MyImageError::MemoryLimit(_)) ) );
+		assert!( load_raw_data( &data, Path::new( "photo.dng" ), Some(16 << 20) ).is_ok() );
+	}
+
+	/// Test the colour profile of a preview is applied, so it matches an sRGB export of the photo
+	#[test]
+	fn test_preview_colour_profile() {
+		let icc_profile = moxcms::ColorProfile::new_adobe_rgb().encode().unwrap();
+		let img = crate::imagehash::load_image_from_file( Path::new( "unit_test_images/bridge1_best.jpg" ) ).unwrap().resize_exact( 64, 48, image::imageops::FilterType::Triangle ).to_rgb8();
+		let mut preview = Vec::new();
+		let mut encoder = JpegEncoder::new( &mut preview );
+		encoder.set_icc_profile( icc_profile.clone() ).unwrap();
+		encoder.write_image( img.as_raw(), img.width(), img.height(), image::ExtendedColorType::Rgb8 ).unwrap();
+		let data = test_tiff_raw( &test_jpeg( 16, 12 ), &preview );
+
+		let decoded = load_raw_data( &data, Path::new( "photo.dng" ), None ).unwrap();
+		let unmanaged = image::load_from_memory_with_format( &preview, ImageFormat::Jpeg ).unwrap();
+		assert_ne!( decoded, unmanaged, "Profile applied" );
+		assert_eq!( decoded, colour::normalise( unmanaged, Some(&icc_profile) ) );
+	}
+
+	/// Test the preview is found in a Fujifilm RAF file
+	#[test]
+	fn test_raf_preview() {
+		let preview = test_jpeg( 300, 200 );
+		let mut data = vec![0u8; 100];
+		data[0..15].copy_from_slice( b"FUJIFILMCCD-RAW" );
+		data[84..88].copy_from_slice( &100u32.to_be_bytes() );
+		data[88..92].copy_from_slice( &(preview.len() as u32).to_be_bytes() );
+		data.extend_from_slice( &preview );
+
+		assert_eq!( find_preview( &data ), Some(preview.as_slice()) );
+		assert!( find_preview( b"not a raw file" ).is_none() );
+	}
+}
@@ -0,0 +1,150 @@
+use std::collections::HashMap;
+use std::fs;
+use std::path::{Path, PathBuf};
+
+/// File extensions of metadata files that accompany images e.g. Lightroom .xmp, Apple .aae, camera .thm and Google Takeout .json
+const SIDECAR_EXTENSIONS : [&str; 4] = [ "xmp", "aae", "thm", "json" ];
+
+/// Caches the contents of each directory so that directories are only listed once when searching for sidecars
+#[derive(Default)]
+pub struct SidecarFinder {
+	dir_listings : HashMap<PathBuf, Vec<String>>,
+}
+
+impl SidecarFinder {
+
+	/// Find the sidecar files belonging to an image. Returns the paths of the sidecars found.
+	/// Sidecars are matched by name, so are not found for images or sidecars with names that aren't UTF-8.
+	pub fn find_sidecars( &mut self, path: &Path ) -> Vec<PathBuf> {
+		let file_name = match path.file_name().and_then( |f| f.to_str() ) {
+			Some(file_name) => file_name,
+			None => return Vec::new(),
+		};
+		let dir = match path.parent() {
+			Some(parent) if !parent.as_os_str().is_empty() => parent.to_path_buf(),
+			_ => PathBuf::from("."),
+		};
+
+		let siblings = self.dir_listings.entry( dir ).or_insert_with_key( |d| list_dir( d ) );
+
+		let mut sidecars = Vec::new();
+		for sibling in siblings.iter() {
+			if is_sidecar_of( file_name, sibling, siblings ) {
+				sidecars.push( path.with_file_name( sibling ) );
+			}
+		}
+		sidecars.sort();
+		sidecars
+	}
+}
+
+/// The names of the files in a directory
+fn list_dir( dir: &Path ) -> Vec<String> {
+	let mut names = Vec::new();
+	if let Ok(entries) = fs::read_dir( dir ) {
+		for entry in entries.flatten() {
+			if let Some(name) = entry.file_name().to_str() {
+				names.push( name.to_string() );
+			}
+		}
+	}
+	names
+}
+
+/// Is the file a sidecar extension
+fn has_sidecar_extension( name: &str ) -> bool {
+	match name.rsplit_once('.') {
+		Some((_, ext)) => SIDECAR_EXTENSIONS.contains( &ext.to_lowercase().as_str() ),
+		None => false,
+	}
+}
+
+/// Test if a file in the same directory is a sidecar of an image. Sidecars are matched case insensitively on either:
+/// 1) The full image file name plus the sidecar extension e.g. IMG_1234.JPG.xmp or IMG_1234.jpg.json
+/// 2) Google Takeout supplemental metadata e.g. IMG_1234.jpg.supplemental-metadata.json
+/// 3) The image file name with the extension replaced e.g. IMG_1234.xmp or IMG_1234.AAE.
+///    Not used where another file shares the same name (e.g. IMG_1234.CR2 and IMG_1234.JPG) as it's ambiguous which file the sidecar belongs to.
+fn is_sidecar_of( image_name: &str, candidate: &str, siblings: &[String] ) -> bool {
+	if !has_sidecar_extension( candidate ) || candidate == image_name {
+		return false;
+	}
+
+	let image_lower = image_name.to_lowercase();
+	let candidate_lower = candidate.to_lowercase();
+
+	if let Some(rest) = candidate_lower.strip_prefix( &image_lower ) {
+		if rest == ".supplemental-metadata.json" {
+			return true;
+		}
+		if let Some(ext) = rest.strip_prefix('.') {
+			return SIDECAR_EXTENSIONS.contains( &ext );
+		}
+	}
+
+	let image_stem = match image_lower.rsplit_once('.') {
+		Some((stem, _)) => stem,
+		None => return false,
+	};
+	let candidate_stem = match candidate_lower.rsplit_once('.') {
+		Some((stem, _)) => stem,
+		None => return false,
+	};
+	if image_stem != candidate_stem {
+		return false;
+	}
+
+	!shares_stem( &image_lower, image_stem, siblings )
+}
+
+/// Whether another file, that isn't a sidecar, has the same name as an image apart from its extension e.g. IMG_1234.CR2 and IMG_1234.JPG
+fn shares_stem( image_lower: &str, image_stem: &str, siblings: &[String] ) -> bool {
+	siblings.iter().map( |sibling| sibling.to_lowercase() ).any( |sibling_lower| {
+		sibling_lower != image_lower && !has_sidecar_extension( &sibling_lower ) && sibling_lower.rsplit_once('.').is_some_and( |(sibling_stem, _)| sibling_stem == image_stem )
+	})
+}
+
+/// Whether a sidecar named by replacing the extension of the image (e.g. IMG_1234.xmp) would be ambiguous, as another file in the same directory has the same name apart from its extension
+pub fn stem_is_shared( path: &Path ) -> bool {
+	let image_lower = match path.file_name().and_then( |f| f.to_str() ) {
+		Some(file_name) => file_name.to_lowercase(),
+		None => return false,
+	};
+	let image_stem = match image_lower.rsplit_once('.') {
+		Some((stem, _)) => stem,
+		None => return false,
+	};
+	let dir = match path.parent() {
+		Some(parent) if !parent.as_os_str().is_empty() => parent,
+		_ => Path::new("."),
+	};
+	shares_stem( &image_lower, image_stem, &list_dir( dir ) )
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Test the naming conventions used by the common sidecar formats are recognised
+	#[test]
+	fn test_sidecar_naming() {
+		let siblings : Vec<String> = [ "IMG_1234.JPG", "IMG_1234.xmp", "IMG_1234.AAE", "IMG_1234.JPG.json", "photo.jpg", "photo.jpg.supplemental-metadata.json", "other.xmp" ]
+			.iter().map( |s| s.to_string() ).collect();
+
+		assert!( is_sidecar_of( "IMG_1234.JPG", "IMG_1234.xmp", &siblings ), "Lightroom style sidecar" );
+		assert!( is_sidecar_of( "IMG_1234.JPG", "IMG_1234.AAE", &siblings ), "Apple style sidecar" );
+		assert!( is_sidecar_of( "IMG_1234.JPG", "IMG_1234.JPG.json", &siblings ), "Google Takeout style sidecar" );
+		assert!( is_sidecar_of( "photo.jpg", "photo.jpg.supplemental-metadata.json", &siblings ), "Google Takeout supplemental metadata" );
+		assert!( !is_sidecar_of( "IMG_1234.JPG", "other.xmp", &siblings ), "Sidecar of another image" );
+		assert!( !is_sidecar_of( "IMG_1234.JPG", "photo.jpg", &siblings ), "Another image is not a sidecar" );
+	}
+
+	/// Test that a sidecar shared by two images with the same name is not associated with either
+	#[test]
+	fn test_ambiguous_sidecar() {
+		let siblings : Vec<String> = [ "IMG_1234.CR2", "IMG_1234.JPG", "IMG_1234.xmp", "IMG_1234.CR2.xmp" ].iter().map( |s| s.to_string() ).collect();
+
+		assert!( !is_sidecar_of( "IMG_1234.JPG", "IMG_1234.xmp", &siblings ), "Ambiguous sidecar not associated" );
+		assert!( is_sidecar_of( "IMG_1234.CR2", "IMG_1234.CR2.xmp", &siblings ), "Sidecar with full file name still associated" );
+		assert!( !is_sidecar_of( "IMG_1234.JPG", "IMG_1234.CR2.xmp", &siblings ), "Full file name sidecar of the other image not associated" );
+	}
+}
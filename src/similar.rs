@@ -0,0 +1,229 @@
+use std::path::{Path, PathBuf};
+use rayon::prelude::*;
+use image::DynamicImage;
+use image::imageops::FilterType;
+
+use crate::imagehash::{self, ImageHashAV, ConfigOptions};
+use crate::image_error::MyImageError;
+use crate::distance;
+use crate::metadata::ImageMetadata;
+
+/// Sharpness and exposure measurements used to choose the best shot from a set of similar shots
+pub struct ShotQuality {
+	/// Variance of the Laplacian of the greyscale image. Higher is sharper.
+	pub sharpness : f32,
+	/// Between 0 and 1. Penalises images that are too dark, too bright or have clipped shadows and highlights.
+	pub exposure : f32,
+}
+
+impl ShotQuality {
+	/// Combined score used to rank shots within a set
+	pub fn score( &self ) -> f32 {
+		self.sharpness * self.exposure
+	}
+}
+
+/// Test if two images are near-identical frames of the same scene e.g. shots from a burst.
+/// Uses looser thresholds than the duplicate check and optionally requires the capture times to be close.
+pub fn is_similar( a: &ImageHashAV, b: &ImageHashAV, capture_a: Option<i64>, capture_b: Option<i64>, config: &ConfigOptions ) -> bool {
+
+	//As with duplicates, low variation images can't be compared reliably
+	if a.std_dev <= config.std_dev_threshold || b.std_dev <= config.std_dev_threshold {
+		return false;
+	}
+
+	if let Some(window) = config.similar_time_window {
+		match (capture_a, capture_b) {
+			(Some(time_a), Some(time_b)) => {
+				if time_a.abs_diff( time_b ) > window {
+					return false;
+				}
+			},
+			_ => return false,
+		}
+	}
+
+	distance::hamming_distance( a.grey_hash, b.grey_hash ) <= config.similar_hash_distance &&
+		a.has_similar_aspect_ratio( b ) &&
+		a.diff_colour( b ) <= config.similar_colour_threshold
+}
+
+/// Group images into sets of similar shots. Returns sets of indexes into the supplied list, only sets with more than one image are returned.
+/// Videos are never part of a set as their sharpness and exposure can't be measured.
+pub fn find_similar_sets( images: &[&ImageHashAV], config: &ConfigOptions ) -> Vec<Vec<usize>> {
+
+	let stills : Vec<usize> = (0..images.len()).filter( |&i| !images[i].image_path.is_video ).collect();
+
+	//Capture times are only needed if windowing by time
+	let mut capture_times : Vec<Option<i64>> = vec![None; images.len()];
+	if config.similar_time_window.is_some() {
+		let still_times : Vec<Option<i64>> = stills.par_iter().map( |&i| ImageMetadata::read_from_file( &images[i].image_path.fpath ).ok().and_then( |m| m.capture_time() ) ).collect();
+		for (&i, time) in stills.iter().zip( still_times ) {
+			capture_times[i] = time;
+		}
+	}
+
+	//The pairwise comparisons are spread over the thread pool, each still finding the later stills similar to it
+	let similar_pairs : Vec<Vec<usize>> = (0..images.len()).into_par_iter().map( |i| {
+		if images[i].image_path.is_video {
+			return Vec::new();
+		}
+		((i+1)..images.len())
+			.filter( |&j| !images[j].image_path.is_video && is_similar( images[i], images[j], capture_times[i], capture_times[j], config ) )
+			.collect()
+	}).collect();
+
+	//Union-find so that chains of similar shots (A~B, B~C) form a single set
+	let mut parent : Vec<usize> = (0..images.len()).collect();
+	for (i, similar) in similar_pairs.iter().enumerate() {
+		for &j in similar {
+			let root_i = find_root( &mut parent, i );
+			let root_j = find_root( &mut parent, j );
+			if root_i != root_j {
+				parent[root_j.max(root_i)] = root_i.min(root_j);
+			}
+		}
+	}
+
+	let mut sets : Vec<Vec<usize>> = vec![Vec::new(); images.len()];
+	for i in 0..images.len() {
+		let root = find_root( &mut parent, i );
+		sets[root].push( i );
+	}
+	sets.retain( |s| s.len() > 1 );
+	sets
+}
+
+/// Find the representative of the set containing an element, compressing the path as it goes
+fn find_root( parent: &mut [usize], i: usize ) -> usize {
+	let mut root = i;
+	while parent[root] != root {
+		root = parent[root];
+	}
+	let mut current = i;
+	while parent[current] != root {
+		let next = parent[current];
+		parent[current] = root;
+		current = next;
+	}
+	root
+}
+
+/// Measure the sharpness and exposure of an image file. Fails with a MemoryLimit error if decoding would allocate more than the limit in bytes.
+pub fn measure_quality( fpath: &Path, memory_limit: Option<u64> ) -> Result<ShotQuality, MyImageError> {
+	let img = imagehash::load_image_from_file_with_limit( fpath, memory_limit )?;
+	match measure_image_quality( &img ) {
+		Some(quality) => Ok(quality),
+		None => Err(MyImageError::ImageTooSmall(format!("Warning: Image too small to measure sharpness: {}", fpath.display()))),
+	}
+}
+
+/// Measure the sharpness and exposure of a decoded image
+fn measure_image_quality( img: &DynamicImage ) -> Option<ShotQuality> {
+
+	//Measure at a fixed scale so that sharpness is comparable between images of different resolutions
+	let grey = img.resize( 512, 512, FilterType::Triangle ).to_luma8();
+	let (width, height) = grey.dimensions();
+	if width < 3 || height < 3 {
+		return None;
+	}
+
+	let mut laplacian_sum : f64 = 0.0;
+	let mut laplacian_square_sum : f64 = 0.0;
+	for y in 1..height-1 {
+		for x in 1..width-1 {
+			let centre = grey.get_pixel(x, y)[0] as f64;
+			let laplacian = grey.get_pixel(x-1, y)[0] as f64 + grey.get_pixel(x+1, y)[0] as f64 +
+				grey.get_pixel(x, y-1)[0] as f64 + grey.get_pixel(x, y+1)[0] as f64 - 4.0 * centre;
+			laplacian_sum += laplacian;
+			laplacian_square_sum += laplacian * laplacian;
+		}
+	}
+	let count = ((width-2) * (height-2)) as f64;
+	let laplacian_mean = laplacian_sum / count;
+	let sharpness = (laplacian_square_sum / count) - (laplacian_mean * laplacian_mean);
+
+	let mut total : u64 = 0;
+	let mut clipped : u64 = 0;
+	for pixel in grey.pixels() {
+		let p = pixel[0];
+		total += p as u64;
+		if p <= 2 || p >= 253 {
+			clipped += 1;
+		}
+	}
+	let num_pixels = (width * height) as f64;
+	let mean = total as f64 / (num_pixels * 255.0);
+	let exposure = (1.0 - 2.0 * (mean - 0.5).abs()) * (1.0 - clipped as f64 / num_pixels);
+
+	Some(ShotQuality { sharpness: sharpness as f32, exposure: exposure as f32 })
+}
+
+/// Measure the quality of each image on all threads. Images that fail to load or would exceed the memory limit are returned as errors.
+pub fn measure_quality_all( paths: &[PathBuf], config: &ConfigOptions ) -> Vec<Result<ShotQuality, MyImageError>> {
+	paths.par_iter().map( |path| measure_quality( path, config.memory_limit ) ).collect()
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+	use crate::imagehash::ImagePath;
+
+	/// Test that a sharp image scores higher than a blurred version and a well exposed image higher than a darkened version
+	#[test]
+	fn test_shot_quality() {
+		let img = imagehash::load_image_from_file( Path::new( "unit_test_images/bridge1_best.jpg" ) ).unwrap();
+		let original = measure_image_quality( &img ).unwrap();
+		let blurred = measure_image_quality( &img.blur( 3.0 ) ).unwrap();
+		let darkened = measure_image_quality( &img.brighten( -120 ) ).unwrap();
+
+		assert!( original.sharpness > blurred.sharpness, "Original is sharper than the blurred version" );
+		assert!( original.exposure > 0.0 && original.exposure <= 1.0, "Exposure between 0 and 1" );
+		assert!( original.exposure > darkened.exposure, "Original is better exposed than the darkened version" );
+	}
+
+	/// Test that true duplicates are also similar, but unrelated images are not
+	#[test]
+	fn test_similar_sets() {
+		let best = ImageHashAV::new( &ImagePath { fpath: "unit_test_images/cat1_best.jpg".into(), ..Default::default() },0,0 ).unwrap();
+		let dupe = ImageHashAV::new( &ImagePath { fpath: "unit_test_images/cat1_duplicate_1.jpg".into(), ..Default::default() },0,0 ).unwrap();
+		let other = ImageHashAV::new( &ImagePath { fpath: "unit_test_images/bridge1_best.jpg".into(), ..Default::default() },0,0 ).unwrap();
+
+		let mut config = crate::get_default_config_options();
+		let sets = find_similar_sets( &[&best, &other, &dupe], &config );
+		assert_eq!( sets, vec![vec![0, 2]], "Only the cat images are similar" );
+
+		//Without capture times no images can be within the time window
+		config.similar_time_window = Some(10);
+		let sets = find_similar_sets( &[&best, &other, &dupe], &config );
+		assert!( sets.is_empty(), "No capture times so no similar sets" );
+	}
+
+	/// Test capture times are read from images inside archives and videos are left out of sets
+	#[test]
+	fn test_similar_archive_and_video() {
+		use std::io::Write;
+		let dir = std::env::temp_dir().join( "photodedupe_similar_test" );
+		let _ = std::fs::remove_dir_all( &dir );
+		std::fs::create_dir_all( &dir ).unwrap();
+		let zip_path = dir.join( "burst.zip" );
+		let mut zip = zip::ZipWriter::new( std::fs::File::create( &zip_path ).unwrap() );
+		for (member, source) in [ ("a.jpg", "unit_test_images/bridge1_best.jpg"), ("b.jpg", "unit_test_images/bridge1_duplicate_1.jpg") ] {
+			zip.start_file( member, zip::write::SimpleFileOptions::default() ).unwrap();
+			zip.write_all( &std::fs::read( source ).unwrap() ).unwrap();
+		}
+		zip.finish().unwrap();
+
+		let hash = |fpath: PathBuf, is_video: bool| ImageHashAV::new( &ImagePath { fpath, is_video, ..Default::default() }, 0, 0 ).unwrap();
+		let a = hash( crate::archive::member_path( &zip_path, "a.jpg" ), false );
+		let b = hash( crate::archive::member_path( &zip_path, "b.jpg" ), false );
+		let clip = hash( "unit_test_images/bridge1_duplicate_2.jpg".into(), true );
+
+		let mut config = crate::get_default_config_options();
+		assert_eq!( find_similar_sets( &[&a, &clip, &b], &config ), vec![vec![0, 2]], "Video not in the set" );
+		config.similar_time_window = Some(10);
+		assert_eq!( find_similar_sets( &[&a, &clip, &b], &config ), vec![vec![0, 2]], "Archived images within the time window" );
+
+		std::fs::remove_dir_all( &dir ).unwrap();
+	}
+}
@@ -0,0 +1,262 @@
+use std::fs;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::Arc;
+use image::{DynamicImage, ImageFormat};
+
+use crate::frames::FramePolicy;
+use crate::imagehash::{ImageHashAV, ImagePath, ConfigOptions};
+use crate::image_error::MyImageError;
+use crate::memory::{self, MemoryBudget};
+
+/// File extensions of videos that can be compared with --videos
+pub const VIDEO_EXTENSIONS : [&str; 3] = [ "mp4", "mov", "m4v" ];
+
+/// Number of frames sampled from each video
+pub const VIDEO_SAMPLE_FRAMES : usize = 5;
+
+/// Is the file a video according to its extension
+pub fn is_video_file( fpath: &Path ) -> bool {
+	match fpath.extension().and_then( |e| e.to_str() ) {
+		Some(ext) => VIDEO_EXTENSIONS.contains( &ext.to_lowercase().as_str() ),
+		None => false,
+	}
+}
+
+/// What is known about a video before its frames are decoded
+pub struct VideoInfo {
+	/// Length of the video in seconds
+	pub duration : f64,
+	/// Width and height of the frames
+	pub dimensions : (u32, u32),
+}
+
+/// Extracts frames from video files. To support another way of decoding video, implement this trait and return it from find_decoder.
+pub trait VideoDecoder : Send + Sync {
+	/// Read the duration and frame size of a video, used to reserve memory for the frames before they are decoded
+	fn probe( &self, fpath: &Path ) -> Result<VideoInfo, MyImageError>;
+	/// Decode up to num_frames frames spread evenly through the video
+	fn extract_frames( &self, fpath: &Path, info: &VideoInfo, num_frames: usize ) -> Result<Vec<DynamicImage>, MyImageError>;
+}
+
+/// Find a decoder for videos, returns None if none is available e.g. ffmpeg is not installed
+pub fn find_decoder( config: &ConfigOptions ) -> Option<Arc<dyn VideoDecoder>> {
+	let binary = config.ffmpeg_path.as_deref().unwrap_or( "ffmpeg" );
+	FfmpegDecoder::new( binary ).map( |d| Arc::new( d ) as Arc<dyn VideoDecoder> )
+}
+
+/// Calculate the statistics of a video from frames sampled evenly through it.
+/// The sampled frames are held in memory together, so memory for all of them is reserved before they are decoded if there is a memory budget.
+pub fn hash_video( decoder: &dyn VideoDecoder, im_path: &ImagePath, min_width: u32, min_height: u32, memory_budget: Option<&Arc<MemoryBudget>> ) -> Result<ImageHashAV, MyImageError> {
+	let file_size = match fs::metadata( &im_path.fpath ) {
+		Ok(md) => md.len(),
+		Err(_) => return Err(MyImageError::FileError(format!("Error: Failed to get size of: {}", im_path.fpath.display()))),
+	};
+	let info = decoder.probe( &im_path.fpath )?;
+	let _reservation = match memory_budget.map( |budget| budget.reserve( memory::estimate_video_size( info.dimensions, VIDEO_SAMPLE_FRAMES ), &im_path.fpath ) ) {
+		Some(Err(e)) => return Err(e),
+		reservation => reservation,
+	};
+	let frames = decoder.extract_frames( &im_path.fpath, &info, VIDEO_SAMPLE_FRAMES )?;
+	if frames.is_empty() {
+		return Err(MyImageError::DecodeFail(format!("Error: Failed to extract any frames from video: {}", im_path.fpath.display())));
+	}
+	let num_frames = frames.len();
+	ImageHashAV::from_frames( Box::new( frames.into_iter().map( Ok ) ), Some(num_frames), im_path, file_size, min_width, min_height, FramePolicy::All )
+}
+
+/// Decodes videos by running an external ffmpeg binary, which outputs each frame as a PPM image on stdout
+pub struct FfmpegDecoder {
+	binary : String,
+}
+
+impl FfmpegDecoder {
+
+	/// Use the ffmpeg binary at the path (or found on the PATH), returns None if it can't be run
+	pub fn new( binary: &str ) -> Option<FfmpegDecoder> {
+		let status = Command::new( binary ).arg( "-version" ).stdout( Stdio::null() ).stderr( Stdio::null() ).status().ok()?;
+		if status.success() { Some(FfmpegDecoder { binary: binary.to_string() }) } else { None }
+	}
+
+	/// Decode num_frames frames, the first at start seconds and then one every interval seconds, in a single run of ffmpeg
+	fn extract_frames_at( &self, fpath: &Path, start: f64, interval: f64, num_frames: usize ) -> Option<Vec<DynamicImage>> {
+		//Seeking before the input is fast as it jumps to the nearest keyframe. The fps filter then picks the frame nearest each sample time.
+		let output = Command::new( &self.binary )
+			.args( [ "-v", "error", "-ss", &format!("{:.3}", start), "-i" ] ).arg( fpath )
+			.args( [ "-vf", &format!("fps=1/{:.6}", interval), "-frames:v", &num_frames.to_string(), "-f", "image2pipe", "-c:v", "ppm", "-" ] )
+			.stdin( Stdio::null() )
+			.output().ok()?;
+		if !output.status.success() {
+			return None;
+		}
+		Some(split_ppm_frames( &output.stdout ).into_iter()
+			.filter_map( |frame| image::load_from_memory_with_format( frame, ImageFormat::Pnm ).ok() )
+			.collect())
+	}
+}
+
+impl VideoDecoder for FfmpegDecoder {
+
+	/// ffmpeg reports the duration and frame size when given an input without an output
+	fn probe( &self, fpath: &Path ) -> Result<VideoInfo, MyImageError> {
+		let output = match Command::new( &self.binary ).args( [ "-hide_banner", "-i" ] ).arg( fpath ).stdin( Stdio::null() ).output() {
+			Ok(output) => String::from_utf8_lossy( &output.stderr ).to_string(),
+			Err(_) => return Err(MyImageError::FileError(format!("Error: Failed to run ffmpeg on video: {}", fpath.display()))),
+		};
+		let duration = match parse_duration( &output ) {
+			Some(duration) => duration,
+			None => return Err(MyImageError::DecodeFail(format!("Error: Failed to read duration of video: {}", fpath.display()))),
+		};
+		match parse_dimensions( &output ) {
+			Some(dimensions) => Ok(VideoInfo { duration, dimensions }),
+			None => Err(MyImageError::DecodeFail(format!("Error: Failed to read frame size of video: {}", fpath.display()))),
+		}
+	}
+
+	fn extract_frames( &self, fpath: &Path, info: &VideoInfo, num_frames: usize ) -> Result<Vec<DynamicImage>, MyImageError> {
+		if info.duration <= 0.0 || num_frames == 0 {
+			return Ok(Vec::new());
+		}
+
+		//Sample from the middle of each equal length section of the video, avoiding black first and last frames
+		let interval = info.duration / num_frames as f64;
+		match self.extract_frames_at( fpath, interval / 2.0, interval, num_frames ) {
+			Some(frames) => Ok(frames),
+			None => Err(MyImageError::DecodeFail(format!("Error: Failed to extract frames from video: {}", fpath.display()))),
+		}
+	}
+}
+
+/// Find the duration in seconds in ffmpeg output e.g. "  Duration: 00:01:02.50, start: 0.000000, bitrate: 9000 kb/s"
+fn parse_duration( output: &str ) -> Option<f64> {
+	let start = output.find( "Duration: " )? + "Duration: ".len();
+	let time = output[start..].split( ',' ).next()?.trim();
+	let mut seconds = 0.0;
+	for part in time.split( ':' ) {
+		seconds = seconds * 60.0 + part.parse::<f64>().ok()?;
+	}
+	Some(seconds)
+}
+
+/// Find the frame size of the video stream in ffmpeg output e.g. "  Stream #0:0(und): Video: h264 (High) (avc1 / 0x31637661), yuv420p, 1920x1080 [SAR 1:1 DAR 16:9], 30 fps"
+fn parse_dimensions( output: &str ) -> Option<(u32, u32)> {
+	let stream = output.lines().find( |line| line.contains( "Video: " ) )?;
+	stream.split( [ ' ', ',' ] ).find_map( |word| {
+		let (width, height) = word.split_once( 'x' )?;
+		Some((width.parse::<u32>().ok()?, height.parse::<u32>().ok()?)).filter( |(width, height)| *width > 0 && *height > 0 )
+	})
+}
+
+/// Split the PPM images ffmpeg writes one after another on stdout. Each is a "P6" header giving the width, height and maximum value followed by the pixels.
+fn split_ppm_frames( data: &[u8] ) -> Vec<&[u8]> {
+	let mut frames = Vec::new();
+	let mut rest = data;
+	while rest.starts_with( b"P6" ) {
+		//Read the three numbers in the header, each preceded by whitespace
+		let mut pos = 2;
+		let mut fields = [0usize; 3];
+		for field in fields.iter_mut() {
+			while pos < rest.len() && rest[pos].is_ascii_whitespace() {
+				pos += 1;
+			}
+			let digits_start = pos;
+			while pos < rest.len() && rest[pos].is_ascii_digit() {
+				pos += 1;
+			}
+			match std::str::from_utf8( &rest[digits_start..pos] ).ok().and_then( |d| d.parse().ok() ) {
+				Some(value) => *field = value,
+				None => return frames,
+			}
+		}
+		//A single whitespace character separates the header from the pixels
+		let [width, height, max_value] = fields;
+		let bytes_per_sample = if max_value > 255 { 2 } else { 1 };
+		let end = width.checked_mul( height ).and_then( |p| p.checked_mul( 3 * bytes_per_sample ) ).and_then( |len| len.checked_add( pos + 1 ) );
+		match end {
+			Some(end) if end <= rest.len() => {
+				frames.push( &rest[..end] );
+				rest = &rest[end..];
+			},
+			_ => break,
+		}
+	}
+	frames
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Stands in for a video by returning test images scaled to the same size as its frames
+	struct TestDecoder {
+		frame_paths : Vec<&'static str>,
+		size : (u32, u32),
+	}
+
+	impl VideoDecoder for TestDecoder {
+		fn probe( &self, _fpath: &Path ) -> Result<VideoInfo, MyImageError> {
+			Ok(VideoInfo { duration: self.frame_paths.len() as f64, dimensions: self.size })
+		}
+
+		fn extract_frames( &self, _fpath: &Path, _info: &VideoInfo, num_frames: usize ) -> Result<Vec<DynamicImage>, MyImageError> {
+			self.frame_paths.iter().take( num_frames )
+				.map( |p| crate::imagehash::load_image_from_file( Path::new( p ) ).map( |img| img.resize_exact( self.size.0, self.size.1, image::imageops::FilterType::Triangle ) ) )
+				.collect()
+		}
+	}
+
+	/// Test the duration and frame size are read from ffmpeg output
+	#[test]
+	fn test_parse_duration() {
+		let output = "Input #0, mov,mp4, from 'a.mov':\n  Duration: 00:01:02.50, start: 0.000000, bitrate: 9000 kb/s\n  Stream #0:0[0x1](und): Video: h264 (High) (avc1 / 0x31637661), yuv420p(tv, bt709, progressive), 1920x1080 [SAR 1:1 DAR 16:9], 8998 kb/s, 30 fps";
+		assert_eq!( parse_duration( output ), Some(62.5) );
+		assert_eq!( parse_dimensions( output ), Some((1920, 1080)) );
+		assert_eq!( parse_duration( "a.mov: No such file or directory" ), None );
+		assert_eq!( parse_dimensions( "a.mov: No such file or directory" ), None );
+	}
+
+	/// Test concatenated PPM frames are split apart, ignoring a truncated last frame
+	#[test]
+	fn test_split_ppm_frames() {
+		//Frames in the same form ffmpeg writes them
+		let mut data = Vec::new();
+		for (width, height) in [ (3, 2), (1, 4) ] {
+			data.extend_from_slice( format!("P6\n{} {}\n255\n", width, height).as_bytes() );
+			data.extend( std::iter::repeat_n( 128u8, width * height * 3 ) );
+		}
+		let whole_len = data.len();
+		data.extend_from_slice( b"P6\n640 480\n255\n\0\0\0" );
+
+		let frames = split_ppm_frames( &data );
+		assert_eq!( frames.len(), 2 );
+		assert_eq!( frames[0].len() + frames[1].len(), whole_len );
+		let second = image::load_from_memory_with_format( frames[1], ImageFormat::Pnm ).unwrap();
+		assert_eq!( (second.width(), second.height()), (1, 4) );
+		assert!( split_ppm_frames( b"" ).is_empty() );
+	}
+
+	/// Test stills are matched to videos containing them, and videos to videos with the same frames
+	#[test]
+	fn test_video_matching() {
+		let config = crate::get_default_config_options();
+		let path = |fpath: &str, is_video: bool| ImagePath { fpath: fpath.into(), is_video, ..Default::default() };
+		let clip = TestDecoder { frame_paths: vec![ "unit_test_images/bridge1_best.jpg", "unit_test_images/cat1_best.jpg", "unit_test_images/car1_best.jpg" ], size: (640, 480) };
+
+		let video = hash_video( &clip, &path( "unit_test_images/cat1_best.jpg", true ), 0, 0, None ).unwrap();
+		let copy = hash_video( &clip, &path( "unit_test_images/cat1_duplicate_1.jpg", true ), 0, 0, Some(&MemoryBudget::new( 64 << 20 )) ).unwrap();
+		let still_img = crate::imagehash::load_image_from_file( Path::new( "unit_test_images/cat1_duplicate_1.jpg" ) ).unwrap().resize_exact( 320, 240, image::imageops::FilterType::Triangle );
+		let still = ImageHashAV::from_image( &still_img, &path( "still.jpg", false ), 0, 0, 0 ).unwrap();
+		let other_img = crate::imagehash::load_image_from_file( Path::new( "unit_test_images/book1_best.jpg" ) ).unwrap().resize_exact( 320, 240, image::imageops::FilterType::Triangle );
+		let other_still = ImageHashAV::from_image( &other_img, &path( "other.jpg", false ), 0, 0, 0 ).unwrap();
+
+		assert_eq!( video.frames.len(), 2 );
+		assert!( still.is_dupe( &video, &config ), "Still is a frame of the video" );
+		assert!( video.is_dupe( &still, &config ) );
+		assert!( !other_still.is_dupe( &video, &config ), "Still is not in the video" );
+		assert!( video.is_dupe( &copy, &config ), "Videos with the same frames are duplicates" );
+
+		//Memory for the sampled frames is reserved before they are decoded
+		let over_budget = hash_video( &clip, &path( "unit_test_images/cat1_best.jpg", true ), 0, 0, Some(&MemoryBudget::new( 4 << 20 )) );
+		assert!( matches!( over_budget, Err(MyImageError::MemoryLimit(_)) ), "Video skipped when its frames need more than the memory limit" );
+	}
+}
@@ -0,0 +1,323 @@
+use std::cmp::Ordering;
+use std::collections::HashMap;
+use std::fs;
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Arc;
+use std::sync::mpsc::{self, RecvTimeoutError};
+use std::time::{Duration, Instant};
+use notify::event::ModifyKind;
+use notify::{EventKind, RecursiveMode, Watcher};
+use rayon::prelude::*;
+use walkdir::WalkDir;
+
+use crate::imagehash::{ConfigOptions, ImageHashAV, ImagePath};
+use crate::memory::{self, MemoryBudget};
+use crate::paths;
+use crate::raw;
+
+/// How often files waiting to stop growing are checked
+const POLL_INTERVAL : Duration = Duration::from_millis(250);
+
+/// Options for watching an inbox directory for new images
+pub struct WatchOptions {
+	/// Print a JSON object on one line for each new image instead of text
+	pub json : bool,
+	/// Directory that new images found to be duplicates are moved into
+	pub quarantine : Option<PathBuf>,
+	/// How long the size of a new file must stay the same before it is read
+	pub settle_time : Duration,
+}
+
+/// Whether a new image is already in the collection
+#[derive(Debug, PartialEq)]
+pub enum Outcome {
+	/// A duplicate of the image in the collection at the path given
+	Duplicate(PathBuf),
+	/// Not in the collection, or a better version of an image in the collection
+	Unique,
+}
+
+/// The statistics of the images in the collection kept in memory, including the new images found to be unique
+pub struct Signatures {
+	images : Vec<ImageHashAV>,
+}
+
+impl Signatures {
+
+	/// Create the set of images in the collection
+	pub fn new( mut images: Vec<ImageHashAV> ) -> Signatures {
+		for image in images.iter_mut() {
+			image.image_path.collection = 0;
+			image.image_path.always_mark_dupe_compare = false;
+		}
+		Signatures { images }
+	}
+
+	/// Number of images in the collection
+	pub fn len( &self ) -> usize {
+		self.images.len()
+	}
+
+	/// Compare a new image with the collection, as with --compare. A new image of higher resolution than the best version in the collection is unique, unless using --ignore-resolution.
+	/// Unique images are added to the collection, so that later copies of them are duplicates. An image already added from the same path (e.g. overwritten since) is replaced, so it isn't a duplicate of itself.
+	pub fn check( &mut self, mut image: ImageHashAV, config: &ConfigOptions ) -> Outcome {
+		self.images.retain( |i| i.image_path.fpath != image.image_path.fpath );
+		image.image_path.collection = 1;
+		image.image_path.always_mark_dupe_compare = config.always_mark_duplicates;
+
+		//The best version of the image in the collection, sorted as in the output of the main command
+		let best = self.images.par_iter().filter( |i| i.is_dupe( &image, config ) ).min();
+		match best {
+			Some(best) if image.cmp( best ) != Ordering::Less => Outcome::Duplicate( best.image_path.fpath.clone() ),
+			_ => {
+				image.image_path.collection = 0;
+				image.image_path.always_mark_dupe_compare = false;
+				self.images.push( image );
+				Outcome::Unique
+			}
+		}
+	}
+}
+
+/// The result of checking a new image
+struct Report {
+	fpath : PathBuf,
+	/// Whether the image is a duplicate, or the error reading it
+	outcome : Result<Outcome, String>,
+	/// Where the image was moved to if quarantined
+	moved_to : Option<PathBuf>,
+}
+
+/// Watch the inbox directory for new images, reporting whether each is a duplicate of the collection on stdout once it has stopped growing. Runs until interrupted.
+/// Images already in the inbox are checked first.
+pub fn watch( inbox: &Path, mut signatures: Signatures, options: &WatchOptions, config: &ConfigOptions ) -> Result<(), String> {
+	let watch_error = |e: notify::Error| format!("Error: Failed to watch {}: {}", inbox.display(), e);
+	let (sender, receiver) = mpsc::channel();
+	let mut watcher = notify::recommended_watcher( sender ).map_err( watch_error )?;
+	watcher.watch( inbox, RecursiveMode::Recursive ).map_err( watch_error )?;
+
+	//Size of each file when last checked and when it last changed
+	let mut pending : HashMap<PathBuf, (u64, Instant)> = HashMap::new();
+	add_pending( inbox, &mut pending, options );
+	
+	//Large images wait for memory to be free before they are decoded, if there is a memory limit
+	let memory_budget = config.memory_limit.map( MemoryBudget::new );
+
+	loop {
+		match receiver.recv_timeout( POLL_INTERVAL ) {
+			Ok(Ok(event)) => {
+				//Changes to permissions or times (e.g. touch) don't change the image
+				let new_content = matches!( event.kind, EventKind::Create(_) | EventKind::Modify(_) ) && !matches!( event.kind, EventKind::Modify(ModifyKind::Metadata(_)) );
+				if new_content {
+					for fpath in event.paths {
+						add_pending( &fpath, &mut pending, options );
+					}
+				}
+			},
+			Ok(Err(e)) => eprintln!("Warning: {}", e),
+			Err(RecvTimeoutError::Timeout) => {},
+			Err(RecvTimeoutError::Disconnected) => return Err(format!("Error: Stopped watching {}", inbox.display())),
+		}
+
+		//Files that have stopped growing are read, files that have gone are forgotten
+		let now = Instant::now();
+		let mut ready = Vec::new();
+		pending.retain( |fpath, (size, since)| {
+			match fs::metadata( fpath ) {
+				Ok(metadata) if metadata.is_file() => {
+					if metadata.len() != *size {
+						*size = metadata.len();
+						*since = now;
+						true
+					}else if now.duration_since( *since ) >= options.settle_time {
+						ready.push( fpath.clone() );
+						false
+					}else{
+						true
+					}
+				},
+				_ => false,
+			}
+		});
+		if ready.is_empty() {
+			continue;
+		}
+		ready.sort();
+
+		//Hashed on all threads, then compared in order so that the first of several copies arriving together is the unique one
+		let hashed : Vec<(PathBuf, Result<ImageHashAV, String>)> = ready.into_par_iter()
+			.filter( |fpath| crate::valid_file_extension( fpath, config ) )
+			.map( |fpath| {
+				let image = hash_new_image( &fpath, memory_budget.as_ref(), config );
+				(fpath, image)
+			})
+			.collect();
+		for (fpath, image) in hashed {
+			let outcome = image.map( |image| signatures.check( image, config ) );
+			let mut moved_to = None;
+			if let (Ok(Outcome::Duplicate(_)), Some(quarantine)) = (&outcome, &options.quarantine) {
+				match quarantine_file( &fpath, quarantine ) {
+					Ok(destination) => moved_to = Some(destination),
+					Err(e) => eprintln!("Error: Failed to move {} to quarantine: {}", fpath.display(), e),
+				}
+			}
+			let report = Report { fpath, outcome, moved_to };
+			if let Err(e) = write_report( &mut io::stdout().lock(), &report, options.json, config ) {
+				panic!("failed printing to stdout: {}", e);
+			}
+		}
+	}
+}
+
+/// Add a new file, or the files in a new directory, to those waiting to stop growing. Invisible files (e.g. partial downloads) and files in the quarantine are ignored.
+fn add_pending( fpath: &Path, pending: &mut HashMap<PathBuf, (u64, Instant)>, options: &WatchOptions ) {
+	for entry in WalkDir::new( fpath ).into_iter().filter_entry( |e| e.depth() == 0 || !e.file_name().as_encoded_bytes().starts_with( b"." ) ).flatten() {
+		let path = entry.path();
+		let quarantined = options.quarantine.as_ref().is_some_and( |q| path.starts_with( q ) );
+		let hidden = path.file_name().is_some_and( |n| n.as_encoded_bytes().starts_with( b"." ) );
+		if entry.file_type().is_file() && !quarantined && !hidden {
+			//Any write restarts the wait
+			pending.insert( path.to_path_buf(), (u64::MAX, Instant::now()) );
+		}
+	}
+}
+
+/// Calculate the statistics of a new image as configured, within the memory budget if there is one
+fn hash_new_image( fpath: &Path, memory_budget: Option<&Arc<MemoryBudget>>, config: &ConfigOptions ) -> Result<ImageHashAV, String> {
+	let image_path = ImagePath { fpath: fpath.to_path_buf(), prefer_as_best: config.prefer_raw && raw::is_raw_file( fpath ), alpha_background: Some(config.alpha_background), jpeg_decode: config.jpeg_decode, memory_limit: config.memory_limit, ..Default::default() };
+	let _reservation = match memory_budget.map( |budget| budget.reserve( memory::estimate_decoded_size( &image_path, config.frame_policy ).unwrap_or(0), fpath ) ) {
+		Some(Err(e)) => return Err(e.to_string()),
+		reservation => reservation,
+	};
+	ImageHashAV::new_with_frames( &image_path, config.min_width, config.min_height, config.frame_policy ).map_err( |e| e.to_string() )
+}
+
+/// Move a file into the quarantine directory, adding a number to the name if there is already a file of the same name there
+fn quarantine_file( fpath: &Path, quarantine: &Path ) -> io::Result<PathBuf> {
+	let file_name = fpath.file_name().ok_or_else( || io::Error::new( io::ErrorKind::InvalidInput, "not a file" ) )?;
+	let mut destination = quarantine.join( file_name );
+	let mut n = 1;
+	while destination.symlink_metadata().is_ok() {
+		let mut numbered = fpath.file_stem().unwrap_or_default().to_os_string();
+		numbered.push( format!("_{}", n) );
+		if let Some(extension) = fpath.extension() {
+			numbered.push( "." );
+			numbered.push( extension );
+		}
+		destination = quarantine.join( numbered );
+		n += 1;
+	}
+	//Renaming fails between filesystems, when the file is copied instead
+	if fs::rename( fpath, &destination ).is_err() {
+		fs::copy( fpath, &destination )?;
+		fs::remove_file( fpath )?;
+	}
+	Ok(destination)
+}
+
+/// Write the result of checking a new image, either as lines of text or one line of JSON. Errors reading the image are printed to stderr unless writing JSON.
+fn write_report( out: &mut impl Write, report: &Report, json: bool, config: &ConfigOptions ) -> io::Result<()> {
+	if json {
+		let mut line = format!("{{\"path\":{}", json_path( &report.fpath, config ));
+		match report.outcome {
+			Ok(Outcome::Duplicate(ref original)) => line.push_str( &format!(",\"status\":\"duplicate\",\"duplicate_of\":{}", json_path( original, config )) ),
+			Ok(Outcome::Unique) => line.push_str( ",\"status\":\"unique\"" ),
+			Err(ref e) => line.push_str( &format!(",\"status\":\"error\",\"error\":{}", json_string( e )) ),
+		}
+		if let Some(ref moved_to) = report.moved_to {
+			line.push_str( &format!(",\"moved_to\":{}", json_path( moved_to, config )) );
+		}
+		line.push_str( "}\n" );
+		return out.write_all( line.as_bytes() );
+	}
+
+	match report.outcome {
+		Ok(Outcome::Duplicate(ref original)) => {
+			paths::write_path_line( out, "Duplicate: ", &report.fpath, config.escape_paths, b'\n' )?;
+			paths::write_path_line( out, "\tOf: ", original, config.escape_paths, b'\n' )?;
+		},
+		Ok(Outcome::Unique) => paths::write_path_line( out, "Unique: ", &report.fpath, config.escape_paths, b'\n' )?,
+		Err(ref e) => eprintln!("{}", e),
+	}
+	if let Some(ref moved_to) = report.moved_to {
+		paths::write_path_line( out, "\tMoved to: ", moved_to, config.escape_paths, b'\n' )?;
+	}
+	Ok(())
+}
+
+/// A path as a JSON string. JSON must be Unicode, so bytes that aren't UTF-8 are replaced, or escaped with --escape-paths.
+fn json_path( path: &Path, config: &ConfigOptions ) -> String {
+	if config.escape_paths {
+		json_string( &paths::escape_path( path ) )
+	}else{
+		json_string( &path.to_string_lossy() )
+	}
+}
+
+/// Quote and escape text as a JSON string
+fn json_string( s: &str ) -> String {
+	let mut quoted = String::from( "\"" );
+	for c in s.chars() {
+		match c {
+			'"' => quoted.push_str( "\\\"" ),
+			'\\' => quoted.push_str( "\\\\" ),
+			'\n' => quoted.push_str( "\\n" ),
+			'\t' => quoted.push_str( "\\t" ),
+			'\r' => quoted.push_str( "\\r" ),
+			c if c.is_control() => quoted.push_str( &format!("\\u{:04x}", c as u32) ),
+			c => quoted.push( c ),
+		}
+	}
+	quoted.push( '"' );
+	quoted
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	/// Test new images are compared with the collection, unique images are added to it and duplicates are quarantined and reported
+	#[test]
+	fn test_watch_check() {
+		let mut config = crate::get_default_config_options();
+		//The image in a file, as if it had arrived at another path
+		let image_at = |fpath: &str, arrived_at: &str| {
+			let mut image = hash_new_image( Path::new( fpath ), None, &crate::get_default_config_options() ).unwrap();
+			image.image_path.fpath = arrived_at.into();
+			image
+		};
+		let mut signatures = Signatures::new( vec![ image_at( "unit_test_images/cat1_duplicate_1.jpg", "photos/cat.jpg" ) ] );
+
+		assert_eq!( signatures.check( image_at( "unit_test_images/cat1_duplicate_1.jpg", "inbox/cat.jpg" ), &config ), Outcome::Duplicate( "photos/cat.jpg".into() ) );
+		assert_eq!( signatures.check( image_at( "unit_test_images/car1_best.jpg", "inbox/car.jpg" ), &config ), Outcome::Unique );
+		assert_eq!( signatures.check( image_at( "unit_test_images/car1_best.jpg", "inbox/car copy.jpg" ), &config ), Outcome::Duplicate( "inbox/car.jpg".into() ), "Unique image added" );
+		assert_eq!( signatures.check( image_at( "unit_test_images/car1_best.jpg", "inbox/car.jpg" ), &config ), Outcome::Unique, "Checked again without matching itself" );
+		config.always_mark_duplicates = true;
+		assert_eq!( signatures.check( image_at( "unit_test_images/cat1_best.jpg", "inbox/cat large.jpg" ), &config ), Outcome::Duplicate( "photos/cat.jpg".into() ), "Better version with --ignore-resolution" );
+		config.always_mark_duplicates = false;
+		assert_eq!( signatures.check( image_at( "unit_test_images/cat1_best.jpg", "inbox/cat large.jpg" ), &config ), Outcome::Unique, "Better version" );
+		assert_eq!( signatures.len(), 3 );
+		assert_eq!( signatures.check( image_at( "unit_test_images/bridge1_best.jpg", "inbox/car.jpg" ), &config ), Outcome::Unique, "Overwritten with another image" );
+		assert_eq!( signatures.check( image_at( "unit_test_images/car1_best.jpg", "inbox/car 2.jpg" ), &config ), Outcome::Unique, "Overwritten image replaced" );
+		assert_eq!( signatures.len(), 4 );
+
+		let dir = std::env::temp_dir().join( "photodedupe_watch_test" );
+		let _ = fs::remove_dir_all( &dir );
+		fs::create_dir_all( dir.join( "quarantine" ) ).unwrap();
+		for _ in 0..2 {
+			fs::write( dir.join( "IMG 1.jpg" ), "Image" ).unwrap();
+			quarantine_file( &dir.join( "IMG 1.jpg" ), &dir.join( "quarantine" ) ).unwrap();
+		}
+		assert!( dir.join( "quarantine/IMG 1.jpg" ).is_file() && dir.join( "quarantine/IMG 1_1.jpg" ).is_file() && !dir.join( "IMG 1.jpg" ).exists() );
+		fs::remove_dir_all( &dir ).unwrap();
+
+		let report = Report { fpath: "inbox/\"a\".jpg".into(), outcome: Ok(Outcome::Duplicate( "photos/a.jpg".into() )), moved_to: Some("quarantine/\"a\".jpg".into()) };
+		let mut out = Vec::new();
+		write_report( &mut out, &report, true, &config ).unwrap();
+		assert_eq!( String::from_utf8( out ).unwrap(), "{\"path\":\"inbox/\\\"a\\\".jpg\",\"status\":\"duplicate\",\"duplicate_of\":\"photos/a.jpg\",\"moved_to\":\"quarantine/\\\"a\\\".jpg\"}\n" );
+		let mut out = Vec::new();
+		write_report( &mut out, &report, false, &config ).unwrap();
+		assert_eq!( String::from_utf8( out ).unwrap(), "Duplicate: inbox/\"a\".jpg\n\tOf: photos/a.jpg\n\tMoved to: quarantine/\"a\".jpg\n" );
+	}
+}